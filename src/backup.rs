@@ -0,0 +1,484 @@
+//! Incremental backups.
+//!
+//! A backup directory holds any number of backups of a storage. SSTs and blob files never change
+//! once written, so a file with the same id and checksum is stored once and shared by every backup
+//! containing it. The manifest and the wal files are stored per backup.
+//!
+//! ```text
+//! backup_dir/
+//!   shared/{name}_{checksum}   SSTs and blob files
+//!   private/{backup_id}/       manifest and wal files
+//!   meta/{backup_id}           the files of the backup
+//! ```
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::{fs::FileSystem, lsm_storage::MiniLsm};
+
+/// Files are read in chunks of this size to compute their checksum.
+const CHECKSUM_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// A file of a backup.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct BackupFile {
+    /// Name of the file in the storage directory.
+    name: String,
+    /// Path of the file relative to the backup directory.
+    path: String,
+    size: u64,
+    checksum: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct BackupMeta {
+    id: usize,
+    /// Seconds since the unix epoch.
+    timestamp: u64,
+    files: Vec<BackupFile>,
+}
+
+/// Information of a backup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupInfo {
+    pub id: usize,
+    /// Seconds since the unix epoch when the backup was created.
+    pub timestamp: u64,
+    /// Total size of the files of the backup, including the shared ones.
+    pub size: u64,
+    pub num_files: usize,
+}
+
+/// Creates, verifies and restores backups in a backup directory.
+pub struct BackupEngine {
+    file_system: Arc<dyn FileSystem>,
+    dir: PathBuf,
+}
+
+impl BackupEngine {
+    /// Open a backup directory of `file_system`, creating it if it does not exist. The storages
+    /// backed up must run on the same file system.
+    pub fn open(file_system: Arc<dyn FileSystem>, dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        for sub_dir in ["shared", "private", "meta"] {
+            file_system
+                .create_dir_all(&dir.join(sub_dir))
+                .context("Failed to create backup directory")?;
+        }
+        Ok(Self { file_system, dir })
+    }
+
+    /// Back up the storage and return the id of the new backup. Only the SSTs and blob files not
+    /// in an earlier backup are copied.
+    pub fn create_new_backup(&self, storage: &MiniLsm) -> Result<usize> {
+        let id = self.backup_ids()?.last().map_or(1, |id| id + 1);
+        let checkpoint = self.dir.join(format!("checkpoint_{}.tmp", id));
+        if self.file_system.exists(&checkpoint) {
+            // left by a failed backup
+            self.remove_dir_with_files(&checkpoint)?;
+        }
+        storage.create_checkpoint(&checkpoint)?;
+        let result = self.backup_checkpoint(id, &checkpoint);
+        self.remove_dir_with_files(&checkpoint)?;
+        result?;
+        Ok(id)
+    }
+
+    fn backup_checkpoint(&self, id: usize, checkpoint: &Path) -> Result<()> {
+        let fs = self.file_system.as_ref();
+        let private = self.dir.join("private").join(id.to_string());
+        if fs.exists(&private) {
+            // left by a failed backup with the same id
+            self.remove_dir_with_files(&private)?;
+        }
+        fs.create_dir_all(&private)?;
+        let mut files = Vec::new();
+        for path in fs.list(checkpoint)? {
+            let name = file_name(&path);
+            let (size, checksum) = self.checksum(&path)?;
+            let backup_path = if name.ends_with(".sst") || name.ends_with(".blob") {
+                format!("shared/{}_{}", name, checksum)
+            } else {
+                format!("private/{}/{}", id, name)
+            };
+            let target = self.dir.join(&backup_path);
+            // a shared file of an earlier backup is reused, only complete files have its name
+            if !fs.exists(&target) {
+                self.install_file(&path, &target)?;
+            }
+            files.push(BackupFile {
+                name,
+                path: backup_path,
+                size,
+                checksum,
+            });
+        }
+        fs.sync_dir(&self.dir.join("shared"))?;
+        fs.sync_dir(&private)?;
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        let meta = BackupMeta {
+            id,
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            files,
+        };
+        // the backup exists once its meta file is renamed in place
+        let meta_path = self.meta_path(id);
+        let tmp_path = meta_path.with_extension("tmp");
+        let mut file = fs.create(&tmp_path)?;
+        file.append(&serde_json::to_vec(&meta)?)?;
+        file.sync()?;
+        fs.rename(&tmp_path, &meta_path)?;
+        fs.sync_dir(&self.dir.join("meta"))?;
+        Ok(())
+    }
+
+    /// Link or copy `from` to a temporary file renamed to `to` once complete, so a failed backup
+    /// never leaves a partial file under a name later backups reuse. The checkpoint files are
+    /// synced, and copies are made durable.
+    fn install_file(&self, from: &Path, to: &Path) -> Result<()> {
+        let fs = self.file_system.as_ref();
+        let mut tmp_path = to.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        if fs.exists(&tmp_path) {
+            fs.remove_file(&tmp_path)?;
+        }
+        fs.hard_link(from, &tmp_path)
+            .or_else(|_| fs.copy(from, &tmp_path))?;
+        fs.rename(&tmp_path, to)?;
+        Ok(())
+    }
+
+    /// List the backups, oldest first.
+    pub fn get_backup_info(&self) -> Result<Vec<BackupInfo>> {
+        self.backup_ids()?
+            .into_iter()
+            .map(|id| {
+                let meta = self.read_meta(id)?;
+                Ok(BackupInfo {
+                    id,
+                    timestamp: meta.timestamp,
+                    size: meta.files.iter().map(|file| file.size).sum(),
+                    num_files: meta.files.len(),
+                })
+            })
+            .collect()
+    }
+
+    /// Delete a backup and the shared files no other backup uses.
+    pub fn delete_backup(&self, id: usize) -> Result<()> {
+        self.read_meta(id)?;
+        self.file_system.remove_file(&self.meta_path(id))?;
+        let private = self.dir.join("private").join(id.to_string());
+        if self.file_system.exists(&private) {
+            self.remove_dir_with_files(&private)?;
+        }
+        self.purge_shared_files()
+    }
+
+    /// Remove the shared files not used by any backup.
+    fn purge_shared_files(&self) -> Result<()> {
+        let mut used = HashSet::new();
+        for id in self.backup_ids()? {
+            used.extend(self.read_meta(id)?.files.into_iter().map(|file| file.path));
+        }
+        for path in self.file_system.list(&self.dir.join("shared"))? {
+            if !used.contains(&format!("shared/{}", file_name(&path))) {
+                self.file_system.remove_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Verify every file of a backup exists with the recorded size and checksum.
+    pub fn verify_backup(&self, id: usize) -> Result<()> {
+        for file in self.read_meta(id)?.files {
+            let path = self.dir.join(&file.path);
+            if !self.file_system.exists(&path) {
+                bail!("file {} of backup {} is missing", file.path, id);
+            }
+            let (size, checksum) = self.checksum(&path)?;
+            if size != file.size {
+                bail!(
+                    "file {} of backup {} has size {}, expected {}",
+                    file.path,
+                    id,
+                    size,
+                    file.size
+                );
+            }
+            if checksum != file.checksum {
+                bail!(
+                    "file {} of backup {} has a checksum mismatch",
+                    file.path,
+                    id
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Restore a backup to `dir`, a new directory that `MiniLsm::open` can open.
+    pub fn restore_backup(&self, id: usize, dir: impl AsRef<Path>) -> Result<()> {
+        let dir = dir.as_ref();
+        if self.file_system.exists(dir) {
+            bail!("restore directory {} already exists", dir.display());
+        }
+        self.verify_backup(id)?;
+        self.file_system.create_dir_all(dir)?;
+        for file in self.read_meta(id)?.files {
+            self.file_system
+                .copy(&self.dir.join(&file.path), &dir.join(&file.name))?;
+        }
+        self.file_system.sync_dir(dir)?;
+        Ok(())
+    }
+
+    /// Restore the newest backup to `dir`.
+    pub fn restore_latest_backup(&self, dir: impl AsRef<Path>) -> Result<()> {
+        match self.backup_ids()?.last() {
+            Some(id) => self.restore_backup(*id, dir),
+            None => bail!("no backup in {}", self.dir.display()),
+        }
+    }
+
+    /// Ids of the backups, sorted.
+    fn backup_ids(&self) -> Result<Vec<usize>> {
+        let mut ids = Vec::new();
+        for path in self.file_system.list(&self.dir.join("meta"))? {
+            if let Ok(id) = file_name(&path).parse() {
+                ids.push(id);
+            }
+        }
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    fn meta_path(&self, id: usize) -> PathBuf {
+        self.dir.join("meta").join(id.to_string())
+    }
+
+    fn read_meta(&self, id: usize) -> Result<BackupMeta> {
+        let data = self
+            .file_system
+            .read(&self.meta_path(id))
+            .with_context(|| format!("Failed to read meta of backup {}", id))?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    /// Remove a directory holding only files.
+    fn remove_dir_with_files(&self, dir: &Path) -> Result<()> {
+        for path in self.file_system.list(dir)? {
+            self.file_system.remove_file(&path)?;
+        }
+        self.file_system.remove_dir(dir)?;
+        Ok(())
+    }
+
+    /// Size and crc32 of a file.
+    fn checksum(&self, path: &Path) -> Result<(u64, u32)> {
+        let file = self.file_system.open(path)?;
+        let size = file.size()?;
+        let mut hasher = crc32fast::Hasher::new();
+        let mut buf = vec![0; CHECKSUM_CHUNK_SIZE as usize];
+        let mut offset = 0;
+        while offset < size {
+            let len = CHECKSUM_CHUNK_SIZE.min(size - offset) as usize;
+            file.read_at(offset, &mut buf[..len])?;
+            hasher.update(&buf[..len]);
+            offset += len as u64;
+        }
+        Ok((size, hasher.finalize()))
+    }
+}
+
+/// The last component of `path`.
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Backup test
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::{
+        fs::{FaultInjectionFs, FileOp, InMemoryFileSystem, StdFileSystem},
+        lsm_storage::LsmStorageOptions,
+    };
+
+    fn option() -> LsmStorageOptions {
+        LsmStorageOptions {
+            target_sst_size: 1024 * 1024,
+            min_blob_size: Some(16),
+            ..Default::default()
+        }
+    }
+
+    fn flush(storage: &MiniLsm) -> Result<()> {
+        let inner = &storage.inner;
+        inner.force_freeze_memtable(&inner.state_lock.lock())?;
+        inner.force_flush_next_imm_memtable()
+    }
+
+    /// Test backups share SSTs and restore to the data at backup time
+    #[test]
+    fn test_backup_and_restore() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let storage = MiniLsm::open(dir.path().join("db"), option())?;
+        let engine = BackupEngine::open(Arc::new(StdFileSystem), dir.path().join("backup"))?;
+
+        storage.put(b"key1", &[b'1'; 32])?;
+        flush(&storage)?;
+        storage.put(b"key2", b"2")?;
+        assert_eq!(engine.create_new_backup(&storage)?, 1);
+
+        storage.put(b"key3", b"3")?;
+        flush(&storage)?;
+        assert_eq!(engine.create_new_backup(&storage)?, 2);
+        storage.close()?;
+
+        // the SST and blob file of the first flush are stored once
+        let shared = std::fs::read_dir(dir.path().join("backup/shared"))?.count();
+        assert_eq!(shared, 3);
+        let info = engine.get_backup_info()?;
+        assert_eq!(info.iter().map(|i| i.id).collect::<Vec<_>>(), vec![1, 2]);
+        engine.verify_backup(1)?;
+        engine.verify_backup(2)?;
+
+        engine.restore_backup(1, dir.path().join("restore1"))?;
+        assert!(
+            engine
+                .restore_backup(1, dir.path().join("restore1"))
+                .is_err()
+        );
+        let restored = MiniLsm::open(dir.path().join("restore1"), option())?;
+        assert_eq!(restored.get(b"key1")?, Some(Bytes::from(vec![b'1'; 32])));
+        assert_eq!(restored.get(b"key2")?, Some(Bytes::from_static(b"2")));
+        assert_eq!(restored.get(b"key3")?, None);
+        restored.close()?;
+
+        engine.delete_backup(1)?;
+        assert!(engine.verify_backup(1).is_err());
+        engine.verify_backup(2)?;
+        engine.restore_latest_backup(dir.path().join("restore2"))?;
+        let restored = MiniLsm::open(dir.path().join("restore2"), option())?;
+        assert_eq!(restored.get(b"key1")?, Some(Bytes::from(vec![b'1'; 32])));
+        assert_eq!(restored.get(b"key3")?, Some(Bytes::from_static(b"3")));
+        restored.close()?;
+
+        engine.delete_backup(2)?;
+        assert_eq!(
+            std::fs::read_dir(dir.path().join("backup/shared"))?.count(),
+            0
+        );
+        Ok(())
+    }
+
+    /// Test a corrupted backup file fails verification
+    #[test]
+    fn test_verify_corrupted_backup() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let storage = MiniLsm::open(dir.path().join("db"), option())?;
+        let engine = BackupEngine::open(Arc::new(StdFileSystem), dir.path().join("backup"))?;
+        storage.put(b"key", b"value")?;
+        flush(&storage)?;
+        let id = engine.create_new_backup(&storage)?;
+        storage.close()?;
+
+        let sst = std::fs::read_dir(dir.path().join("backup/shared"))?
+            .next()
+            .unwrap()?
+            .path();
+        let mut data = std::fs::read(&sst)?;
+        data[0] ^= 0xff;
+        std::fs::write(&sst, data)?;
+        assert!(engine.verify_backup(id).is_err());
+        assert!(
+            engine
+                .restore_backup(id, dir.path().join("restore"))
+                .is_err()
+        );
+        Ok(())
+    }
+
+    /// Test a backup round trip on an in-memory file system
+    #[test]
+    fn test_backup_in_memory() -> Result<()> {
+        let fs = Arc::new(InMemoryFileSystem::new());
+        let option = LsmStorageOptions {
+            file_system: Some(fs.clone()),
+            ..option()
+        };
+        let storage = MiniLsm::open("/db", option.clone())?;
+        let engine = BackupEngine::open(fs.clone(), "/backup")?;
+        storage.put(b"key1", &[b'1'; 32])?;
+        flush(&storage)?;
+        storage.put(b"key2", b"2")?;
+        let id = engine.create_new_backup(&storage)?;
+        storage.close()?;
+        assert!(!fs.exists(Path::new("/backup/checkpoint_1.tmp")));
+        assert_eq!(fs.list(Path::new("/backup/shared"))?.len(), 2);
+
+        engine.verify_backup(id)?;
+        engine.restore_backup(id, "/restore")?;
+        let restored = MiniLsm::open("/restore", option)?;
+        assert_eq!(restored.get(b"key1")?, Some(Bytes::from(vec![b'1'; 32])));
+        assert_eq!(restored.get(b"key2")?, Some(Bytes::from_static(b"2")));
+        restored.close()?;
+
+        engine.delete_backup(id)?;
+        assert!(fs.list(Path::new("/backup/shared"))?.is_empty());
+        assert!(!fs.exists(Path::new("/backup/private/1")));
+        Ok(())
+    }
+
+    /// Test a backup failing while it copies a file leaves no partial file that the next backup
+    /// reuses
+    #[test]
+    fn test_backup_after_failed_copy() -> Result<()> {
+        let fs = Arc::new(FaultInjectionFs::new(Arc::new(InMemoryFileSystem::new())));
+        let option = LsmStorageOptions {
+            file_system: Some(fs.clone()),
+            ..option()
+        };
+        let storage = MiniLsm::open("/db", option.clone())?;
+        let engine = BackupEngine::open(fs.clone(), "/backup")?;
+        storage.put(b"key1", &[b'1'; 32])?;
+        flush(&storage)?;
+        storage.put(b"key2", b"2")?;
+
+        // files are copied instead of linked, and the copy of the blob file is torn
+        let blob_name = format!("{:05}.blob", storage.inner.state.read().l0_sstable[0]);
+        let (_, checksum) = engine.checksum(&Path::new("/db").join(&blob_name))?;
+        fs.fail(FileOp::HardLink, "");
+        fs.tear_next_append(&format!("{}_{}.tmp", blob_name, checksum), 4);
+        assert!(engine.create_new_backup(&storage).is_err());
+        assert!(engine.get_backup_info()?.is_empty());
+        let shared = fs.list(Path::new("/backup/shared"))?;
+        assert!(!shared.is_empty());
+        assert!(shared.iter().all(|path| file_name(path).ends_with(".tmp")));
+        let id = engine.create_new_backup(&storage)?;
+        fs.clear_faults();
+        storage.close()?;
+        assert_eq!(id, 1);
+
+        engine.verify_backup(id)?;
+        engine.restore_backup(id, "/restore")?;
+        let restored = MiniLsm::open("/restore", option)?;
+        assert_eq!(restored.get(b"key1")?, Some(Bytes::from(vec![b'1'; 32])));
+        assert_eq!(restored.get(b"key2")?, Some(Bytes::from_static(b"2")));
+        restored.close()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,211 @@
+//! Prints the layout and the amplifications of `lsm_tree::compact::simulator` runs.
+//!
+//! ```text
+//! compaction-simulator <simple|leveled|tiered> [--option value]...
+//! ```
+
+use std::{collections::HashMap, str::FromStr};
+
+use anyhow::{Context, Result, anyhow, bail};
+use lsm_tree::compact::{
+    CompactionOptions, LeveledCompactionOptions, SimpleLeveledCompactionOptions,
+    TieredCompactionOptions,
+    simulator::{Simulator, SimulatorOptions, Workload},
+};
+
+const USAGE: &str = "\
+usage: compaction-simulator <simple|leveled|tiered> [--option value]...
+
+workload:
+  --flushes N                               number of flushes (100)
+  --keys-per-sst N                          keys of a flushed or compacted SST (1024)
+  --entry-size N                            bytes of a key-value pair (1024)
+  --workload uniform|sequential             keys spread over the key space, or increasing (uniform)
+  --key-space N                             number of distinct keys of the uniform workload (1000000)
+  --seed N                                  seed of the uniform workload (1)
+
+simple:
+  --size-ratio-percent N                    (200)
+  --level0-file-num-compaction-trigger N    (2)
+  --max-levels N                            (4)
+
+leveled:
+  --level-size-multiplier N                 (10)
+  --level0-file-num-compaction-trigger N    (2)
+  --max-levels N                            (4)
+  --base-level-size-mb N                    (8)
+
+tiered:
+  --num-tiers N                             (8)
+  --max-size-amplification-percent N        (200)
+  --size-ratio N                            (1)
+  --min-merge-width N                       (2)
+  --max-merge-width N                       (unbounded)
+";
+
+/// Command line options, taken out as they are read.
+struct Args(HashMap<String, String>);
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut options = HashMap::new();
+        while let Some(arg) = args.next() {
+            let name = arg
+                .strip_prefix("--")
+                .with_context(|| format!("unexpected argument {}", arg))?;
+            let value = args
+                .next()
+                .with_context(|| format!("missing value for --{}", name))?;
+            options.insert(name.to_string(), value);
+        }
+        Ok(Self(options))
+    }
+
+    fn get_opt<T: FromStr>(&mut self, name: &str) -> Result<Option<T>> {
+        self.0
+            .remove(name)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|_| anyhow!("invalid value {} for --{}", value, name))
+            })
+            .transpose()
+    }
+
+    fn get<T: FromStr>(&mut self, name: &str, default: T) -> Result<T> {
+        Ok(self.get_opt(name)?.unwrap_or(default))
+    }
+
+    /// Fail on the options nothing read.
+    fn finish(self) -> Result<()> {
+        match self.0.into_keys().next() {
+            Some(name) => bail!("unknown option --{}", name),
+            None => Ok(()),
+        }
+    }
+}
+
+fn print_layout(simulator: &Simulator) {
+    let mb = |bytes: u64| bytes as f64 / 1024.0 / 1024.0;
+    let l0 = &simulator.state().l0_sstable;
+    println!("L0: {:.1} MB {:?}", mb(simulator.size_of(l0)), l0);
+    let tiered = matches!(
+        simulator.options().compaction_options,
+        CompactionOptions::Tiered(_)
+    );
+    for (id, ids) in &simulator.state().levels {
+        let name = if tiered {
+            format!("tier {}", id)
+        } else {
+            format!("L{}", id)
+        };
+        println!("{}: {:.1} MB {:?}", name, mb(simulator.size_of(ids)), ids);
+    }
+}
+
+fn print_amplification(simulator: &Simulator) {
+    println!(
+        "write amplification {:.2}, space amplification {:.2}, read amplification {}",
+        simulator.write_amplification(),
+        simulator.space_amplification(),
+        simulator.read_amplification()
+    );
+}
+
+fn compaction_options(strategy: &str, args: &mut Args) -> Result<CompactionOptions> {
+    Ok(match strategy {
+        "simple" => CompactionOptions::Simple(SimpleLeveledCompactionOptions {
+            size_ratio_percent: args.get("size-ratio-percent", 200)?,
+            level0_file_num_compaction_trigger: args
+                .get("level0-file-num-compaction-trigger", 2)?,
+            max_levels: args.get("max-levels", 4)?,
+        }),
+        "leveled" => CompactionOptions::Leveled(LeveledCompactionOptions {
+            level_size_multiplier: args.get("level-size-multiplier", 10)?,
+            level0_file_num_compaction_trigger: args
+                .get("level0-file-num-compaction-trigger", 2)?,
+            max_levels: args.get("max-levels", 4)?,
+            base_level_size_mb: args.get("base-level-size-mb", 8)?,
+        }),
+        "tiered" => CompactionOptions::Tiered(TieredCompactionOptions {
+            num_tiers: args.get("num-tiers", 8)?,
+            max_size_amplification_percent: args.get("max-size-amplification-percent", 200)?,
+            size_ratio: args.get("size-ratio", 1)?,
+            min_merge_width: args.get("min-merge-width", 2)?,
+            max_merge_width: args.get_opt("max-merge-width")?,
+        }),
+        _ => bail!("unknown compaction strategy {}", strategy),
+    })
+}
+
+fn run(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let strategy = args.next().context("missing compaction strategy")?;
+    let mut args = Args::parse(args)?;
+    let compaction_options = compaction_options(&strategy, &mut args)?;
+    let flushes = args.get("flushes", 100)?;
+    let options = SimulatorOptions {
+        compaction_options,
+        keys_per_sst: args.get("keys-per-sst", 1024)?,
+        entry_size: args.get("entry-size", 1024)?,
+        workload: args.get("workload", Workload::Uniform)?,
+        key_space: args.get("key-space", 1_000_000)?,
+        seed: args.get("seed", 1)?,
+    };
+    args.finish()?;
+    if options.keys_per_sst == 0 || options.key_space == 0 {
+        bail!("--keys-per-sst and --key-space must be positive");
+    }
+
+    let mut simulator = Simulator::new(options);
+    for step in 1..=flushes {
+        simulator.flush();
+        let compactions = simulator.compact()?;
+        println!("--- flush {}, {} compactions", step, compactions);
+        print_layout(&simulator);
+        print_amplification(&simulator);
+    }
+    Ok(())
+}
+
+fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if args.is_empty() || args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        print!("{}", USAGE);
+        return;
+    }
+    if let Err(e) = run(args.into_iter()) {
+        eprintln!("error: {:#}\n\n{}", e, USAGE);
+        std::process::exit(1);
+    }
+}
+
+/// Compaction simulator test
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Result<Args> {
+        Args::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    /// Test the options are read from the command line, and unknown ones are refused
+    #[test]
+    fn test_simulator_args() -> Result<()> {
+        let mut parsed = args(&["--max-levels", "3", "--workload", "sequential"])?;
+        let options = compaction_options("simple", &mut parsed)?;
+        assert!(matches!(
+            options,
+            CompactionOptions::Simple(SimpleLeveledCompactionOptions { max_levels: 3, .. })
+        ));
+        assert_eq!(
+            parsed.get("workload", Workload::Uniform)?,
+            Workload::Sequential
+        );
+        parsed.finish()?;
+
+        assert!(args(&["--flushes"]).is_err());
+        assert!(compaction_options("universal", &mut args(&[])?).is_err());
+        assert!(args(&["--seed", "1"])?.finish().is_err());
+        Ok(())
+    }
+}
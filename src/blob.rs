@@ -0,0 +1,260 @@
+//! Blob files for key-value separation.
+//!
+//! Large values are written to an append-only blob file when a memtable is flushed, and the SST
+//! only stores a `BlobPointer` to them, so compactions do not have to rewrite the value.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+};
+
+use anyhow::{Context, Result, bail};
+use bytes::{Buf, BufMut, Bytes};
+
+use crate::{
+    fs::{FileSystem, WritableFile},
+    rate_limiter::{IoPriority, RateLimiter},
+    table::FileObject,
+};
+
+/// Size of an encoded `BlobPointer`.
+const BLOB_POINTER_SIZE: usize = 8 + 8 + 4;
+
+/// Locates a value inside a blob file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlobPointer {
+    /// Id of the blob file.
+    pub file_id: usize,
+    /// Offset of the value in the blob file.
+    pub offset: u64,
+    /// Length of the value.
+    pub len: u32,
+}
+
+impl BlobPointer {
+    /// Encode the pointer to a buffer.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.put_u64(self.file_id as u64);
+        buf.put_u64(self.offset);
+        buf.put_u32(self.len);
+    }
+
+    /// Decode a pointer from a buffer.
+    pub fn decode(mut buf: &[u8]) -> Result<Self> {
+        if buf.len() != BLOB_POINTER_SIZE {
+            bail!("invalid blob pointer size {}", buf.len());
+        }
+        Ok(Self {
+            file_id: buf.get_u64() as usize,
+            offset: buf.get_u64(),
+            len: buf.get_u32(),
+        })
+    }
+}
+
+/// Appends values to a new blob file.
+pub struct BlobFileBuilder {
+    id: usize,
+    path: PathBuf,
+    file_system: Arc<dyn FileSystem>,
+    file: Box<dyn WritableFile>,
+    offset: u64,
+    min_blob_size: usize,
+    rate_limiter: Option<(Arc<RateLimiter>, IoPriority)>,
+}
+
+impl BlobFileBuilder {
+    /// Create a new blob file.
+    ///
+    /// # Arguments
+    /// * `file_system` - File system of the blob file
+    /// * `id` - Id of the blob file
+    /// * `path` - File
+    /// * `min_blob_size` - Values of at least this size go to the blob file
+    pub fn create(
+        file_system: Arc<dyn FileSystem>,
+        id: usize,
+        path: impl AsRef<Path>,
+        min_blob_size: usize,
+    ) -> Result<Self> {
+        let file = file_system
+            .create(path.as_ref())
+            .context("Failed to create blob file")?;
+        Ok(Self {
+            id,
+            path: path.as_ref().to_path_buf(),
+            file_system,
+            file,
+            offset: 0,
+            min_blob_size,
+            rate_limiter: None,
+        })
+    }
+
+    /// Write the values through `rate_limiter` with `priority`.
+    pub fn with_rate_limiter(
+        mut self,
+        rate_limiter: Option<Arc<RateLimiter>>,
+        priority: IoPriority,
+    ) -> Self {
+        self.rate_limiter = rate_limiter.map(|rate_limiter| (rate_limiter, priority));
+        self
+    }
+
+    /// Whether `value` should be separated into the blob file.
+    pub fn should_separate(&self, value: &[u8]) -> bool {
+        value.len() >= self.min_blob_size
+    }
+
+    /// Append a value and return the pointer to it.
+    pub fn add(&mut self, value: &[u8]) -> Result<BlobPointer> {
+        if let Some((rate_limiter, priority)) = &self.rate_limiter {
+            rate_limiter.request(value.len(), *priority);
+        }
+        self.file.append(value)?;
+        let pointer = BlobPointer {
+            file_id: self.id,
+            offset: self.offset,
+            len: value.len() as u32,
+        };
+        self.offset += value.len() as u64;
+        Ok(pointer)
+    }
+
+    /// Whether no value has been appended.
+    pub fn is_empty(&self) -> bool {
+        self.offset == 0
+    }
+
+    /// Sync the blob file and open it for reading. An empty blob file is removed and `None` is
+    /// returned.
+    pub fn build(mut self) -> Result<Option<BlobFile>> {
+        if self.offset == 0 {
+            drop(self.file);
+            self.file_system.remove_file(&self.path)?;
+            return Ok(None);
+        }
+        self.file.sync()?;
+        self.file_system.sync_parent_dir(&self.path)?;
+        Ok(Some(BlobFile::open(
+            self.file_system.as_ref(),
+            self.id,
+            &self.path,
+        )?))
+    }
+}
+
+/// A read-only blob file.
+pub struct BlobFile {
+    id: usize,
+    file: FileObject,
+    /// Set once the blob file left the state, it is removed through this file system when the
+    /// last snapshot reading it is dropped.
+    obsolete: OnceLock<Arc<dyn FileSystem>>,
+}
+
+impl BlobFile {
+    /// Open an existing blob file.
+    pub fn open(file_system: &dyn FileSystem, id: usize, path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self {
+            id,
+            file: FileObject::open(file_system, path.as_ref())?,
+            obsolete: OnceLock::new(),
+        })
+    }
+
+    /// Remove the blob file through `file_system` once it is dropped, so the snapshots still
+    /// holding it keep reading it.
+    pub(crate) fn mark_obsolete(&self, file_system: Arc<dyn FileSystem>) {
+        self.obsolete.set(file_system).ok();
+    }
+
+    /// Read the value `pointer` refers to.
+    pub fn read(&self, pointer: &BlobPointer) -> Result<Bytes> {
+        if pointer.file_id != self.id {
+            bail!(
+                "blob pointer of file {} read from blob file {}",
+                pointer.file_id,
+                self.id
+            );
+        }
+        if pointer.offset + pointer.len as u64 > self.file.size() {
+            bail!("blob pointer out of range of blob file {}", self.id);
+        }
+        Ok(self.file.read(pointer.offset, pointer.len as u64)?.into())
+    }
+
+    /// Get the id of the blob file.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Get the size of the blob file.
+    pub fn size(&self) -> u64 {
+        self.file.size()
+    }
+}
+
+impl Drop for BlobFile {
+    fn drop(&mut self) {
+        if let Some(file_system) = self.obsolete.get() {
+            file_system.remove_file(self.file.path()).ok();
+        }
+    }
+}
+
+/// Blob test
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::InMemoryFileSystem;
+
+    /// Test blob pointer encode and decode
+    #[test]
+    fn test_blob_pointer_encode_decode() -> Result<()> {
+        let pointer = BlobPointer {
+            file_id: 1,
+            offset: 1024,
+            len: 4096,
+        };
+        let mut buf = Vec::new();
+        pointer.encode(&mut buf);
+        assert_eq!(BlobPointer::decode(&buf)?, pointer);
+        assert!(BlobPointer::decode(&buf[1..]).is_err());
+        Ok(())
+    }
+
+    /// Test blob file write and read
+    #[test]
+    fn test_blob_file_write_read() -> Result<()> {
+        let fs = Arc::new(InMemoryFileSystem::new());
+        let mut builder = BlobFileBuilder::create(fs, 1, "00001.blob", 4)?;
+        assert!(!builder.should_separate(b"abc"));
+        assert!(builder.should_separate(b"abcd"));
+        let p1 = builder.add(b"first value")?;
+        let p2 = builder.add(b"second value")?;
+        let blob = builder.build()?.unwrap();
+        assert_eq!(blob.read(&p1)?, Bytes::from_static(b"first value"));
+        assert_eq!(blob.read(&p2)?, Bytes::from_static(b"second value"));
+        assert!(
+            blob.read(&BlobPointer {
+                file_id: 1,
+                offset: 100,
+                len: 1
+            })
+            .is_err()
+        );
+        Ok(())
+    }
+
+    /// Test an empty blob file is removed
+    #[test]
+    fn test_blob_file_empty() -> Result<()> {
+        let fs = Arc::new(InMemoryFileSystem::new());
+        let builder = BlobFileBuilder::create(fs.clone(), 1, "00001.blob", 4)?;
+        assert!(fs.exists(Path::new("00001.blob")));
+        assert!(builder.build()?.is_none());
+        assert!(!fs.exists(Path::new("00001.blob")));
+        Ok(())
+    }
+}
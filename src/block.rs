@@ -0,0 +1,116 @@
+mod builder;
+mod iterator;
+
+pub use builder::BlockBuilder;
+pub use iterator::BlockIterator;
+
+use bytes::{Buf, BufMut, Bytes};
+
+/// Size of a `u32` offset or length stored in a block.
+pub(crate) const SIZEOF_U32: usize = std::mem::size_of::<u32>();
+/// Size of a `u16` key length stored in a block.
+pub(crate) const SIZEOF_U16: usize = std::mem::size_of::<u16>();
+
+/// A block is the smallest unit of read and caching in an SST. It is a collection of sorted
+/// key-value pairs.
+///
+/// Layout:
+/// `| entry | entry | ... | offset (u32) | offset (u32) | ... | num_of_elements (u32) |`
+///
+/// Each entry is `| key_len (u16) | key | value_len (u32) | value |`.
+pub struct Block {
+    pub(crate) data: Vec<u8>,
+    pub(crate) offsets: Vec<u32>,
+}
+
+impl Block {
+    /// Encode the block into a byte buffer.
+    pub fn encode(&self) -> Bytes {
+        let mut buf = self.data.clone();
+        for offset in &self.offsets {
+            buf.put_u32(*offset);
+        }
+        buf.put_u32(self.offsets.len() as u32);
+        buf.into()
+    }
+
+    /// Decode a block from a byte buffer.
+    pub fn decode(data: &[u8]) -> Self {
+        let num_of_elements = (&data[data.len() - SIZEOF_U32..]).get_u32() as usize;
+        let data_end = data.len() - SIZEOF_U32 - num_of_elements * SIZEOF_U32;
+        let offsets = data[data_end..data.len() - SIZEOF_U32]
+            .chunks(SIZEOF_U32)
+            .map(|mut x| x.get_u32())
+            .collect();
+        Self {
+            data: data[..data_end].to_vec(),
+            offsets,
+        }
+    }
+
+    /// Get the number of entries in the block.
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    /// Whether the block has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+}
+
+/// Block test
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    /// Test block encode and decode
+    #[test]
+    fn test_block_encode_decode() {
+        let mut builder = BlockBuilder::new(4096);
+        assert!(builder.add(b"key1", b"value1"));
+        assert!(builder.add(b"key2", b""));
+        let block = builder.build();
+        let decoded = Block::decode(&block.encode());
+        assert_eq!(decoded.offsets, block.offsets);
+        assert_eq!(decoded.data, block.data);
+    }
+
+    /// Test block builder rejects entries past the block size
+    #[test]
+    fn test_block_builder_full() {
+        let mut builder = BlockBuilder::new(16);
+        // the first entry is always accepted
+        assert!(builder.add(b"key1", b"a very long value"));
+        assert!(!builder.add(b"key2", b"value2"));
+    }
+
+    /// Test block iterator seek
+    #[test]
+    fn test_block_iterator_seek() {
+        let mut builder = BlockBuilder::new(4096);
+        for i in 0..100 {
+            let key = format!("key_{:03}", i * 2);
+            let value = format!("value_{}", i);
+            assert!(builder.add(key.as_bytes(), value.as_bytes()));
+        }
+        let block = Arc::new(builder.build());
+
+        let mut iter = BlockIterator::create_and_seek_to_first(block.clone());
+        let mut count = 0;
+        while iter.is_valid() {
+            count += 1;
+            iter.next();
+        }
+        assert_eq!(count, 100);
+
+        let iter = BlockIterator::create_and_seek_to_key(block.clone(), b"key_011");
+        assert_eq!(iter.key(), b"key_012");
+        assert_eq!(iter.value(), b"value_6");
+
+        let iter = BlockIterator::create_and_seek_to_key(block, b"key_999");
+        assert!(!iter.is_valid());
+    }
+}
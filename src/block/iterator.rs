@@ -0,0 +1,105 @@
+use std::sync::Arc;
+
+use bytes::Buf;
+
+use super::{Block, SIZEOF_U16, SIZEOF_U32};
+
+/// Iterates on a block.
+pub struct BlockIterator {
+    /// The internal `Block`, wrapped by an `Arc`
+    block: Arc<Block>,
+    /// The current key, empty represents the iterator is invalid
+    key: Vec<u8>,
+    /// The value range of the current entry in the block data
+    value_range: (usize, usize),
+    /// Current index of the key-value pair, should be in range of [0, num_of_elements)
+    idx: usize,
+}
+
+impl BlockIterator {
+    fn new(block: Arc<Block>) -> Self {
+        Self {
+            block,
+            key: Vec::new(),
+            value_range: (0, 0),
+            idx: 0,
+        }
+    }
+
+    /// Creates a block iterator and seek to the first entry.
+    pub fn create_and_seek_to_first(block: Arc<Block>) -> Self {
+        let mut iter = Self::new(block);
+        iter.seek_to_first();
+        iter
+    }
+
+    /// Creates a block iterator and seek to the first key that >= `key`.
+    pub fn create_and_seek_to_key(block: Arc<Block>, key: &[u8]) -> Self {
+        let mut iter = Self::new(block);
+        iter.seek_to_key(key);
+        iter
+    }
+
+    /// Returns the key of the current entry.
+    pub fn key(&self) -> &[u8] {
+        debug_assert!(!self.key.is_empty(), "invalid iterator");
+        &self.key
+    }
+
+    /// Returns the value of the current entry.
+    pub fn value(&self) -> &[u8] {
+        debug_assert!(!self.key.is_empty(), "invalid iterator");
+        &self.block.data[self.value_range.0..self.value_range.1]
+    }
+
+    /// Returns true if the iterator is valid.
+    pub fn is_valid(&self) -> bool {
+        !self.key.is_empty()
+    }
+
+    /// Seeks to the first key in the block.
+    pub fn seek_to_first(&mut self) {
+        self.seek_to(0);
+    }
+
+    /// Move to the next key in the block.
+    pub fn next(&mut self) {
+        self.idx += 1;
+        self.seek_to(self.idx);
+    }
+
+    /// Seek to the first key that >= `key`.
+    pub fn seek_to_key(&mut self, key: &[u8]) {
+        let mut low = 0;
+        let mut high = self.block.offsets.len();
+        while low < high {
+            let mid = low + (high - low) / 2;
+            self.seek_to(mid);
+            match self.key().cmp(key) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return,
+            }
+        }
+        self.seek_to(low);
+    }
+
+    /// Seeks to the idx-th key in the block.
+    fn seek_to(&mut self, idx: usize) {
+        if idx >= self.block.offsets.len() {
+            self.key.clear();
+            self.value_range = (0, 0);
+            return;
+        }
+        let offset = self.block.offsets[idx] as usize;
+        let mut entry = &self.block.data[offset..];
+        let key_len = entry.get_u16() as usize;
+        self.key.clear();
+        self.key.extend_from_slice(&entry[..key_len]);
+        entry.advance(key_len);
+        let value_len = entry.get_u32() as usize;
+        let value_begin = offset + SIZEOF_U16 + key_len + SIZEOF_U32;
+        self.value_range = (value_begin, value_begin + value_len);
+        self.idx = idx;
+    }
+}
@@ -0,0 +1,435 @@
+mod secondary;
+mod table_cache;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use parking_lot::Mutex;
+
+pub use secondary::{CompressedSecondaryCache, FileSecondaryCache, SecondaryCache};
+pub use table_cache::TableCache;
+
+use crate::block::{Block, SIZEOF_U32};
+
+/// A cached block: the cache id of its SST and the index of the block.
+pub type BlockCacheKey = (u64, usize);
+
+/// The block index the meta section of an SST, with its index, is cached under.
+pub(crate) const META_BLOCK_IDX: usize = usize::MAX;
+
+/// Where a block goes in the cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CachePriority {
+    /// SST meta sections, which hold the index, pinned in the high priority pool: only other high
+    /// priority blocks evict them, once the pool is full.
+    High,
+    /// Data blocks, in the low priority pool.
+    Low,
+}
+
+/// Capacity and usage of a cache shard, in bytes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheShardStats {
+    pub capacity: usize,
+    pub usage: usize,
+    pub high_pri_capacity: usize,
+    pub high_pri_usage: usize,
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+struct Entry<V> {
+    value: V,
+    charge: usize,
+    /// Whether the entry is pinned in the high priority pool.
+    high_pri: bool,
+    /// Position in the LRU list of its pool.
+    tick: u64,
+}
+
+/// An LRU cache split in two pools. Blocks inserted with high priority are pinned in the high
+/// priority pool, which evicts its oldest blocks once they take more than `high_pri_capacity`.
+/// The other blocks share the rest of the capacity and are evicted first, so however many of
+/// them are read, the high priority blocks stay.
+struct LruShard<K, V> {
+    capacity: usize,
+    high_pri_capacity: usize,
+    usage: usize,
+    high_pri_usage: usize,
+    entries: HashMap<K, Entry<V>>,
+    /// Keys of each pool, from the least recently used.
+    low_pri_lru: BTreeMap<u64, K>,
+    high_pri_lru: BTreeMap<u64, K>,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl<K: Hash + Eq + Copy, V: Clone> LruShard<K, V> {
+    fn new(capacity: usize, high_pri_capacity: usize) -> Self {
+        Self {
+            capacity,
+            high_pri_capacity,
+            usage: 0,
+            high_pri_usage: 0,
+            entries: HashMap::new(),
+            low_pri_lru: BTreeMap::new(),
+            high_pri_lru: BTreeMap::new(),
+            tick: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Put the entry of `key` at the most recently used end of its pool.
+    fn push(&mut self, key: K) {
+        self.tick += 1;
+        let entry = self.entries.get_mut(&key).unwrap();
+        entry.tick = self.tick;
+        match entry.high_pri {
+            true => self.high_pri_lru.insert(self.tick, key),
+            false => self.low_pri_lru.insert(self.tick, key),
+        };
+    }
+
+    /// Take the entry of `key` out of its LRU list.
+    fn unlink(&mut self, key: &K) {
+        let entry = &self.entries[key];
+        match entry.high_pri {
+            true => self.high_pri_lru.remove(&entry.tick),
+            false => self.low_pri_lru.remove(&entry.tick),
+        };
+    }
+
+    fn remove(&mut self, key: &K) -> Option<Entry<V>> {
+        self.entries.get(key)?;
+        self.unlink(key);
+        let entry = self.entries.remove(key)?;
+        self.usage -= entry.charge;
+        if entry.high_pri {
+            self.high_pri_usage -= entry.charge;
+        }
+        Some(entry)
+    }
+
+    /// Evict the oldest high priority entries until they fit `high_pri_capacity`, then the
+    /// oldest low priority entries until the shard fits its capacity. Returns the evicted
+    /// entries.
+    fn evict(&mut self) -> Vec<(K, V)> {
+        let mut evicted = Vec::new();
+        while self.high_pri_usage > self.high_pri_capacity {
+            let Some((_, key)) = self.high_pri_lru.first_key_value() else {
+                break;
+            };
+            let key = *key;
+            let entry = self.remove(&key).unwrap();
+            evicted.push((key, entry.value));
+        }
+        // the high priority entries fit the capacity on their own, they are only left once the
+        // low priority ones are all evicted
+        while self.usage > self.capacity {
+            let Some((_, key)) = self
+                .low_pri_lru
+                .first_key_value()
+                .or_else(|| self.high_pri_lru.first_key_value())
+            else {
+                break;
+            };
+            let key = *key;
+            let entry = self.remove(&key).unwrap();
+            evicted.push((key, entry.value));
+        }
+        evicted
+    }
+
+    fn get(&mut self, key: &K) -> Option<V> {
+        if !self.entries.contains_key(key) {
+            self.misses += 1;
+            return None;
+        }
+        self.hits += 1;
+        self.unlink(key);
+        self.push(*key);
+        Some(self.entries[key].value.clone())
+    }
+
+    /// Remove the entry of `key` and return its value, counting a hit or a miss.
+    fn take(&mut self, key: &K) -> Option<V> {
+        match self.remove(key) {
+            Some(entry) => {
+                self.hits += 1;
+                Some(entry.value)
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Insert an entry, returning the entries evicted to make room for it. An entry larger than
+    /// the shard is returned as evicted right away, and a high priority entry larger than the
+    /// high priority pool goes to the low priority pool.
+    fn insert(&mut self, key: K, value: V, charge: usize, priority: CachePriority) -> Vec<(K, V)> {
+        self.remove(&key);
+        if charge > self.capacity {
+            return vec![(key, value)];
+        }
+        let high_pri = priority == CachePriority::High && charge <= self.high_pri_capacity;
+        self.entries.insert(
+            key,
+            Entry {
+                value,
+                charge,
+                high_pri,
+                tick: 0,
+            },
+        );
+        self.usage += charge;
+        if high_pri {
+            self.high_pri_usage += charge;
+        }
+        self.push(key);
+        self.evict()
+    }
+
+    fn stats(&self) -> CacheShardStats {
+        CacheShardStats {
+            capacity: self.capacity,
+            usage: self.usage,
+            high_pri_capacity: self.high_pri_capacity,
+            high_pri_usage: self.high_pri_usage,
+            entries: self.entries.len(),
+            hits: self.hits,
+            misses: self.misses,
+        }
+    }
+}
+
+/// Bytes a decoded block is charged in the cache.
+pub(crate) fn block_charge(block: &Block) -> usize {
+    block.data.len() + block.offsets.len() * SIZEOF_U32
+}
+
+/// A sharded LRU cache of decoded SST blocks, shared by the SSTs that are given it. Every SST
+/// takes its own cache id, so a cache can be shared by several storage engines.
+pub struct BlockCache {
+    shards: Vec<Mutex<LruShard<BlockCacheKey, Arc<Block>>>>,
+    next_table_id: AtomicU64,
+    /// Keeps the blocks evicted from the shards.
+    secondary_cache: Option<Arc<dyn SecondaryCache>>,
+}
+
+impl BlockCache {
+    /// Create a cache of `capacity` bytes split over `1 << num_shard_bits` shards, each pinning
+    /// high priority blocks in up to `high_pri_pool_ratio` of its capacity.
+    pub fn new(capacity: usize, num_shard_bits: u32, high_pri_pool_ratio: f64) -> Self {
+        let num_shards = 1 << num_shard_bits;
+        let shard_capacity = capacity / num_shards;
+        let high_pri_capacity =
+            (shard_capacity as f64 * high_pri_pool_ratio.clamp(0.0, 1.0)) as usize;
+        Self {
+            shards: (0..num_shards)
+                .map(|_| Mutex::new(LruShard::new(shard_capacity, high_pri_capacity)))
+                .collect(),
+            next_table_id: AtomicU64::new(0),
+            secondary_cache: None,
+        }
+    }
+
+    /// Move the evicted blocks to `secondary_cache`, where a block missing from this cache is
+    /// looked up before it is read from its SST.
+    pub fn with_secondary_cache(mut self, secondary_cache: Arc<dyn SecondaryCache>) -> Self {
+        self.secondary_cache = Some(secondary_cache);
+        self
+    }
+
+    pub fn secondary_cache(&self) -> Option<&Arc<dyn SecondaryCache>> {
+        self.secondary_cache.as_ref()
+    }
+
+    fn shard(&self, key: &BlockCacheKey) -> &Mutex<LruShard<BlockCacheKey, Arc<Block>>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    /// A cache id for a newly opened SST.
+    pub(crate) fn new_table_id(&self) -> u64 {
+        self.next_table_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Look up a block, falling back to the secondary cache. A block found there moves back to
+    /// this cache.
+    pub fn get(&self, key: &BlockCacheKey) -> Option<Arc<Block>> {
+        if let Some(block) = self.shard(key).lock().get(key) {
+            return Some(block);
+        }
+        let block = Arc::new(self.secondary_cache.as_ref()?.take(key)?);
+        let charge = block_charge(&block);
+        self.insert(*key, block.clone(), charge, CachePriority::Low);
+        Some(block)
+    }
+
+    /// Cache `block`, which takes `charge` bytes. Blocks larger than a shard are not cached.
+    pub fn insert(
+        &self,
+        key: BlockCacheKey,
+        block: Arc<Block>,
+        charge: usize,
+        priority: CachePriority,
+    ) {
+        let evicted = self.shard(&key).lock().insert(key, block, charge, priority);
+        // outside of the shard lock, the secondary cache may write files
+        if let Some(secondary_cache) = &self.secondary_cache {
+            for (key, block) in evicted {
+                secondary_cache.insert(key, &block);
+            }
+        }
+    }
+
+    /// Capacity and usage of every shard.
+    pub fn shard_stats(&self) -> Vec<CacheShardStats> {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().stats())
+            .collect()
+    }
+
+    /// Bytes of the cached blocks.
+    pub fn usage(&self) -> usize {
+        self.shards.iter().map(|shard| shard.lock().usage).sum()
+    }
+}
+
+impl std::fmt::Debug for BlockCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BlockCache")
+            .field("shards", &self.shard_stats())
+            .field(
+                "secondary_cache",
+                &self.secondary_cache.as_ref().map(|cache| cache.stats()),
+            )
+            .finish()
+    }
+}
+
+/// Block cache test
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block() -> Arc<Block> {
+        Arc::new(Block {
+            data: Vec::new(),
+            offsets: Vec::new(),
+        })
+    }
+
+    fn cached(cache: &BlockCache, block_idx: usize) -> bool {
+        cache.shards[0].lock().entries.contains_key(&(0, block_idx))
+    }
+
+    /// Test high priority blocks stay pinned through a scan and lookups of low priority blocks,
+    /// and are only evicted by other high priority blocks
+    #[test]
+    fn test_block_cache_pools() {
+        let cache = BlockCache::new(1000, 0, 0.5);
+        cache.insert((0, 0), block(), 100, CachePriority::High);
+        cache.insert((0, 1), block(), 100, CachePriority::Low);
+        cache.insert((0, 2), block(), 100, CachePriority::Low);
+        assert!(cache.get(&(0, 1)).is_some());
+        assert!(cache.get(&(0, 3)).is_none());
+        // a scan goes through the low priority pool only
+        for block_idx in 10..30 {
+            cache.insert((0, block_idx), block(), 100, CachePriority::Low);
+        }
+        assert!(cached(&cache, 0));
+        assert!(!cached(&cache, 1));
+        assert!(!cached(&cache, 2));
+        assert!(cached(&cache, 29));
+
+        let stats = cache.shard_stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].capacity, 1000);
+        assert_eq!(stats[0].usage, 1000);
+        assert_eq!(stats[0].high_pri_usage, 100);
+        assert_eq!(stats[0].entries, 10);
+        assert_eq!((stats[0].hits, stats[0].misses), (1, 1));
+
+        // the oldest high priority blocks are evicted once the pool is full
+        for block_idx in 30..35 {
+            cache.insert((0, block_idx), block(), 100, CachePriority::High);
+        }
+        assert!(!cached(&cache, 0));
+        assert_eq!(cache.shard_stats()[0].high_pri_usage, 500);
+        // low priority blocks hit again do not push them out either
+        for block_idx in 40..60 {
+            cache.insert((0, block_idx), block(), 100, CachePriority::Low);
+            assert!(cache.get(&(0, block_idx)).is_some());
+        }
+        assert!((30..35).all(|block_idx| cached(&cache, block_idx)));
+        assert!(cached(&cache, 59));
+        assert_eq!(cache.shard_stats()[0].high_pri_usage, 500);
+
+        // larger than the high priority pool, not pinned, so it cannot push the pinned blocks out
+        cache.insert((0, 98), block(), 600, CachePriority::High);
+        assert!(!cached(&cache, 98));
+        assert!((30..35).all(|block_idx| cached(&cache, block_idx)));
+        assert_eq!(cache.shard_stats()[0].high_pri_usage, 500);
+        // too large to be cached
+        cache.insert((0, 99), block(), 2000, CachePriority::Low);
+        assert!(!cached(&cache, 99));
+        assert!(cache.usage() <= 1000);
+    }
+
+    /// Test the capacity is split over the shards
+    #[test]
+    fn test_block_cache_shards() {
+        let cache = BlockCache::new(4000, 2, 0.0);
+        for block_idx in 0..100 {
+            cache.insert((1, block_idx), block(), 100, CachePriority::High);
+        }
+        let stats = cache.shard_stats();
+        assert_eq!(stats.len(), 4);
+        for stats in stats {
+            assert_eq!(stats.capacity, 1000);
+            assert!(stats.usage <= 1000);
+            assert_eq!(stats.high_pri_usage, 0);
+        }
+        assert!(cache.get(&(1, 99)).is_some());
+        assert!(cache.usage() <= 4000);
+    }
+
+    /// Test blocks evicted from the cache are found in the secondary cache
+    #[test]
+    fn test_block_cache_secondary_cache() {
+        let secondary_cache = Arc::new(CompressedSecondaryCache::new(1000, Default::default()));
+        let cache = BlockCache::new(200, 0, 0.0).with_secondary_cache(secondary_cache.clone());
+        let block = Arc::new(Block {
+            data: vec![0; 100],
+            offsets: Vec::new(),
+        });
+        for block_idx in 0..5 {
+            cache.insert((0, block_idx), block.clone(), 100, CachePriority::Low);
+        }
+        assert!(!cached(&cache, 0));
+        assert_eq!(secondary_cache.stats().entries, 3);
+
+        // a hit in the secondary cache moves the block back
+        assert!(cache.get(&(0, 0)).is_some());
+        assert!(cached(&cache, 0));
+        assert!(!cached(&cache, 3));
+        assert_eq!(secondary_cache.stats().entries, 3);
+        assert_eq!(secondary_cache.stats().hits, 1);
+        assert!(cache.get(&(0, 9)).is_none());
+        assert_eq!(secondary_cache.stats().misses, 1);
+    }
+}
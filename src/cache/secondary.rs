@@ -0,0 +1,215 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{Result, bail};
+use bytes::{Buf, BufMut, Bytes};
+use parking_lot::Mutex;
+
+use super::{BlockCacheKey, CachePriority, CacheShardStats, LruShard};
+use crate::{
+    block::{Block, SIZEOF_U32},
+    compress::CompressionType,
+    fs::FileSystem,
+};
+
+/// A second tier behind the `BlockCache`, keeping the blocks it evicts in a cheaper form.
+pub trait SecondaryCache: Send + Sync {
+    /// Keep a block evicted from the primary cache.
+    fn insert(&self, key: BlockCacheKey, block: &Block);
+
+    /// Take the block of `key` out of the cache, it goes back to the primary cache.
+    fn take(&self, key: &BlockCacheKey) -> Option<Block>;
+
+    /// Capacity and usage of the cache, in bytes.
+    fn stats(&self) -> CacheShardStats;
+}
+
+/// Keeps the evicted blocks compressed in memory.
+pub struct CompressedSecondaryCache {
+    shard: Mutex<LruShard<BlockCacheKey, Bytes>>,
+    compression: CompressionType,
+}
+
+impl CompressedSecondaryCache {
+    /// Create a cache of `capacity` bytes of compressed blocks.
+    pub fn new(capacity: usize, compression: CompressionType) -> Self {
+        Self {
+            shard: Mutex::new(LruShard::new(capacity, 0)),
+            compression,
+        }
+    }
+}
+
+impl SecondaryCache for CompressedSecondaryCache {
+    fn insert(&self, key: BlockCacheKey, block: &Block) {
+        let mut buf = Vec::new();
+        self.compression.compress_block(&block.encode(), &mut buf);
+        let charge = buf.len();
+        self.shard
+            .lock()
+            .insert(key, buf.into(), charge, CachePriority::Low);
+    }
+
+    fn take(&self, key: &BlockCacheKey) -> Option<Block> {
+        let raw = self.shard.lock().take(key)?;
+        let data = CompressionType::decompress_block(&raw, None).ok()?;
+        Some(Block::decode(&data))
+    }
+
+    fn stats(&self) -> CacheShardStats {
+        self.shard.lock().stats()
+    }
+}
+
+/// Keeps the evicted blocks in files of a local directory, one file per block.
+pub struct FileSecondaryCache {
+    file_system: Arc<dyn FileSystem>,
+    path: PathBuf,
+    /// The cached blocks and the size of their file.
+    shard: Mutex<LruShard<BlockCacheKey, ()>>,
+}
+
+impl FileSecondaryCache {
+    /// Create a cache of `capacity` bytes of files in `path` of `file_system`. Blocks cached by
+    /// an earlier process are removed, their SSTs have other cache ids now.
+    pub fn new(
+        file_system: Arc<dyn FileSystem>,
+        path: impl AsRef<Path>,
+        capacity: usize,
+    ) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        file_system.create_dir_all(&path)?;
+        for entry in file_system.list(&path)? {
+            if entry.extension().is_some_and(|ext| ext == "blk") {
+                file_system.remove_file(&entry)?;
+            }
+        }
+        Ok(Self {
+            file_system,
+            path,
+            shard: Mutex::new(LruShard::new(capacity, 0)),
+        })
+    }
+
+    fn path_of_block(&self, (table_id, block_idx): &BlockCacheKey) -> PathBuf {
+        self.path.join(format!("{}-{}.blk", table_id, block_idx))
+    }
+
+    /// Write a block file, the cache needs no durability.
+    fn write_block(&self, path: &Path, buf: &[u8]) -> io::Result<()> {
+        let mut file = self.file_system.create(path)?;
+        file.append(buf)?;
+        file.flush()
+    }
+
+    /// Read a block file, checking the checksum at its end.
+    fn read_block(&self, path: &Path) -> Result<Block> {
+        let raw = self.file_system.read(path)?;
+        if raw.len() < SIZEOF_U32 {
+            bail!("cached block file {} is truncated", path.display());
+        }
+        let (data, mut checksum) = raw.split_at(raw.len() - SIZEOF_U32);
+        if crc32fast::hash(data) != checksum.get_u32() {
+            bail!("cached block file {} is corrupted", path.display());
+        }
+        Ok(Block::decode(data))
+    }
+}
+
+impl SecondaryCache for FileSecondaryCache {
+    fn insert(&self, key: BlockCacheKey, block: &Block) {
+        let mut buf = block.encode().to_vec();
+        buf.put_u32(crc32fast::hash(&buf));
+        let path = self.path_of_block(&key);
+        if self.write_block(&path, &buf).is_err() {
+            let _ = self.file_system.remove_file(&path);
+            return;
+        }
+        let evicted = self
+            .shard
+            .lock()
+            .insert(key, (), buf.len(), CachePriority::Low);
+        for (key, ()) in evicted {
+            let _ = self.file_system.remove_file(&self.path_of_block(&key));
+        }
+    }
+
+    fn take(&self, key: &BlockCacheKey) -> Option<Block> {
+        self.shard.lock().take(key)?;
+        let path = self.path_of_block(key);
+        let block = self.read_block(&path).ok();
+        let _ = self.file_system.remove_file(&path);
+        block
+    }
+
+    fn stats(&self) -> CacheShardStats {
+        self.shard.lock().stats()
+    }
+}
+
+/// Secondary cache test
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{block::BlockBuilder, fs::InMemoryFileSystem};
+
+    fn block(i: usize) -> Block {
+        let mut builder = BlockBuilder::new(4096);
+        for j in 0..20 {
+            let key = format!("key_{:03}_{:03}", i, j);
+            assert!(builder.add(key.as_bytes(), &[b'v'; 64]));
+        }
+        builder.build()
+    }
+
+    fn check_secondary_cache(cache: &dyn SecondaryCache) {
+        for i in 0..10 {
+            cache.insert((0, i), &block(i));
+        }
+        let stats = cache.stats();
+        assert!(stats.usage <= stats.capacity);
+        assert!(stats.entries < 10);
+        // the oldest blocks are evicted
+        assert!(cache.take(&(0, 0)).is_none());
+        let taken = cache.take(&(0, 9)).unwrap();
+        assert_eq!(taken.encode(), block(9).encode());
+        // taken blocks leave the cache
+        assert!(cache.take(&(0, 9)).is_none());
+        assert_eq!(cache.stats().entries, stats.entries - 1);
+    }
+
+    /// Test the compressed cache keeps blocks smaller than they are
+    #[test]
+    fn test_compressed_secondary_cache() {
+        let cache = CompressedSecondaryCache::new(1000, CompressionType::Lz4);
+        check_secondary_cache(&cache);
+        assert!(cache.stats().usage < block(0).encode().len() * cache.stats().entries);
+    }
+
+    /// Test the file cache keeps one file per block
+    #[test]
+    fn test_file_secondary_cache() -> Result<()> {
+        let fs = Arc::new(InMemoryFileSystem::new());
+        let dir = Path::new("/cache");
+        let capacity = (block(0).encode().len() + SIZEOF_U32) * 4;
+        let cache = FileSecondaryCache::new(fs.clone(), dir, capacity)?;
+        check_secondary_cache(&cache);
+        assert_eq!(fs.list(dir)?.len(), 3);
+
+        // a corrupted file is a miss
+        let path = cache.path_of_block(&(0, 8));
+        let mut raw = fs.read(&path)?;
+        raw[0] ^= 1;
+        fs.create(&path)?.append(&raw)?;
+        assert!(cache.take(&(0, 8)).is_none());
+
+        // files of an earlier process are removed
+        let cache = FileSecondaryCache::new(fs.clone(), dir, capacity)?;
+        assert!(fs.list(dir)?.is_empty());
+        assert_eq!(cache.stats().entries, 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,122 @@
+use std::{path::Path, sync::Arc};
+
+use anyhow::Result;
+use parking_lot::Mutex;
+
+use super::{BlockCache, CachePriority, CacheShardStats, LruShard};
+use crate::{
+    fs::FileSystem,
+    table::{FileObject, TableReader},
+};
+
+/// An LRU cache of the open SST files and their block meta, keyed by SST id. It bounds the
+/// number of open files, an SST whose reader was evicted opens its file again when read.
+pub struct TableCache {
+    /// Every reader is charged one open file.
+    shard: Mutex<LruShard<usize, Arc<TableReader>>>,
+    file_system: Arc<dyn FileSystem>,
+}
+
+impl TableCache {
+    /// Create a cache keeping at most `max_open_files` SSTs of `file_system` open. Iterators keep
+    /// their SST open until they are dropped, whether it was evicted or not.
+    pub fn new(max_open_files: usize, file_system: Arc<dyn FileSystem>) -> Self {
+        Self {
+            shard: Mutex::new(LruShard::new(max_open_files, 0)),
+            file_system,
+        }
+    }
+
+    pub(crate) fn insert(&self, sst_id: usize, reader: Arc<TableReader>) {
+        let evicted = self
+            .shard
+            .lock()
+            .insert(sst_id, reader, 1, CachePriority::Low);
+        // the files are closed outside of the lock
+        drop(evicted);
+    }
+
+    /// Get the reader of the SST `sst_id`, opening `path` when it is not cached. The meta section
+    /// is read through `block_cache`, with the cache id of the SST.
+    pub(crate) fn get_or_open(
+        &self,
+        sst_id: usize,
+        path: &Path,
+        block_cache: Option<(&BlockCache, u64)>,
+    ) -> Result<Arc<TableReader>> {
+        if let Some(reader) = self.shard.lock().get(&sst_id) {
+            return Ok(reader);
+        }
+        let file = FileObject::open(self.file_system.as_ref(), path)?;
+        let (reader, _) = TableReader::open_cached(sst_id, file, block_cache)?;
+        let reader = Arc::new(reader);
+        self.insert(sst_id, reader.clone());
+        Ok(reader)
+    }
+
+    /// Close the file of a removed SST.
+    pub(crate) fn evict(&self, sst_id: usize) {
+        let removed = self.shard.lock().remove(&sst_id);
+        drop(removed);
+    }
+
+    /// The maximum and current number of open files.
+    pub fn stats(&self) -> CacheShardStats {
+        self.shard.lock().stats()
+    }
+}
+
+impl std::fmt::Debug for TableCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TableCache")
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+/// Table cache test
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::{
+        fs::StdFileSystem,
+        table::{SsTable, SsTableBuilder, TableValue},
+    };
+
+    /// Test SSTs evicted from the table cache are opened again
+    #[test]
+    fn test_table_cache() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let table_cache = Arc::new(TableCache::new(2, Arc::new(StdFileSystem)));
+        let tables = (0..5)
+            .map(|id| {
+                let mut builder = SsTableBuilder::new(128);
+                for idx in 0..20 {
+                    builder.add(format!("key_{:02}", idx).as_bytes(), &[b'0' + id as u8]);
+                }
+                Ok(builder
+                    .build(id, dir.path().join(format!("{}.sst", id)))?
+                    .with_table_cache(Some(table_cache.clone())))
+            })
+            .collect::<Result<Vec<SsTable>>>()?;
+        assert_eq!(table_cache.stats().usage, 2);
+
+        for (id, table) in tables.iter().enumerate() {
+            assert_eq!(
+                table.get(b"key_07")?,
+                Some(TableValue::Inline(Bytes::from(vec![b'0' + id as u8])))
+            );
+        }
+        let stats = table_cache.stats();
+        assert_eq!((stats.capacity, stats.usage), (2, 2));
+        assert_eq!((stats.hits, stats.misses), (0, 5));
+
+        table_cache.evict(4);
+        assert_eq!(table_cache.stats().usage, 1);
+        std::fs::remove_file(dir.path().join("0.sst"))?;
+        assert!(tables[0].get(b"key_07").is_err());
+        Ok(())
+    }
+}
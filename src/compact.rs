@@ -1,10 +1,20 @@
+mod fifo;
 mod leveld;
 mod simple_leveld;
+pub mod simulator;
 mod tiered;
 
-pub use leveld::LeveledCompactionOptions;
-pub use simple_leveld::SimpleLeveledCompactionOptions;
-pub use tiered::TieredCompactionOptions;
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+
+pub use fifo::{FifoCompactionController, FifoCompactionOptions, FifoCompactionTask};
+pub use leveld::{LeveledCompactionController, LeveledCompactionOptions, LeveledCompactionTask};
+pub use simple_leveld::{
+    SimpleLeveledCompactionController, SimpleLeveledCompactionOptions, SimpleLeveledCompactionTask,
+};
+pub use tiered::{TieredCompactionController, TieredCompactionOptions, TieredCompactionTask};
+
+use crate::{error::Error, lsm_storage::LsmStorageState};
 
 #[derive(Debug, Clone)]
 pub enum CompactionOptions {
@@ -15,6 +25,166 @@ pub enum CompactionOptions {
     Tiered(TieredCompactionOptions),
     /// Simple leveled compaction
     Simple(SimpleLeveledCompactionOptions),
+    /// FIFO compaction, everything stays in L0 and the oldest SSTs are deleted (= RocksDB's FIFO
+    /// Compaction)
+    Fifo(FifoCompactionOptions),
     /// In no compaction mode (week 1), always flush to L0
     NoCompaction,
 }
+
+/// A compaction job, recorded in the manifest along with the SSTs it produced.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CompactionTask {
+    Leveled(LeveledCompactionTask),
+    Tiered(TieredCompactionTask),
+    Simple(SimpleLeveledCompactionTask),
+    /// A compaction the controller did not pick, requested by `MiniLsm::compact_range` or of a
+    /// stale SST, from a level to the next one or within the last level.
+    Range(LeveledCompactionTask),
+    Fifo(FifoCompactionTask),
+}
+
+impl CompactionTask {
+    /// Whether the output is the last sorted run, so tombstones can be dropped.
+    pub fn compact_to_bottom_level(&self) -> bool {
+        match self {
+            CompactionTask::Leveled(task) => task.is_lower_level_bottom_level,
+            CompactionTask::Tiered(task) => task.bottom_tier_included,
+            CompactionTask::Simple(task) => task.is_lower_level_bottom_level,
+            CompactionTask::Range(task) => task.is_lower_level_bottom_level,
+            // older SSTs may be left in L0
+            CompactionTask::Fifo(_) => false,
+        }
+    }
+
+    /// The level the output goes to, tiers count as L1 and FIFO SSTs as L0.
+    pub fn output_level(&self) -> usize {
+        match self {
+            CompactionTask::Leveled(task) => task.lower_level,
+            CompactionTask::Tiered(_) => 1,
+            CompactionTask::Simple(task) => task.lower_level,
+            CompactionTask::Range(task) => task.lower_level,
+            CompactionTask::Fifo(_) => 0,
+        }
+    }
+
+    /// The SSTs merged by the task, from the newest to the oldest data.
+    pub fn input_sst_ids(&self) -> Vec<usize> {
+        match self {
+            CompactionTask::Leveled(LeveledCompactionTask {
+                upper_level_sst_ids,
+                lower_level_sst_ids,
+                ..
+            })
+            | CompactionTask::Range(LeveledCompactionTask {
+                upper_level_sst_ids,
+                lower_level_sst_ids,
+                ..
+            })
+            | CompactionTask::Simple(SimpleLeveledCompactionTask {
+                upper_level_sst_ids,
+                lower_level_sst_ids,
+                ..
+            }) => upper_level_sst_ids
+                .iter()
+                .chain(lower_level_sst_ids)
+                .copied()
+                .collect(),
+            CompactionTask::Tiered(task) => task
+                .l0_sstables
+                .iter()
+                .chain(task.tiers.iter().flat_map(|(_, ids)| ids))
+                .copied()
+                .collect(),
+            CompactionTask::Fifo(FifoCompactionTask::Merge(ids)) => ids.clone(),
+            CompactionTask::Fifo(FifoCompactionTask::Delete(_)) => Vec::new(),
+        }
+    }
+}
+
+/// Decides which SSTs of a column family to compact, and how the levels change afterwards.
+#[derive(Debug, Clone)]
+pub enum CompactionController {
+    Leveled(LeveledCompactionController),
+    Tiered(TieredCompactionController),
+    Simple(SimpleLeveledCompactionController),
+    Fifo(FifoCompactionController),
+    NoCompaction,
+}
+
+impl CompactionController {
+    pub fn new(options: &CompactionOptions) -> Self {
+        match options {
+            CompactionOptions::Leveled(options) => {
+                Self::Leveled(LeveledCompactionController::new(options.clone()))
+            }
+            CompactionOptions::Tiered(options) => {
+                Self::Tiered(TieredCompactionController::new(options.clone()))
+            }
+            CompactionOptions::Simple(options) => {
+                Self::Simple(SimpleLeveledCompactionController::new(options.clone()))
+            }
+            CompactionOptions::Fifo(options) => {
+                Self::Fifo(FifoCompactionController::new(options.clone()))
+            }
+            CompactionOptions::NoCompaction => Self::NoCompaction,
+        }
+    }
+
+    pub fn generate_compaction_task(&self, snapshot: &LsmStorageState) -> Option<CompactionTask> {
+        match self {
+            CompactionController::Leveled(ctrl) => ctrl
+                .generate_compaction_task(snapshot)
+                .map(CompactionTask::Leveled),
+            CompactionController::Tiered(ctrl) => ctrl
+                .generate_compaction_task(snapshot)
+                .map(CompactionTask::Tiered),
+            CompactionController::Simple(ctrl) => ctrl
+                .generate_compaction_task(snapshot)
+                .map(CompactionTask::Simple),
+            CompactionController::Fifo(ctrl) => ctrl
+                .generate_compaction_task(snapshot)
+                .map(CompactionTask::Fifo),
+            CompactionController::NoCompaction => None,
+        }
+    }
+
+    /// Apply the result of `task`, returning the new state and the SSTs to remove. `snapshot`
+    /// must hold the output SSTs unless `in_recovery` is set, the output may hold input SSTs
+    /// that were moved without being rewritten.
+    pub fn apply_compaction_result(
+        &self,
+        snapshot: &LsmStorageState,
+        task: &CompactionTask,
+        output: &[usize],
+        in_recovery: bool,
+    ) -> Result<(LsmStorageState, Vec<usize>)> {
+        let (snapshot, mut removed) = match (self, task) {
+            (CompactionController::Leveled(ctrl), CompactionTask::Leveled(task)) => {
+                ctrl.apply_compaction_result(snapshot, task, output, in_recovery)
+            }
+            (CompactionController::Tiered(ctrl), CompactionTask::Tiered(task)) => {
+                ctrl.apply_compaction_result(snapshot, task, output)
+            }
+            (CompactionController::Simple(ctrl), CompactionTask::Simple(task)) => {
+                ctrl.apply_compaction_result(snapshot, task, output)
+            }
+            (CompactionController::Fifo(ctrl), CompactionTask::Fifo(task)) => {
+                ctrl.apply_compaction_result(snapshot, task, output)
+            }
+            (
+                CompactionController::Leveled(_) | CompactionController::Simple(_),
+                CompactionTask::Range(task),
+            ) => {
+                LeveledCompactionController::apply_level_result(snapshot, task, output, in_recovery)
+            }
+            _ => bail!(Error::InvalidArgument(format!(
+                "compaction task {:?} does not match the compaction options",
+                task
+            ))),
+        };
+        // the SSTs of a trivial move are both inputs and outputs
+        removed.retain(|id| !output.contains(id));
+        Ok((snapshot, removed))
+    }
+}
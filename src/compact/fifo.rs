@@ -0,0 +1,110 @@
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::lsm_storage::LsmStorageState;
+
+#[derive(Debug, Clone)]
+pub struct FifoCompactionOptions {
+    /// The oldest SSTs are deleted once all SSTs add up to more bytes.
+    pub max_table_files_size: u64,
+    /// SSTs whose newest data is older are deleted.
+    pub ttl: Option<Duration>,
+    /// Merge the newest L0 SSTs into one once there are that many.
+    pub intra_l0_compaction_trigger: Option<usize>,
+    /// The newest L0 SSTs are only merged while they add up to fewer bytes, so a merged SST is
+    /// never rewritten again once it has grown this large.
+    pub intra_l0_max_table_size: u64,
+}
+
+/// Delete or merge L0 SSTs, the only level FIFO compaction uses.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FifoCompactionTask {
+    /// Delete the oldest SSTs, nothing is written.
+    Delete(Vec<usize>),
+    /// Merge consecutive SSTs into a single one that takes their place.
+    Merge(Vec<usize>),
+}
+
+/// Keeps every SST in L0 and drops the oldest ones once they are too large or too old, for data
+/// that is only needed for a while.
+#[derive(Debug, Clone)]
+pub struct FifoCompactionController {
+    options: FifoCompactionOptions,
+}
+
+impl FifoCompactionController {
+    pub fn new(options: FifoCompactionOptions) -> Self {
+        Self { options }
+    }
+
+    pub fn generate_compaction_task(
+        &self,
+        snapshot: &LsmStorageState,
+    ) -> Option<FifoCompactionTask> {
+        let now = SystemTime::now();
+        let mut total_size = snapshot
+            .l0_sstable
+            .iter()
+            .map(|id| snapshot.sstables[id].table_size())
+            .sum::<u64>();
+        let mut deleted = Vec::new();
+        // from the oldest SST
+        for id in snapshot.l0_sstable.iter().rev() {
+            let table = &snapshot.sstables[id];
+            let expired = self.options.ttl.is_some_and(|ttl| {
+                now.duration_since(table.creation_time())
+                    .is_ok_and(|age| age >= ttl)
+            });
+            if total_size <= self.options.max_table_files_size && !expired {
+                break;
+            }
+            total_size -= table.table_size();
+            deleted.push(*id);
+        }
+        if !deleted.is_empty() {
+            return Some(FifoCompactionTask::Delete(deleted));
+        }
+
+        let trigger = self.options.intra_l0_compaction_trigger?.max(2);
+        let mut merged = Vec::new();
+        let mut merged_size = 0;
+        // from the newest SST, stopping at the first one too large to merge
+        for id in &snapshot.l0_sstable {
+            merged_size += snapshot.sstables[id].table_size();
+            if merged_size >= self.options.intra_l0_max_table_size {
+                break;
+            }
+            merged.push(*id);
+        }
+        if merged.len() < trigger {
+            return None;
+        }
+        Some(FifoCompactionTask::Merge(merged))
+    }
+
+    /// Apply the result of `task`, returning the new state and the SSTs to remove.
+    pub fn apply_compaction_result(
+        &self,
+        snapshot: &LsmStorageState,
+        task: &FifoCompactionTask,
+        output: &[usize],
+    ) -> (LsmStorageState, Vec<usize>) {
+        let mut snapshot = snapshot.clone();
+        let removed = match task {
+            FifoCompactionTask::Delete(ids) => ids,
+            FifoCompactionTask::Merge(ids) => {
+                // SSTs flushed since the task was generated stay in front of the output
+                let pos = snapshot
+                    .l0_sstable
+                    .iter()
+                    .position(|id| ids.contains(id))
+                    .unwrap_or(snapshot.l0_sstable.len());
+                snapshot.l0_sstable.splice(pos..pos, output.iter().copied());
+                ids
+            }
+        };
+        snapshot.l0_sstable.retain(|id| !removed.contains(id));
+        (snapshot, removed.clone())
+    }
+}
@@ -1,3 +1,7 @@
+use serde::{Deserialize, Serialize};
+
+use crate::lsm_storage::LsmStorageState;
+
 #[derive(Debug, Clone)]
 pub struct LeveledCompactionOptions {
     pub level_size_multiplier: usize,
@@ -5,3 +9,153 @@ pub struct LeveledCompactionOptions {
     pub max_levels: usize,
     pub base_level_size_mb: usize,
 }
+
+/// Merge some SSTs of a level, or all of L0 when `upper_level` is `None`, with the SSTs they
+/// overlap in the level below.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LeveledCompactionTask {
+    pub upper_level: Option<usize>,
+    pub upper_level_sst_ids: Vec<usize>,
+    pub lower_level: usize,
+    pub lower_level_sst_ids: Vec<usize>,
+    pub is_lower_level_bottom_level: bool,
+}
+
+/// Compacts L0 into the base level, and one SST at a time from the level that exceeds its
+/// target size the most. The target sizes grow by `level_size_multiplier` from the base level to
+/// the last level, and levels above the base level are left empty.
+#[derive(Debug, Clone)]
+pub struct LeveledCompactionController {
+    options: LeveledCompactionOptions,
+}
+
+impl LeveledCompactionController {
+    pub fn new(options: LeveledCompactionOptions) -> Self {
+        Self { options }
+    }
+
+    /// The SSTs of `level` overlapping the key range of `sst_ids`.
+    fn find_overlapping_ssts(
+        snapshot: &LsmStorageState,
+        sst_ids: &[usize],
+        level: usize,
+    ) -> Vec<usize> {
+        let tables = sst_ids.iter().map(|id| &snapshot.sstables[id]);
+        let (Some(first_key), Some(last_key)) = (
+            tables.clone().map(|table| table.first_key()).min(),
+            tables.map(|table| table.last_key()).max(),
+        ) else {
+            return Vec::new();
+        };
+        snapshot.levels[level - 1]
+            .1
+            .iter()
+            .filter(|id| {
+                let table = &snapshot.sstables[*id];
+                table.first_key() <= last_key && first_key <= table.last_key()
+            })
+            .copied()
+            .collect()
+    }
+
+    pub fn generate_compaction_task(
+        &self,
+        snapshot: &LsmStorageState,
+    ) -> Option<LeveledCompactionTask> {
+        let max_levels = self.options.max_levels;
+        let real_sizes = snapshot
+            .levels
+            .iter()
+            .map(|(_, ids)| {
+                ids.iter()
+                    .map(|id| snapshot.sstables[id].table_size())
+                    .sum::<u64>()
+            })
+            .collect::<Vec<_>>();
+        let base_level_size = self.options.base_level_size_mb as u64 * 1024 * 1024;
+        let mut target_sizes = vec![0; max_levels];
+        target_sizes[max_levels - 1] = real_sizes[max_levels - 1].max(base_level_size);
+        let mut base_level = max_levels;
+        for level in (0..max_levels - 1).rev() {
+            if target_sizes[level + 1] > base_level_size {
+                target_sizes[level] =
+                    target_sizes[level + 1] / self.options.level_size_multiplier as u64;
+            }
+            if target_sizes[level] > 0 {
+                base_level = level + 1;
+            }
+        }
+
+        if snapshot.l0_sstable.len() >= self.options.level0_file_num_compaction_trigger {
+            return Some(LeveledCompactionTask {
+                upper_level: None,
+                upper_level_sst_ids: snapshot.l0_sstable.clone(),
+                lower_level: base_level,
+                lower_level_sst_ids: Self::find_overlapping_ssts(
+                    snapshot,
+                    &snapshot.l0_sstable,
+                    base_level,
+                ),
+                is_lower_level_bottom_level: base_level == max_levels,
+            });
+        }
+
+        let (_, level) = (0..max_levels - 1)
+            .map(|level| {
+                (
+                    real_sizes[level] as f64 / target_sizes[level] as f64,
+                    level + 1,
+                )
+            })
+            .filter(|(priority, _)| *priority > 1.0)
+            .max_by(|a, b| a.0.total_cmp(&b.0))?;
+        // the oldest SST of the level goes first
+        let selected = *snapshot.levels[level - 1].1.iter().min()?;
+        Some(LeveledCompactionTask {
+            upper_level: Some(level),
+            upper_level_sst_ids: vec![selected],
+            lower_level: level + 1,
+            lower_level_sst_ids: Self::find_overlapping_ssts(snapshot, &[selected], level + 1),
+            is_lower_level_bottom_level: level + 1 == max_levels,
+        })
+    }
+
+    /// Apply the result of `task`, returning the new state and the SSTs to remove. The SSTs are
+    /// not opened during recovery, the levels are sorted once they are.
+    pub fn apply_compaction_result(
+        &self,
+        snapshot: &LsmStorageState,
+        task: &LeveledCompactionTask,
+        output: &[usize],
+        in_recovery: bool,
+    ) -> (LsmStorageState, Vec<usize>) {
+        Self::apply_level_result(snapshot, task, output, in_recovery)
+    }
+
+    /// Replace the inputs of `task` with `output` in the lower level, whichever controller
+    /// generated the task.
+    pub fn apply_level_result(
+        snapshot: &LsmStorageState,
+        task: &LeveledCompactionTask,
+        output: &[usize],
+        in_recovery: bool,
+    ) -> (LsmStorageState, Vec<usize>) {
+        let mut snapshot = snapshot.clone();
+        let upper_ids = match task.upper_level {
+            Some(upper_level) => &mut snapshot.levels[upper_level - 1].1,
+            // SSTs flushed since the task was generated stay in L0
+            None => &mut snapshot.l0_sstable,
+        };
+        upper_ids.retain(|id| !task.upper_level_sst_ids.contains(id));
+        let lower_ids = &mut snapshot.levels[task.lower_level - 1].1;
+        lower_ids.retain(|id| !task.lower_level_sst_ids.contains(id));
+        lower_ids.extend(output);
+        if !in_recovery {
+            let sstables = &snapshot.sstables;
+            lower_ids.sort_by(|a, b| sstables[a].first_key().cmp(sstables[b].first_key()));
+        }
+        let mut removed = task.upper_level_sst_ids.clone();
+        removed.extend(&task.lower_level_sst_ids);
+        (snapshot, removed)
+    }
+}
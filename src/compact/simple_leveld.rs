@@ -1,6 +1,90 @@
+use serde::{Deserialize, Serialize};
+
+use crate::lsm_storage::LsmStorageState;
+
 #[derive(Debug, Clone)]
 pub struct SimpleLeveledCompactionOptions {
     pub size_ratio_percent: usize,
     pub level0_file_num_compaction_trigger: usize,
     pub max_levels: usize,
 }
+
+/// Merge a whole level, or L0 when `upper_level` is `None`, into the level below it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SimpleLeveledCompactionTask {
+    pub upper_level: Option<usize>,
+    pub upper_level_sst_ids: Vec<usize>,
+    pub lower_level: usize,
+    pub lower_level_sst_ids: Vec<usize>,
+    pub is_lower_level_bottom_level: bool,
+}
+
+/// Compacts a level into the next one when the number of SSTs of the next level is too small
+/// compared to it.
+#[derive(Debug, Clone)]
+pub struct SimpleLeveledCompactionController {
+    options: SimpleLeveledCompactionOptions,
+}
+
+impl SimpleLeveledCompactionController {
+    pub fn new(options: SimpleLeveledCompactionOptions) -> Self {
+        Self { options }
+    }
+
+    pub fn generate_compaction_task(
+        &self,
+        snapshot: &LsmStorageState,
+    ) -> Option<SimpleLeveledCompactionTask> {
+        let mut level_sizes = vec![snapshot.l0_sstable.len()];
+        level_sizes.extend(snapshot.levels.iter().map(|(_, ids)| ids.len()));
+        for upper in 0..self.options.max_levels {
+            if upper == 0
+                && snapshot.l0_sstable.len() < self.options.level0_file_num_compaction_trigger
+            {
+                continue;
+            }
+            let lower = upper + 1;
+            if level_sizes[upper] == 0 {
+                continue;
+            }
+            let size_ratio = level_sizes[lower] as f64 / level_sizes[upper] as f64;
+            if size_ratio < self.options.size_ratio_percent as f64 / 100.0 {
+                return Some(SimpleLeveledCompactionTask {
+                    upper_level: (upper > 0).then_some(upper),
+                    upper_level_sst_ids: if upper == 0 {
+                        snapshot.l0_sstable.clone()
+                    } else {
+                        snapshot.levels[upper - 1].1.clone()
+                    },
+                    lower_level: lower,
+                    lower_level_sst_ids: snapshot.levels[lower - 1].1.clone(),
+                    is_lower_level_bottom_level: lower == self.options.max_levels,
+                });
+            }
+        }
+        None
+    }
+
+    /// Apply the result of `task`, returning the new state and the SSTs to remove.
+    pub fn apply_compaction_result(
+        &self,
+        snapshot: &LsmStorageState,
+        task: &SimpleLeveledCompactionTask,
+        output: &[usize],
+    ) -> (LsmStorageState, Vec<usize>) {
+        let mut snapshot = snapshot.clone();
+        let mut removed = task.upper_level_sst_ids.clone();
+        match task.upper_level {
+            Some(upper_level) => snapshot.levels[upper_level - 1]
+                .1
+                .retain(|id| !task.upper_level_sst_ids.contains(id)),
+            // SSTs flushed since the task was generated stay in L0
+            None => snapshot
+                .l0_sstable
+                .retain(|id| !task.upper_level_sst_ids.contains(id)),
+        }
+        removed.extend(&task.lower_level_sst_ids);
+        snapshot.levels[task.lower_level - 1].1 = output.to_vec();
+        (snapshot, removed)
+    }
+}
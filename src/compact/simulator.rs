@@ -0,0 +1,277 @@
+//! Drives the compaction controllers against a synthetic sequence of flushes. The SSTs only
+//! hold their metadata and live in memory, so nothing touches the disk.
+
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    sync::Arc,
+};
+
+use anyhow::{Result, bail};
+use bytes::Bytes;
+
+use super::{
+    CompactionController, CompactionOptions, LeveledCompactionOptions,
+    SimpleLeveledCompactionOptions,
+};
+use crate::{lsm_storage::LsmStorageState, mem_table::MemTable, table::SsTable};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Workload {
+    /// Every flush holds keys drawn from the whole key space, later flushes overwrite them.
+    Uniform,
+    /// Every flush holds keys after the ones of the previous flush.
+    Sequential,
+}
+
+impl FromStr for Workload {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "uniform" => Ok(Self::Uniform),
+            "sequential" => Ok(Self::Sequential),
+            _ => bail!("unknown workload {}", s),
+        }
+    }
+}
+
+/// The compaction strategy and the workload of a simulation.
+pub struct SimulatorOptions {
+    pub compaction_options: CompactionOptions,
+    pub keys_per_sst: usize,
+    pub entry_size: u64,
+    pub workload: Workload,
+    pub key_space: u64,
+    pub seed: u64,
+}
+
+/// The state of a simulated column family, with the keys of every SST.
+pub struct Simulator {
+    options: SimulatorOptions,
+    controller: CompactionController,
+    state: LsmStorageState,
+    keys: HashMap<usize, Vec<u64>>,
+    live_keys: HashSet<u64>,
+    next_sst_id: usize,
+    next_key: u64,
+    rng: u64,
+    flushed_bytes: u64,
+    compacted_bytes: u64,
+}
+
+impl Simulator {
+    pub fn new(options: SimulatorOptions) -> Self {
+        let levels = match &options.compaction_options {
+            CompactionOptions::Leveled(LeveledCompactionOptions { max_levels, .. })
+            | CompactionOptions::Simple(SimpleLeveledCompactionOptions { max_levels, .. }) => {
+                (1..=*max_levels).map(|level| (level, Vec::new())).collect()
+            }
+            _ => Vec::new(),
+        };
+        Self {
+            controller: CompactionController::new(&options.compaction_options),
+            state: LsmStorageState {
+                memtable: Arc::new(MemTable::create(0)),
+                imm_memtable: Vec::new(),
+                l0_sstable: Vec::new(),
+                levels,
+                sstables: HashMap::new(),
+                blob_files: HashMap::new(),
+            },
+            keys: HashMap::new(),
+            live_keys: HashSet::new(),
+            next_sst_id: 1,
+            next_key: 0,
+            rng: options.seed.max(1),
+            flushed_bytes: 0,
+            compacted_bytes: 0,
+            options,
+        }
+    }
+
+    /// xorshift64, good enough to spread the keys.
+    fn next_random(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+
+    /// Add an SST holding `keys`, which are sorted and distinct, returning its id.
+    fn add_sst(&mut self, keys: Vec<u64>) -> usize {
+        let sst_id = self.next_sst_id;
+        self.next_sst_id += 1;
+        let table = SsTable::create_meta_only(
+            sst_id,
+            keys.len() as u64 * self.options.entry_size,
+            Bytes::copy_from_slice(&keys[0].to_be_bytes()),
+            Bytes::copy_from_slice(&keys[keys.len() - 1].to_be_bytes()),
+            keys.len() as u64,
+        );
+        self.state.sstables.insert(sst_id, Arc::new(table));
+        self.keys.insert(sst_id, keys);
+        sst_id
+    }
+
+    /// Flush an SST of new keys to L0.
+    pub fn flush(&mut self) {
+        let keys_per_sst = self.options.keys_per_sst as u64;
+        let mut keys = match self.options.workload {
+            Workload::Uniform => (0..keys_per_sst)
+                .map(|_| self.next_random() % self.options.key_space)
+                .collect::<Vec<_>>(),
+            Workload::Sequential => (self.next_key..self.next_key + keys_per_sst).collect(),
+        };
+        self.next_key += keys_per_sst;
+        keys.sort();
+        keys.dedup();
+        self.live_keys.extend(&keys);
+        self.flushed_bytes += keys.len() as u64 * self.options.entry_size;
+        let sst_id = self.add_sst(keys);
+        self.state.l0_sstable.insert(0, sst_id);
+    }
+
+    /// Run the compactions the controller asks for, returning how many ran.
+    pub fn compact(&mut self) -> Result<usize> {
+        let mut compactions = 0;
+        while let Some(task) = self.controller.generate_compaction_task(&self.state) {
+            let mut inputs = task.input_sst_ids();
+            inputs.sort_by(|a, b| {
+                let sstables = &self.state.sstables;
+                sstables[a].first_key().cmp(sstables[b].first_key())
+            });
+            // the inputs are moved as they are when none of them overlap, like the engine does
+            let trivial_move = inputs.windows(2).all(|pair| {
+                let sstables = &self.state.sstables;
+                sstables[&pair[0]].last_key() < sstables[&pair[1]].first_key()
+            });
+            let output = if trivial_move {
+                inputs
+            } else {
+                let mut keys = inputs
+                    .iter()
+                    .flat_map(|id| self.keys[id].iter().copied())
+                    .collect::<Vec<_>>();
+                keys.sort();
+                keys.dedup();
+                self.compacted_bytes += keys.len() as u64 * self.options.entry_size;
+                keys.chunks(self.options.keys_per_sst)
+                    .map(|chunk| self.add_sst(chunk.to_vec()))
+                    .collect()
+            };
+            let (state, removed) =
+                self.controller
+                    .apply_compaction_result(&self.state, &task, &output, false)?;
+            self.state = state;
+            for sst_id in removed {
+                self.state.sstables.remove(&sst_id);
+                self.keys.remove(&sst_id);
+            }
+            compactions += 1;
+            if compactions > 10000 {
+                bail!("compaction does not converge");
+            }
+        }
+        Ok(compactions)
+    }
+
+    /// The bytes of the SSTs `ids`.
+    pub fn size_of(&self, ids: &[usize]) -> u64 {
+        ids.iter()
+            .map(|id| self.state.sstables[id].table_size())
+            .sum()
+    }
+
+    /// Bytes written by flushes and compactions for every flushed byte.
+    pub fn write_amplification(&self) -> f64 {
+        (self.flushed_bytes + self.compacted_bytes) as f64 / self.flushed_bytes.max(1) as f64
+    }
+
+    /// Bytes of all SSTs for every byte of distinct keys.
+    pub fn space_amplification(&self) -> f64 {
+        let total = self.size_of(&self.state.sstables.keys().copied().collect::<Vec<_>>());
+        total as f64 / (self.live_keys.len() as u64 * self.options.entry_size).max(1) as f64
+    }
+
+    /// Number of SSTs a point lookup may read: every L0 SST and one SST per sorted run.
+    pub fn read_amplification(&self) -> usize {
+        self.state.l0_sstable.len()
+            + self
+                .state
+                .levels
+                .iter()
+                .filter(|(_, ids)| !ids.is_empty())
+                .count()
+    }
+
+    pub fn options(&self) -> &SimulatorOptions {
+        &self.options
+    }
+
+    /// The L0 SSTs and the levels or tiers, as in `LsmStorageState`.
+    pub fn state(&self) -> &LsmStorageState {
+        &self.state
+    }
+}
+
+/// Compaction simulator test
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compact::TieredCompactionOptions;
+
+    fn simulate(compaction_options: CompactionOptions, workload: Workload) -> Result<Simulator> {
+        let mut simulator = Simulator::new(SimulatorOptions {
+            compaction_options,
+            keys_per_sst: 256,
+            entry_size: 1024,
+            workload,
+            key_space: 20000,
+            seed: 1,
+        });
+        for _ in 0..100 {
+            simulator.flush();
+            simulator.compact()?;
+        }
+        Ok(simulator)
+    }
+
+    /// Test every strategy keeps the distinct keys and bounds the number of sorted runs, and
+    /// sequential keys are only moved
+    #[test]
+    fn test_simulator() -> Result<()> {
+        let strategies = [
+            CompactionOptions::Simple(SimpleLeveledCompactionOptions {
+                size_ratio_percent: 200,
+                level0_file_num_compaction_trigger: 2,
+                max_levels: 4,
+            }),
+            CompactionOptions::Leveled(LeveledCompactionOptions {
+                level_size_multiplier: 10,
+                level0_file_num_compaction_trigger: 2,
+                max_levels: 4,
+                base_level_size_mb: 8,
+            }),
+            CompactionOptions::Tiered(TieredCompactionOptions {
+                num_tiers: 8,
+                max_size_amplification_percent: 200,
+                size_ratio: 1,
+                min_merge_width: 2,
+                max_merge_width: None,
+            }),
+        ];
+        for strategy in strategies {
+            let simulator = simulate(strategy.clone(), Workload::Uniform)?;
+            let keys = simulator.keys.values().flatten().collect::<HashSet<_>>();
+            assert_eq!(keys.len(), simulator.live_keys.len(), "{:?}", strategy);
+            assert!(simulator.write_amplification() > 1.0, "{:?}", strategy);
+            assert!(simulator.space_amplification() >= 1.0, "{:?}", strategy);
+            assert!(simulator.read_amplification() <= 10, "{:?}", strategy);
+
+            let simulator = simulate(strategy.clone(), Workload::Sequential)?;
+            assert_eq!(simulator.write_amplification(), 1.0, "{:?}", strategy);
+        }
+        Ok(())
+    }
+}
@@ -1,3 +1,7 @@
+use serde::{Deserialize, Serialize};
+
+use crate::lsm_storage::LsmStorageState;
+
 #[derive(Debug, Clone)]
 pub struct TieredCompactionOptions {
     pub num_tiers: usize,
@@ -6,3 +10,109 @@ pub struct TieredCompactionOptions {
     pub min_merge_width: usize,
     pub max_merge_width: Option<usize>,
 }
+
+/// Merge the newest sorted runs into a new tier: the L0 SSTs, each its own run, then the first
+/// tiers of the levels.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TieredCompactionTask {
+    pub l0_sstables: Vec<usize>,
+    pub tiers: Vec<(usize, Vec<usize>)>,
+    pub bottom_tier_included: bool,
+}
+
+/// Compacts sorted runs like RocksDB's universal compaction. Flushed SSTs stay in L0 and count
+/// as the newest runs, each compaction merges the newest runs into a tier named after its first
+/// SST.
+#[derive(Debug, Clone)]
+pub struct TieredCompactionController {
+    options: TieredCompactionOptions,
+}
+
+impl TieredCompactionController {
+    pub fn new(options: TieredCompactionOptions) -> Self {
+        Self { options }
+    }
+
+    pub fn generate_compaction_task(
+        &self,
+        snapshot: &LsmStorageState,
+    ) -> Option<TieredCompactionTask> {
+        // number of SSTs of the sorted runs, from the newest to the oldest
+        let runs = snapshot
+            .l0_sstable
+            .iter()
+            .map(|_| 1)
+            .chain(snapshot.levels.iter().map(|(_, ids)| ids.len()))
+            .collect::<Vec<_>>();
+        if runs.len() < self.options.num_tiers.max(2) {
+            return None;
+        }
+
+        // space amplification: everything above the last run compared to the last run
+        let last_size = runs[runs.len() - 1];
+        let upper_size = runs[..runs.len() - 1].iter().sum::<usize>();
+        if upper_size as f64 / last_size as f64 * 100.0
+            >= self.options.max_size_amplification_percent as f64
+        {
+            return Some(self.task(snapshot, runs.len()));
+        }
+
+        // size ratio: the next run is much larger than the runs above it
+        let size_ratio_trigger = (100.0 + self.options.size_ratio as f64) / 100.0;
+        let mut size = 0;
+        for idx in 0..runs.len() - 1 {
+            size += runs[idx];
+            let next_size = runs[idx + 1];
+            if next_size as f64 / size as f64 > size_ratio_trigger
+                && idx + 1 >= self.options.min_merge_width
+            {
+                return Some(self.task(snapshot, idx + 1));
+            }
+        }
+
+        // reduce the number of runs below `num_tiers`
+        let num_runs = (runs.len() - self.options.num_tiers + 2)
+            .min(self.options.max_merge_width.unwrap_or(usize::MAX))
+            .max(2);
+        Some(self.task(snapshot, num_runs))
+    }
+
+    /// A task merging the `num_runs` newest runs. All of L0 is merged, the new tier goes below
+    /// the SSTs left in L0 so it must not be newer than them.
+    fn task(&self, snapshot: &LsmStorageState, num_runs: usize) -> TieredCompactionTask {
+        let l0_runs = snapshot.l0_sstable.len();
+        let num_runs = num_runs.max(l0_runs);
+        TieredCompactionTask {
+            l0_sstables: snapshot.l0_sstable.clone(),
+            tiers: snapshot.levels[..num_runs - l0_runs].to_vec(),
+            bottom_tier_included: num_runs == snapshot.l0_sstable.len() + snapshot.levels.len(),
+        }
+    }
+
+    /// Apply the result of `task`, returning the new state and the SSTs to remove.
+    pub fn apply_compaction_result(
+        &self,
+        snapshot: &LsmStorageState,
+        task: &TieredCompactionTask,
+        output: &[usize],
+    ) -> (LsmStorageState, Vec<usize>) {
+        let mut snapshot = snapshot.clone();
+        // SSTs flushed since the task was generated stay in L0
+        snapshot
+            .l0_sstable
+            .retain(|id| !task.l0_sstables.contains(id));
+        let mut removed = task.l0_sstables.clone();
+        for (tier_id, ids) in &task.tiers {
+            assert_eq!(
+                snapshot.levels[0].0, *tier_id,
+                "compacted tiers must be the newest"
+            );
+            snapshot.levels.remove(0);
+            removed.extend(ids);
+        }
+        if let Some(tier_id) = output.first() {
+            snapshot.levels.insert(0, (*tier_id, output.to_vec()));
+        }
+        (snapshot, removed)
+    }
+}
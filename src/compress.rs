@@ -0,0 +1,223 @@
+//! Compression of SST data blocks.
+//!
+//! Every data block ends with a one byte trailer naming the codec it was written with, so SSTs
+//! written with different options can be read together.
+//!
+//! Small values compress poorly one block at a time, so an SST may instead carry a zstd
+//! dictionary trained from its own blocks and shared by all of them.
+
+use std::io::Read;
+
+use anyhow::{Result, bail};
+use zstd::dict::DecoderDictionary;
+
+const TAG_NONE: u8 = 0;
+const TAG_LZ4: u8 = 1;
+const TAG_ZSTD: u8 = 2;
+const TAG_ZSTD_DICTIONARY: u8 = 3;
+
+/// Zstd level used for data blocks.
+const ZSTD_LEVEL: i32 = 3;
+
+/// The samples used to train a dictionary are at most this many times the dictionary size.
+const DICTIONARY_SAMPLE_RATIO: usize = 100;
+
+/// Codec of a data block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CompressionType {
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl CompressionType {
+    fn tag(self) -> u8 {
+        match self {
+            CompressionType::None => TAG_NONE,
+            CompressionType::Lz4 => TAG_LZ4,
+            CompressionType::Zstd => TAG_ZSTD,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Result<Self> {
+        match tag {
+            TAG_NONE => Ok(CompressionType::None),
+            TAG_LZ4 => Ok(CompressionType::Lz4),
+            TAG_ZSTD => Ok(CompressionType::Zstd),
+            tag => bail!("unknown compression type {}", tag),
+        }
+    }
+
+    /// Compress `data` and append the trailer. The block is stored uncompressed when compressing
+    /// fails or does not make it smaller.
+    pub fn compress_block(self, data: &[u8], buf: &mut Vec<u8>) {
+        let compressed = match self {
+            CompressionType::None => None,
+            CompressionType::Lz4 => Some(lz4_flex::compress_prepend_size(data)),
+            CompressionType::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL).ok(),
+        };
+        match compressed {
+            Some(compressed) if compressed.len() < data.len() => {
+                buf.extend_from_slice(&compressed);
+                buf.push(self.tag());
+            }
+            _ => {
+                buf.extend_from_slice(data);
+                buf.push(TAG_NONE);
+            }
+        }
+    }
+
+    /// Decompress a block written by `compress_block` or `DictionaryCompressor`, `dictionary`
+    /// is the dictionary of the SST the block belongs to.
+    pub fn decompress_block(raw: &[u8], dictionary: Option<&ZstdDictionary>) -> Result<Vec<u8>> {
+        let Some((tag, data)) = raw.split_last() else {
+            bail!("block is missing its compression trailer");
+        };
+        if *tag == TAG_ZSTD_DICTIONARY {
+            let Some(dictionary) = dictionary else {
+                bail!("block is compressed with a dictionary the sst does not have");
+            };
+            let mut block = Vec::new();
+            zstd::stream::read::Decoder::with_prepared_dictionary(data, &dictionary.decoder)?
+                .read_to_end(&mut block)?;
+            return Ok(block);
+        }
+        Ok(match Self::from_tag(*tag)? {
+            CompressionType::None => data.to_vec(),
+            CompressionType::Lz4 => lz4_flex::decompress_size_prepended(data)?,
+            CompressionType::Zstd => zstd::stream::decode_all(data)?,
+        })
+    }
+}
+
+/// The zstd dictionary of an SST, prepared once for all its blocks.
+pub struct ZstdDictionary {
+    decoder: DecoderDictionary<'static>,
+}
+
+impl ZstdDictionary {
+    /// Train a dictionary of at most `max_size` bytes from the blocks of an SST, sampling them
+    /// evenly. Returns `None` when there is not enough data to train on.
+    pub fn train(blocks: &[Vec<u8>], max_size: usize) -> Option<Vec<u8>> {
+        let total_size = blocks.iter().map(Vec::len).sum::<usize>();
+        let step = total_size
+            .div_ceil(max_size * DICTIONARY_SAMPLE_RATIO)
+            .max(1);
+        let samples = blocks.iter().step_by(step).collect::<Vec<_>>();
+        zstd::dict::from_samples(&samples, max_size).ok()
+    }
+
+    /// Prepare a dictionary read from an SST.
+    pub fn new(dictionary: &[u8]) -> Self {
+        Self {
+            decoder: DecoderDictionary::copy(dictionary),
+        }
+    }
+}
+
+/// Compresses the blocks of an SST with its dictionary.
+pub struct DictionaryCompressor {
+    compressor: zstd::bulk::Compressor<'static>,
+}
+
+impl DictionaryCompressor {
+    pub fn new(dictionary: &[u8]) -> Result<Self> {
+        Ok(Self {
+            compressor: zstd::bulk::Compressor::with_dictionary(ZSTD_LEVEL, dictionary)?,
+        })
+    }
+
+    /// Compress `data` and append the trailer, like `CompressionType::compress_block`.
+    pub fn compress_block(&mut self, data: &[u8], buf: &mut Vec<u8>) {
+        match self.compressor.compress(data) {
+            Ok(compressed) if compressed.len() < data.len() => {
+                buf.extend_from_slice(&compressed);
+                buf.push(TAG_ZSTD_DICTIONARY);
+            }
+            _ => {
+                buf.extend_from_slice(data);
+                buf.push(TAG_NONE);
+            }
+        }
+    }
+}
+
+/// Compression of the SSTs of each level.
+#[derive(Debug, Clone, Default)]
+pub struct CompressionOptions {
+    /// The codec of each level, starting from L0. Levels past the end use the last codec, no
+    /// compression when empty.
+    pub per_level: Vec<CompressionType>,
+    /// Maximum size of the zstd dictionary trained for each SST compaction writes to the
+    /// bottommost level, 0 disables dictionaries.
+    pub zstd_max_dict_bytes: usize,
+}
+
+impl CompressionOptions {
+    /// Use the same codec for every level.
+    pub fn uniform(compression: CompressionType) -> Self {
+        Self {
+            per_level: vec![compression],
+            ..Default::default()
+        }
+    }
+
+    /// The codec of `level`, 0 for L0.
+    pub fn for_level(&self, level: usize) -> CompressionType {
+        self.per_level
+            .get(level)
+            .or(self.per_level.last())
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+/// Compression test
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test every codec round trips, and incompressible blocks are stored as is
+    #[test]
+    fn test_compress_block() -> Result<()> {
+        let data = br#"{"name":"value","name":"value","name":"value","name":"value"}"#.repeat(16);
+        for compression in [
+            CompressionType::None,
+            CompressionType::Lz4,
+            CompressionType::Zstd,
+        ] {
+            let mut buf = Vec::new();
+            compression.compress_block(&data, &mut buf);
+            assert_eq!(*buf.last().unwrap(), compression.tag());
+            assert_eq!(CompressionType::decompress_block(&buf, None)?, data);
+        }
+
+        let mut buf = Vec::new();
+        CompressionType::Zstd.compress_block(b"ab", &mut buf);
+        assert_eq!(buf, b"ab\0");
+        assert!(CompressionType::decompress_block(b"ab\x09", None).is_err());
+        Ok(())
+    }
+
+    /// Test the codec of each level
+    #[test]
+    fn test_compression_for_level() {
+        let options = CompressionOptions {
+            per_level: vec![
+                CompressionType::None,
+                CompressionType::Lz4,
+                CompressionType::Zstd,
+            ],
+            zstd_max_dict_bytes: 0,
+        };
+        assert_eq!(options.for_level(0), CompressionType::None);
+        assert_eq!(options.for_level(1), CompressionType::Lz4);
+        assert_eq!(options.for_level(5), CompressionType::Zstd);
+        assert_eq!(
+            CompressionOptions::default().for_level(1),
+            CompressionType::None
+        );
+    }
+}
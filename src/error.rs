@@ -0,0 +1,92 @@
+//! Typed errors of the storage engine.
+//!
+//! The public methods of `MiniLsm` return `Error`. Internally errors are `anyhow::Error`, and
+//! the typed ones are recovered with a downcast when they reach the public API.
+
+use std::{fmt, io, path::PathBuf};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The request is invalid, e.g. an empty or too large key, or an unknown column family.
+    InvalidArgument(String),
+    /// The data of `file` at `offset` does not match its checksum.
+    Corruption { file: PathBuf, offset: u64 },
+    /// An I/O operation failed.
+    Io {
+        kind: io::ErrorKind,
+        message: String,
+    },
+    /// The storage cannot serve the request now, it may succeed when retried.
+    Busy(String),
+    /// The storage has been closed.
+    Closed,
+    /// An unexpected failure, e.g. a malformed file.
+    Internal(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidArgument(message) => write!(f, "invalid argument: {}", message),
+            Error::Corruption { file, offset } => {
+                write!(f, "corruption in {} at offset {}", file.display(), offset)
+            }
+            Error::Io { message, .. } => write!(f, "io error: {}", message),
+            Error::Busy(message) => write!(f, "busy: {}", message),
+            Error::Closed => write!(f, "storage is closed"),
+            Error::Internal(message) => write!(f, "internal error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<anyhow::Error> for Error {
+    fn from(error: anyhow::Error) -> Self {
+        let error = match error.downcast::<Error>() {
+            Ok(error) => return error,
+            Err(error) => error,
+        };
+        let message = format!("{:#}", error);
+        match error.chain().find_map(|e| e.downcast_ref::<io::Error>()) {
+            Some(io_error) => Error::Io {
+                kind: io_error.kind(),
+                message,
+            },
+            None => Error::Internal(message),
+        }
+    }
+}
+
+/// Error test
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+
+    use super::*;
+
+    /// Test anyhow errors are converted to the typed error they carry
+    #[test]
+    fn test_from_anyhow() {
+        let error: anyhow::Error = Error::InvalidArgument("key cannot be empty".to_string()).into();
+        assert_eq!(
+            Error::from(error.context("write batch")),
+            Error::InvalidArgument("key cannot be empty".to_string())
+        );
+
+        let error = std::fs::read("/nonexistent/file").context("Failed to read");
+        assert!(matches!(
+            Error::from(error.unwrap_err()),
+            Error::Io {
+                kind: io::ErrorKind::NotFound,
+                ..
+            }
+        ));
+        assert!(matches!(
+            Error::from(anyhow::anyhow!("sst 1 has no blocks")),
+            Error::Internal(_)
+        ));
+    }
+}
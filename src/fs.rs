@@ -0,0 +1,292 @@
+//! The file system the storage engine runs on.
+//!
+//! Every file of the storage is created, read and removed through a `FileSystem`, so that tests
+//! can run the engine in memory, or on a file system that injects faults.
+
+mod fault_injection;
+mod memory;
+
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Write},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+pub use fault_injection::{FaultInjectionFs, FileOp};
+pub use memory::InMemoryFileSystem;
+
+/// A file open for appending.
+pub trait WritableFile: Send {
+    /// Append `data`, it may stay buffered until the file is flushed or synced.
+    fn append(&mut self, data: &[u8]) -> io::Result<()>;
+
+    /// Hand the buffered data to the file system.
+    fn flush(&mut self) -> io::Result<()>;
+
+    /// Flush the buffered data and make the file durable.
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+/// A file open for reading at any offset.
+pub trait RandomAccessFile: Send + Sync {
+    /// Read exactly `buf.len()` bytes at `offset`.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+
+    /// Get the size of the file.
+    fn size(&self) -> io::Result<u64>;
+}
+
+/// An exclusive lock on a file, released when dropped.
+pub struct FileLock {
+    _guard: Box<dyn Send + Sync>,
+}
+
+impl FileLock {
+    /// A lock released when `guard` is dropped.
+    pub fn new(guard: impl Send + Sync + 'static) -> Self {
+        Self {
+            _guard: Box::new(guard),
+        }
+    }
+}
+
+impl fmt::Debug for FileLock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileLock").finish()
+    }
+}
+
+/// The operations the storage engine needs from a file system.
+pub trait FileSystem: Send + Sync + fmt::Debug {
+    /// Create a file for writing, truncating it when it exists.
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WritableFile>>;
+
+    /// Open an existing file for appending.
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn WritableFile>>;
+
+    /// Open an existing file for reading.
+    fn open(&self, path: &Path) -> io::Result<Arc<dyn RandomAccessFile>>;
+
+    /// Truncate a file to `len` bytes, and make it durable.
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()>;
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Link `to` to the data of the file `from`.
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// List the files and directories in `dir`.
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()>;
+
+    /// Remove an empty directory.
+    fn remove_dir(&self, dir: &Path) -> io::Result<()>;
+
+    fn exists(&self, path: &Path) -> bool;
+
+    /// Make the files created, renamed or removed in `dir` durable.
+    fn sync_dir(&self, dir: &Path) -> io::Result<()>;
+
+    /// Take an exclusive lock on a file, created when missing. Fails with `WouldBlock` when it is
+    /// already locked.
+    fn lock(&self, path: &Path) -> io::Result<FileLock>;
+
+    /// Make the directory entry of a new file durable.
+    fn sync_parent_dir(&self, path: &Path) -> io::Result<()> {
+        self.sync_dir(parent_dir(path))
+    }
+
+    /// Read a whole file.
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let file = self.open(path)?;
+        let mut data = vec![0; file.size()? as usize];
+        file.read_at(0, &mut data)?;
+        Ok(data)
+    }
+
+    /// Copy a file, and make the copy durable.
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let data = self.read(from)?;
+        let mut file = self.create(to)?;
+        file.append(&data)?;
+        file.sync()
+    }
+}
+
+/// The directory of `path`, `.` for a relative path without one.
+pub(crate) fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+/// The file system of the operating system.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StdFileSystem;
+
+struct StdWritableFile(BufWriter<File>);
+
+impl WritableFile for StdWritableFile {
+    fn append(&mut self, data: &[u8]) -> io::Result<()> {
+        self.0.write_all(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.0.flush()?;
+        self.0.get_ref().sync_all()
+    }
+}
+
+impl RandomAccessFile for File {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.read_exact_at(buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+}
+
+impl FileSystem for StdFileSystem {
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        let file = File::create(path)?;
+        Ok(Box::new(StdWritableFile(BufWriter::new(file))))
+    }
+
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        let file = OpenOptions::new().append(true).open(path)?;
+        Ok(Box::new(StdWritableFile(BufWriter::new(file))))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Arc<dyn RandomAccessFile>> {
+        Ok(Arc::new(File::open(path)?))
+    }
+
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()> {
+        let file = OpenOptions::new().write(true).open(path)?;
+        file.set_len(len)?;
+        file.sync_all()
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        std::fs::rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        std::fs::hard_link(from, to)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        std::fs::read_dir(dir)?
+            .map(|entry| Ok(entry?.path()))
+            .collect()
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        std::fs::create_dir_all(dir)
+    }
+
+    fn remove_dir(&self, dir: &Path) -> io::Result<()> {
+        std::fs::remove_dir(dir)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        File::open(dir)?.sync_all()
+    }
+
+    fn lock(&self, path: &Path) -> io::Result<FileLock> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+        file.try_lock().map_err(|error| match error {
+            std::fs::TryLockError::WouldBlock => io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!("{} is locked by another process", path.display()),
+            ),
+            std::fs::TryLockError::Error(error) => error,
+        })?;
+        Ok(FileLock::new(file))
+    }
+}
+
+/// File system test
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Check the operations of a file system in `dir`
+    pub(crate) fn check_file_system(fs: &dyn FileSystem, dir: &Path) -> io::Result<()> {
+        fs.create_dir_all(&dir.join("sub"))?;
+        let path = dir.join("1.log");
+        let mut file = fs.create(&path)?;
+        file.append(b"hello ")?;
+        file.append(b"world")?;
+        file.sync()?;
+        assert_eq!(fs.read(&path)?, b"hello world");
+        let mut file = fs.open_append(&path)?;
+        file.append(b"!")?;
+        file.sync()?;
+        let reader = fs.open(&path)?;
+        assert_eq!(reader.size()?, 12);
+        let mut buf = [0; 5];
+        reader.read_at(6, &mut buf)?;
+        assert_eq!(&buf, b"world");
+        assert!(reader.read_at(10, &mut buf).is_err());
+
+        fs.truncate(&path, 5)?;
+        assert_eq!(fs.read(&path)?, b"hello");
+        fs.hard_link(&path, &dir.join("2.log"))?;
+        fs.rename(&path, &dir.join("sub/3.log"))?;
+        assert!(!fs.exists(&path));
+        assert_eq!(fs.read(&dir.join("2.log"))?, b"hello");
+        fs.copy(&dir.join("2.log"), &path)?;
+        fs.remove_file(&dir.join("2.log"))?;
+        assert_eq!(
+            fs.remove_file(&dir.join("2.log")).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        let mut files = fs.list(dir)?;
+        files.sort();
+        assert_eq!(files, vec![path.clone(), dir.join("sub")]);
+        assert_eq!(fs.list(&dir.join("sub"))?, vec![dir.join("sub/3.log")]);
+        fs.sync_dir(dir)?;
+        assert!(fs.remove_dir(&dir.join("sub")).is_err());
+        fs.remove_file(&dir.join("sub/3.log"))?;
+        fs.remove_dir(&dir.join("sub"))?;
+        assert!(!fs.exists(&dir.join("sub")));
+
+        let lock = fs.lock(&dir.join("LOCK"))?;
+        assert!(fs.lock(&dir.join("LOCK")).is_err());
+        drop(lock);
+        fs.lock(&dir.join("LOCK"))?;
+        Ok(())
+    }
+
+    /// Test the file system of the operating system
+    #[test]
+    fn test_std_file_system() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        check_file_system(&StdFileSystem, dir.path())
+    }
+}
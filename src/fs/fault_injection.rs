@@ -0,0 +1,454 @@
+use std::{
+    collections::BTreeMap,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use parking_lot::Mutex;
+
+use super::{FileLock, FileSystem, RandomAccessFile, WritableFile, parent_dir};
+
+/// The operations of a `FaultInjectionFs` that can be made to fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileOp {
+    Create,
+    OpenAppend,
+    Open,
+    Read,
+    Append,
+    Sync,
+    Truncate,
+    Rename,
+    RemoveFile,
+    HardLink,
+    List,
+    CreateDir,
+    RemoveDir,
+    SyncDir,
+    Lock,
+}
+
+/// A file written since the last crash.
+#[derive(Debug)]
+struct FileState {
+    /// Bytes written to the file.
+    len: u64,
+    /// Bytes that survive a crash.
+    synced_len: u64,
+    /// Whether the directory entry of the file survives a crash.
+    entry_synced: bool,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Files untouched since the last crash are durable, and not tracked.
+    files: BTreeMap<PathBuf, FileState>,
+    /// Operations failing on the files whose path ends with a suffix.
+    failures: Vec<(FileOp, String)>,
+    /// The next append to a file whose path ends with a suffix only writes that many bytes.
+    torn_appends: Vec<(String, usize)>,
+    /// Operations left before every operation fails.
+    ops_until_crash: Option<usize>,
+}
+
+fn ends_with(path: &Path, suffix: &str) -> bool {
+    path.as_os_str().to_string_lossy().ends_with(suffix)
+}
+
+impl State {
+    /// Count an operation, failing it when a fault was injected for it.
+    fn check(&mut self, op: FileOp, path: &Path) -> io::Result<()> {
+        match &mut self.ops_until_crash {
+            Some(0) => return Err(io::Error::other("the file system crashed")),
+            Some(ops) => *ops -= 1,
+            None => {}
+        }
+        if self
+            .failures
+            .iter()
+            .any(|(failed_op, suffix)| *failed_op == op && ends_with(path, suffix))
+        {
+            return Err(io::Error::other(format!(
+                "injected {:?} error on {}",
+                op,
+                path.display()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Take the length of a torn append to `path`.
+    fn take_torn_append(&mut self, path: &Path) -> Option<usize> {
+        let idx = self
+            .torn_appends
+            .iter()
+            .position(|(suffix, _)| ends_with(path, suffix))?;
+        Some(self.torn_appends.remove(idx).1)
+    }
+}
+
+/// A file system wrapping another one to simulate power losses and I/O errors, for crash tests.
+///
+/// The data appended to a file is lost in a crash until the file is synced, and a new file is
+/// lost until its directory is synced. Truncations, renames and removals survive a crash as soon
+/// as they return.
+#[derive(Debug, Clone)]
+pub struct FaultInjectionFs {
+    inner: Arc<dyn FileSystem>,
+    state: Arc<Mutex<State>>,
+}
+
+impl FaultInjectionFs {
+    pub fn new(inner: Arc<dyn FileSystem>) -> Self {
+        Self {
+            inner,
+            state: Default::default(),
+        }
+    }
+
+    /// Fail every `op` on the files whose path ends with `suffix`.
+    pub fn fail(&self, op: FileOp, suffix: &str) {
+        self.state.lock().failures.push((op, suffix.to_string()));
+    }
+
+    /// The next append to a file whose path ends with `suffix` only writes its first `len` bytes,
+    /// then fails.
+    pub fn tear_next_append(&self, suffix: &str, len: usize) {
+        self.state
+            .lock()
+            .torn_appends
+            .push((suffix.to_string(), len));
+    }
+
+    /// Fail every operation once `ops` more operations are done, as if the process was killed
+    /// there.
+    pub fn crash_after(&self, ops: usize) {
+        self.state.lock().ops_until_crash = Some(ops);
+    }
+
+    /// Remove the injected failures, torn appends and crash point.
+    pub fn clear_faults(&self) {
+        let mut state = self.state.lock();
+        state.failures.clear();
+        state.torn_appends.clear();
+        state.ops_until_crash = None;
+    }
+
+    /// Simulate a power loss: the data not synced is lost. The files must not be open anymore,
+    /// the faults are cleared.
+    pub fn crash(&self) -> io::Result<()> {
+        self.crash_with_torn_writes(|_, _| 0)
+    }
+
+    /// Simulate a power loss tearing the writes not synced: of the `len` bytes not synced of a
+    /// file, the first `keep(path, len)` bytes survive.
+    pub fn crash_with_torn_writes(
+        &self,
+        mut keep: impl FnMut(&Path, u64) -> u64,
+    ) -> io::Result<()> {
+        let mut state = self.state.lock();
+        for (path, file) in std::mem::take(&mut state.files) {
+            if !file.entry_synced {
+                match self.inner.remove_file(&path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            } else if file.len > file.synced_len {
+                let unsynced = file.len - file.synced_len;
+                let kept = keep(&path, unsynced).min(unsynced);
+                self.inner.truncate(&path, file.synced_len + kept)?;
+            }
+        }
+        drop(state);
+        self.clear_faults();
+        Ok(())
+    }
+
+    fn writable_file(&self, inner: Box<dyn WritableFile>, path: &Path) -> Box<dyn WritableFile> {
+        Box::new(FaultWritableFile {
+            inner,
+            path: path.to_path_buf(),
+            state: self.state.clone(),
+        })
+    }
+
+    /// The length of a file the file system does not track.
+    fn durable_len(&self, path: &Path) -> io::Result<u64> {
+        self.inner.open(path)?.size()
+    }
+}
+
+struct FaultWritableFile {
+    inner: Box<dyn WritableFile>,
+    path: PathBuf,
+    state: Arc<Mutex<State>>,
+}
+
+impl WritableFile for FaultWritableFile {
+    fn append(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut state = self.state.lock();
+        state.check(FileOp::Append, &self.path)?;
+        let torn = state.take_torn_append(&self.path);
+        let written = &data[..torn.unwrap_or(data.len()).min(data.len())];
+        // nothing stays buffered in the inner file, a crash sees every byte written
+        self.inner.append(written)?;
+        self.inner.flush()?;
+        if let Some(file) = state.files.get_mut(&self.path) {
+            file.len += written.len() as u64;
+        }
+        match torn {
+            Some(_) => Err(io::Error::other(format!(
+                "torn write to {}",
+                self.path.display()
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        let mut state = self.state.lock();
+        state.check(FileOp::Sync, &self.path)?;
+        self.inner.sync()?;
+        if let Some(file) = state.files.get_mut(&self.path) {
+            file.synced_len = file.len;
+        }
+        Ok(())
+    }
+}
+
+struct FaultRandomAccessFile {
+    inner: Arc<dyn RandomAccessFile>,
+    path: PathBuf,
+    state: Arc<Mutex<State>>,
+}
+
+impl RandomAccessFile for FaultRandomAccessFile {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.state.lock().check(FileOp::Read, &self.path)?;
+        self.inner.read_at(offset, buf)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.inner.size()
+    }
+}
+
+impl FileSystem for FaultInjectionFs {
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        let mut state = self.state.lock();
+        state.check(FileOp::Create, path)?;
+        let entry_synced = match state.files.get(path) {
+            Some(file) => file.entry_synced,
+            None => self.inner.exists(path),
+        };
+        let file = self.inner.create(path)?;
+        state.files.insert(
+            path.to_path_buf(),
+            FileState {
+                len: 0,
+                synced_len: 0,
+                entry_synced,
+            },
+        );
+        Ok(self.writable_file(file, path))
+    }
+
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        let mut state = self.state.lock();
+        state.check(FileOp::OpenAppend, path)?;
+        let file = self.inner.open_append(path)?;
+        if !state.files.contains_key(path) {
+            let len = self.durable_len(path)?;
+            state.files.insert(
+                path.to_path_buf(),
+                FileState {
+                    len,
+                    synced_len: len,
+                    entry_synced: true,
+                },
+            );
+        }
+        Ok(self.writable_file(file, path))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Arc<dyn RandomAccessFile>> {
+        self.state.lock().check(FileOp::Open, path)?;
+        Ok(Arc::new(FaultRandomAccessFile {
+            inner: self.inner.open(path)?,
+            path: path.to_path_buf(),
+            state: self.state.clone(),
+        }))
+    }
+
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()> {
+        let mut state = self.state.lock();
+        state.check(FileOp::Truncate, path)?;
+        self.inner.truncate(path, len)?;
+        if let Some(file) = state.files.get_mut(path) {
+            file.len = len;
+            file.synced_len = len;
+        }
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state.lock();
+        state.check(FileOp::Rename, from)?;
+        self.inner.rename(from, to)?;
+        state.files.remove(to);
+        if let Some(file) = state.files.remove(from) {
+            state.files.insert(to.to_path_buf(), file);
+        }
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock();
+        state.check(FileOp::RemoveFile, path)?;
+        self.inner.remove_file(path)?;
+        state.files.remove(path);
+        Ok(())
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state.lock();
+        state.check(FileOp::HardLink, to)?;
+        self.inner.hard_link(from, to)?;
+        let (len, synced_len) = match state.files.get(from) {
+            Some(file) => (file.len, file.synced_len),
+            None => {
+                let len = self.durable_len(from)?;
+                (len, len)
+            }
+        };
+        state.files.insert(
+            to.to_path_buf(),
+            FileState {
+                len,
+                synced_len,
+                entry_synced: false,
+            },
+        );
+        Ok(())
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        self.state.lock().check(FileOp::List, dir)?;
+        self.inner.list(dir)
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        self.state.lock().check(FileOp::CreateDir, dir)?;
+        self.inner.create_dir_all(dir)
+    }
+
+    fn remove_dir(&self, dir: &Path) -> io::Result<()> {
+        self.state.lock().check(FileOp::RemoveDir, dir)?;
+        self.inner.remove_dir(dir)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        let mut state = self.state.lock();
+        state.check(FileOp::SyncDir, dir)?;
+        self.inner.sync_dir(dir)?;
+        for (path, file) in state.files.iter_mut() {
+            if parent_dir(path) == dir {
+                file.entry_synced = true;
+            }
+        }
+        Ok(())
+    }
+
+    fn lock(&self, path: &Path) -> io::Result<FileLock> {
+        self.state.lock().check(FileOp::Lock, path)?;
+        self.inner.lock(path)
+    }
+}
+
+/// Fault injection file system test
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::{InMemoryFileSystem, tests::check_file_system};
+
+    fn fault_injection_fs() -> io::Result<FaultInjectionFs> {
+        let fs = FaultInjectionFs::new(Arc::new(InMemoryFileSystem::new()));
+        fs.create_dir_all(Path::new("/db"))?;
+        Ok(fs)
+    }
+
+    /// Test the file system behaves like the one it wraps when no fault is injected
+    #[test]
+    fn test_fault_injection_fs() -> io::Result<()> {
+        check_file_system(&fault_injection_fs()?, Path::new("/db"))
+    }
+
+    /// Test a crash loses the data not synced, and the files whose directory was not synced
+    #[test]
+    fn test_fault_injection_fs_crash() -> io::Result<()> {
+        let fs = fault_injection_fs()?;
+        let synced = Path::new("/db/1.log");
+        let mut file = fs.create(synced)?;
+        file.append(b"synced")?;
+        file.sync()?;
+        fs.sync_dir(Path::new("/db"))?;
+        file.append(b" lost")?;
+        let unlinked = Path::new("/db/2.log");
+        let mut file = fs.create(unlinked)?;
+        file.append(b"synced")?;
+        file.sync()?;
+        drop(file);
+        fs.crash()?;
+        assert_eq!(fs.read(synced)?, b"synced");
+        assert!(!fs.exists(unlinked));
+
+        // files untouched since the crash are durable
+        let mut file = fs.open_append(synced)?;
+        file.append(b" torn")?;
+        fs.crash_with_torn_writes(|path, len| {
+            assert_eq!((path, len), (synced, 5));
+            3
+        })?;
+        assert_eq!(fs.read(synced)?, b"synced to");
+        Ok(())
+    }
+
+    /// Test injected failures, torn appends and crash points
+    #[test]
+    fn test_fault_injection_fs_failures() -> io::Result<()> {
+        let fs = fault_injection_fs()?;
+        let path = Path::new("/db/1.log");
+        fs.fail(FileOp::Sync, ".log");
+        let mut file = fs.create(path)?;
+        file.append(b"data")?;
+        assert!(file.sync().is_err());
+        assert!(fs.open(path)?.read_at(0, &mut [0; 4]).is_ok());
+        fs.clear_faults();
+        file.sync()?;
+
+        fs.tear_next_append("1.log", 2);
+        assert!(file.append(b"torn").is_err());
+        file.append(b"!")?;
+        assert_eq!(fs.read(path)?, b"datato!");
+
+        fs.crash_after(2);
+        file.append(b"1")?;
+        file.append(b"2")?;
+        assert!(file.append(b"3").is_err());
+        assert!(fs.open(path).is_err());
+        drop(file);
+        fs.sync_dir(Path::new("/db")).unwrap_err();
+        fs.crash()?;
+        assert!(!fs.exists(path));
+        Ok(())
+    }
+}
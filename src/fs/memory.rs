@@ -0,0 +1,247 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use parking_lot::{Mutex, RwLock};
+
+use super::{FileLock, FileSystem, RandomAccessFile, WritableFile};
+
+/// The data of a file, shared by its hard links and the handles open on it.
+type FileData = Arc<RwLock<Vec<u8>>>;
+
+#[derive(Debug, Default)]
+struct State {
+    files: BTreeMap<PathBuf, FileData>,
+    dirs: BTreeSet<PathBuf>,
+    locks: HashSet<PathBuf>,
+}
+
+/// A file system that keeps its files in memory, for tests that leave nothing behind. Files
+/// removed while open can still be read through their handles.
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryFileSystem {
+    state: Arc<Mutex<State>>,
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} does not exist", path.display()),
+    )
+}
+
+impl State {
+    fn file(&self, path: &Path) -> io::Result<FileData> {
+        self.files.get(path).cloned().ok_or_else(|| not_found(path))
+    }
+
+    /// Check the directory of `path` exists.
+    fn check_parent(&self, path: &Path) -> io::Result<()> {
+        match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() && !self.dirs.contains(dir) => {
+                Err(not_found(dir))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl InMemoryFileSystem {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+struct InMemoryFile(FileData);
+
+impl WritableFile for InMemoryFile {
+    fn append(&mut self, data: &[u8]) -> io::Result<()> {
+        self.0.write().extend_from_slice(data);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl RandomAccessFile for InMemoryFile {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let data = self.0.read();
+        let range = offset as usize..offset as usize + buf.len();
+        let Some(bytes) = data.get(range) else {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "read past the end of the file",
+            ));
+        };
+        buf.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.0.read().len() as u64)
+    }
+}
+
+/// Releases a lock of an `InMemoryFileSystem`.
+struct InMemoryLock {
+    state: Arc<Mutex<State>>,
+    path: PathBuf,
+}
+
+impl Drop for InMemoryLock {
+    fn drop(&mut self) {
+        self.state.lock().locks.remove(&self.path);
+    }
+}
+
+impl FileSystem for InMemoryFileSystem {
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        let mut state = self.state.lock();
+        state.check_parent(path)?;
+        let data = FileData::default();
+        state.files.insert(path.to_path_buf(), data.clone());
+        Ok(Box::new(InMemoryFile(data)))
+    }
+
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        Ok(Box::new(InMemoryFile(self.state.lock().file(path)?)))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Arc<dyn RandomAccessFile>> {
+        Ok(Arc::new(InMemoryFile(self.state.lock().file(path)?)))
+    }
+
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()> {
+        self.state.lock().file(path)?.write().truncate(len as usize);
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state.lock();
+        state.check_parent(to)?;
+        let data = state.files.remove(from).ok_or_else(|| not_found(from))?;
+        state.files.insert(to.to_path_buf(), data);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock();
+        state.files.remove(path).ok_or_else(|| not_found(path))?;
+        Ok(())
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state.lock();
+        state.check_parent(to)?;
+        if state.files.contains_key(to) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", to.display()),
+            ));
+        }
+        let data = state.file(from)?;
+        state.files.insert(to.to_path_buf(), data);
+        Ok(())
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let state = self.state.lock();
+        if !state.dirs.contains(dir) {
+            return Err(not_found(dir));
+        }
+        Ok(state
+            .files
+            .keys()
+            .chain(&state.dirs)
+            .filter(|path| path.parent() == Some(dir))
+            .cloned()
+            .collect())
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        let mut state = self.state.lock();
+        for dir in dir.ancestors().filter(|dir| !dir.as_os_str().is_empty()) {
+            state.dirs.insert(dir.to_path_buf());
+        }
+        Ok(())
+    }
+
+    fn remove_dir(&self, dir: &Path) -> io::Result<()> {
+        let mut state = self.state.lock();
+        if !state.dirs.contains(dir) {
+            return Err(not_found(dir));
+        }
+        let mut entries = state.files.keys().chain(&state.dirs);
+        if entries.any(|path| path.parent() == Some(dir)) {
+            return Err(io::Error::new(
+                io::ErrorKind::DirectoryNotEmpty,
+                format!("{} is not empty", dir.display()),
+            ));
+        }
+        state.dirs.remove(dir);
+        Ok(())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        let state = self.state.lock();
+        state.files.contains_key(path) || state.dirs.contains(path)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        // `.` is where relative paths without a directory live
+        match dir == Path::new(".") || self.state.lock().dirs.contains(dir) {
+            true => Ok(()),
+            false => Err(not_found(dir)),
+        }
+    }
+
+    fn lock(&self, path: &Path) -> io::Result<FileLock> {
+        let mut state = self.state.lock();
+        state.check_parent(path)?;
+        if !state.locks.insert(path.to_path_buf()) {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!("{} is already locked", path.display()),
+            ));
+        }
+        state.files.entry(path.to_path_buf()).or_default();
+        Ok(FileLock::new(InMemoryLock {
+            state: self.state.clone(),
+            path: path.to_path_buf(),
+        }))
+    }
+}
+
+/// In-memory file system test
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::tests::check_file_system;
+
+    /// Test the in-memory file system behaves like the one of the operating system
+    #[test]
+    fn test_in_memory_file_system() -> io::Result<()> {
+        let fs = InMemoryFileSystem::new();
+        check_file_system(&fs, Path::new("/db"))?;
+
+        // files removed while open can still be read
+        let path = Path::new("/db/4.log");
+        fs.create(path)?.append(b"data")?;
+        let file = fs.open(path)?;
+        fs.remove_file(path)?;
+        let mut buf = [0; 4];
+        file.read_at(0, &mut buf)?;
+        assert_eq!(&buf, b"data");
+        assert!(fs.create(Path::new("/missing/1.log")).is_err());
+        Ok(())
+    }
+}
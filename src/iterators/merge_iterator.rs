@@ -0,0 +1,165 @@
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, binary_heap::PeekMut},
+};
+
+use anyhow::Result;
+
+use super::StorageIterator;
+
+/// An iterator with its index, a lower index is a newer iterator.
+struct HeapWrapper<I: StorageIterator>(usize, Box<I>);
+
+impl<I: StorageIterator> PartialEq for HeapWrapper<I> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<I: StorageIterator> Eq for HeapWrapper<I> {}
+
+impl<I: StorageIterator> PartialOrd for HeapWrapper<I> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<I: StorageIterator> Ord for HeapWrapper<I> {
+    // reversed, the heap is a max-heap and the smallest key of the newest iterator goes first
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.1
+            .key()
+            .cmp(other.1.key())
+            .then(self.0.cmp(&other.0))
+            .reverse()
+    }
+}
+
+/// Merges iterators of the same key space. When several iterators hold the same key, only the
+/// entry of the iterator that comes first in the list is returned.
+pub struct MergeIterator<I: StorageIterator> {
+    iters: BinaryHeap<HeapWrapper<I>>,
+    current: Option<HeapWrapper<I>>,
+}
+
+impl<I: StorageIterator> MergeIterator<I> {
+    /// Create a merge iterator, `iters` go from the newest to the oldest.
+    pub fn create(iters: Vec<Box<I>>) -> Self {
+        let mut heap = iters
+            .into_iter()
+            .enumerate()
+            .filter(|(_, iter)| iter.is_valid())
+            .map(|(idx, iter)| HeapWrapper(idx, iter))
+            .collect::<BinaryHeap<_>>();
+        let current = heap.pop();
+        Self {
+            iters: heap,
+            current,
+        }
+    }
+}
+
+impl<I: StorageIterator> StorageIterator for MergeIterator<I> {
+    fn value(&self) -> &[u8] {
+        self.current.as_ref().unwrap().1.value()
+    }
+
+    fn key(&self) -> &[u8] {
+        self.current.as_ref().unwrap().1.key()
+    }
+
+    fn is_valid(&self) -> bool {
+        self.current
+            .as_ref()
+            .is_some_and(|current| current.1.is_valid())
+    }
+
+    fn next(&mut self) -> Result<()> {
+        let Some(current) = self.current.as_mut() else {
+            return Ok(());
+        };
+        // skip the older versions of the current key
+        while let Some(mut inner) = self.iters.peek_mut() {
+            if inner.1.key() != current.1.key() {
+                break;
+            }
+            if let Err(e) = inner.1.next() {
+                PeekMut::pop(inner);
+                return Err(e);
+            }
+            if !inner.1.is_valid() {
+                PeekMut::pop(inner);
+            }
+        }
+
+        current.1.next()?;
+        if !current.1.is_valid() {
+            self.current = self.iters.pop();
+            return Ok(());
+        }
+        // another iterator may have a smaller key now
+        if let Some(mut inner) = self.iters.peek_mut()
+            && *current < *inner
+        {
+            std::mem::swap(&mut *inner, current);
+        }
+        Ok(())
+    }
+}
+
+/// Merge iterator test
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct VecIterator(Vec<(&'static [u8], &'static [u8])>, usize);
+
+    impl StorageIterator for VecIterator {
+        fn value(&self) -> &[u8] {
+            self.0[self.1].1
+        }
+
+        fn key(&self) -> &[u8] {
+            self.0[self.1].0
+        }
+
+        fn is_valid(&self) -> bool {
+            self.1 < self.0.len()
+        }
+
+        fn next(&mut self) -> Result<()> {
+            self.1 += 1;
+            Ok(())
+        }
+    }
+
+    /// Test keys are merged in order and the newest version wins
+    #[test]
+    fn test_merge_iterator() -> Result<()> {
+        let iters = vec![
+            Box::new(VecIterator(vec![(b"b", b"new"), (b"d", b"new")], 0)),
+            Box::new(VecIterator(vec![], 0)),
+            Box::new(VecIterator(
+                vec![
+                    (b"a", b"old"),
+                    (b"b", b"old"),
+                    (b"c", b"old"),
+                    (b"d", b"old"),
+                ],
+                0,
+            )),
+        ];
+        let mut iter = MergeIterator::create(iters);
+        let mut entries = Vec::new();
+        while iter.is_valid() {
+            entries.push((iter.key().to_vec(), iter.value().to_vec()));
+            iter.next()?;
+        }
+        let expected = [("a", "old"), ("b", "new"), ("c", "old"), ("d", "new")]
+            .iter()
+            .map(|(k, v)| (k.as_bytes().to_vec(), v.as_bytes().to_vec()))
+            .collect::<Vec<_>>();
+        assert_eq!(entries, expected);
+        Ok(())
+    }
+}
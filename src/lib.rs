@@ -1,4 +1,16 @@
+pub mod backup;
+pub mod blob;
+pub mod block;
+pub mod cache;
 pub mod compact;
+pub mod compress;
+pub mod error;
+pub mod fs;
+pub mod iterators;
 pub mod lsm_storage;
+pub mod manifest;
 pub mod mem_table;
+pub mod rate_limiter;
+pub mod table;
+pub mod value;
 pub mod wal;
@@ -1,19 +1,87 @@
+mod checkpoint;
+mod compaction;
+mod thread_pool;
+mod verify;
+mod write_stall;
+
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap, HashSet},
     path::{Path, PathBuf},
-    sync::{Arc, atomic::AtomicUsize},
-    thread::JoinHandle,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    time::Duration,
 };
 
-use anyhow::{Context, Ok, Result};
+use anyhow::{Context, Result, bail};
 use bytes::Bytes;
-use parking_lot::{Mutex, MutexGuard, RwLock};
+use parking_lot::{Condvar, Mutex, MutexGuard, RwLock};
 
 use crate::{
-    compact::{CompactionOptions, LeveledCompactionOptions, SimpleLeveledCompactionOptions},
+    blob::{BlobFile, BlobFileBuilder},
+    cache::{BlockCache, TableCache},
+    compact::{
+        CompactionController, CompactionOptions, LeveledCompactionOptions,
+        SimpleLeveledCompactionOptions,
+    },
+    compress::CompressionOptions,
+    error::{self, Error},
+    fs::{FileLock, FileSystem, StdFileSystem},
+    iterators::StorageIterator,
+    manifest::{FlushedTable, Manifest, ManifestRecord},
     mem_table::MemTable,
+    rate_limiter::{IoPriority, RateLimiter},
+    table::{FileObject, SsTable, SsTableBuilder, SsTableIterator, TableValue},
+    value::Value,
+    wal::Wal,
 };
 
+use thread_pool::ThreadPool;
+pub use verify::{VerifyIssue, VerifyReport};
+use write_stall::WriteStallCounters;
+pub use write_stall::{WriteStallOptions, WriteStallStats};
+
+/// Largest key the wal and SST formats can hold.
+pub const MAX_KEY_SIZE: usize = u16::MAX as usize;
+/// Largest value the wal and SST entries can hold, SST values carry a one byte tag. The block
+/// offsets of an SST are 32 bits and building an SST past 4 GiB fails, so values stored inline
+/// must also keep `target_sst_size` plus `max_value_size` below that; larger values belong in
+/// blob files, see `min_blob_size`.
+pub const MAX_VALUE_SIZE: usize = u32::MAX as usize - 1;
+
+/// Check `key` is not empty and at most `max_key_size` bytes, and no larger than `MAX_KEY_SIZE`.
+pub(crate) fn check_key(key: &[u8], max_key_size: usize) -> Result<()> {
+    if key.is_empty() {
+        bail!(Error::InvalidArgument("key cannot be empty".to_string()));
+    }
+    let max_key_size = max_key_size.min(MAX_KEY_SIZE);
+    if key.len() > max_key_size {
+        bail!(Error::InvalidArgument(format!(
+            "key of {} bytes exceeds the maximum of {} bytes",
+            key.len(),
+            max_key_size
+        )));
+    }
+    Ok(())
+}
+
+/// Check `value` is at most `max_value_size` bytes, and no larger than `MAX_VALUE_SIZE`.
+pub(crate) fn check_value(value: &[u8], max_value_size: usize) -> Result<()> {
+    let max_value_size = max_value_size.min(MAX_VALUE_SIZE);
+    if value.len() > max_value_size {
+        bail!(Error::InvalidArgument(format!(
+            "value of {} bytes exceeds the maximum of {} bytes",
+            value.len(),
+            max_value_size
+        )));
+    }
+    Ok(())
+}
+
+/// Name of the column family that always exists.
+pub const DEFAULT_COLUMN_FAMILY: &str = "default";
+
 /// Represents the state of the storage engine.
 ///
 #[derive(Clone)]
@@ -27,8 +95,10 @@ pub struct LsmStorageState {
     /// SsTables sorted by key range; L1 - L_max for leveled compaction, or tiers for tiered
     /// compaction.
     pub levels: Vec<(usize, Vec<usize>)>,
-    // SST objects. todo need arc sstable, just use [u8] for now
-    pub sstables: HashMap<usize, Arc<[u8]>>,
+    /// SST objects.
+    pub sstables: HashMap<usize, Arc<SsTable>>,
+    /// Blob files holding the values separated from SSTs.
+    pub blob_files: HashMap<usize, Arc<BlobFile>>,
 }
 
 pub enum WriteBatchRecord<T: AsRef<[u8]>> {
@@ -50,6 +120,78 @@ pub struct LsmStorageOptions {
     pub enable_wal: bool,
     // Searialized
     pub serialized: bool,
+    // Values of at least this size are written to blob files on flush, `None` disables key-value
+    // separation
+    pub min_blob_size: Option<usize>,
+    // Blob files whose values are at least this ratio garbage, no longer pointed to by any SST,
+    // are garbage collected by the compaction thread; blob files nothing points to are always
+    // removed
+    pub blob_gc_garbage_ratio: Option<f64>,
+    // Codec of the SST data blocks of each level
+    pub compression: CompressionOptions,
+    // Verify the checksum of every SST block read, footers and block meta are always verified
+    pub verify_checksums: bool,
+    // Maximum key size in bytes, at most `MAX_KEY_SIZE`
+    pub max_key_size: usize,
+    // Maximum value size in bytes, at most `MAX_VALUE_SIZE`; 1 GiB by default, so an SST holding
+    // such a value inline stays addressable
+    pub max_value_size: usize,
+    // Slowdown and stop triggers for writes when immutable memtables or L0 SSTs pile up
+    pub write_stall: WriteStallOptions,
+    // Limits the bytes per second written by flush, compaction and blob gc, shared by every
+    // column family; wal writes are never limited
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    // Maximum number of key ranges a compaction is split into and merged in parallel, 1 merges
+    // everything on the compaction thread; the storage options size the pool of threads every
+    // column family merges them on
+    pub max_subcompactions: usize,
+    // SSTs written more than this many seconds ago are compacted even when the levels are within
+    // their targets, so compaction filters and tombstone cleanup reach cold key ranges; 0
+    // disables it
+    pub periodic_compaction_seconds: u64,
+    // SSTs whose tombstones are at least this ratio of their entries are compacted before the
+    // size based triggers, so the deleted keys stop slowing down scans
+    pub tombstone_compaction_ratio: Option<f64>,
+    // Caches the blocks read from the SSTs, shared by every column family
+    pub block_cache: Option<Arc<BlockCache>>,
+    // SSTs beyond this many are closed, least recently read first, and opened again when read;
+    // None keeps every SST open
+    pub max_open_files: Option<usize>,
+    // The file system every file of the storage goes through; None is the file system of the
+    // operating system
+    pub file_system: Option<Arc<dyn FileSystem>>,
+    // Sync the wal before a write returns, so acknowledged writes survive a power loss; otherwise
+    // the wal is synced when its memtable is frozen or the storage closed
+    pub sync_writes: bool,
+}
+
+impl Default for LsmStorageOptions {
+    /// A single column family with a synced-on-freeze wal and no compaction.
+    fn default() -> Self {
+        Self {
+            block_size: 4096,
+            target_sst_size: 2 << 20,
+            num_memttable_limit: 10,
+            compaction_options: CompactionOptions::NoCompaction,
+            enable_wal: true,
+            serialized: false,
+            min_blob_size: None,
+            blob_gc_garbage_ratio: None,
+            compression: CompressionOptions::default(),
+            verify_checksums: true,
+            max_key_size: MAX_KEY_SIZE,
+            max_value_size: 1 << 30,
+            write_stall: WriteStallOptions::default(),
+            rate_limiter: None,
+            max_subcompactions: 1,
+            periodic_compaction_seconds: 0,
+            tombstone_compaction_ratio: None,
+            block_cache: None,
+            max_open_files: None,
+            file_system: None,
+            sync_writes: false,
+        }
+    }
 }
 
 impl LsmStorageState {
@@ -62,6 +204,7 @@ impl LsmStorageState {
                 .map(|level| (level, Vec::new()))
                 .collect::<Vec<_>>(),
             CompactionOptions::Tiered(_) => Vec::new(),
+            CompactionOptions::Fifo(_) => Vec::new(),
             CompactionOptions::NoCompaction => Vec::new(),
         };
         Self {
@@ -69,8 +212,28 @@ impl LsmStorageState {
             imm_memtable: Vec::new(),
             l0_sstable: Vec::new(),
             levels,
-            // todo!()
             sstables: Default::default(),
+            blob_files: Default::default(),
+        }
+    }
+}
+
+/// Options for `MiniLsm::ingest_external_files`.
+#[derive(Debug, Clone)]
+pub struct IngestExternalFileOptions {
+    /// Allow the files to overlap existing data or each other. Overlapping files go to L0 and take
+    /// precedence over the existing data; the memtables are flushed first when they overlap.
+    pub allow_overlap: bool,
+    /// Hard link the files into the storage directory and remove the originals, instead of
+    /// copying them.
+    pub move_files: bool,
+}
+
+impl Default for IngestExternalFileOptions {
+    fn default() -> Self {
+        Self {
+            allow_overlap: true,
+            move_files: false,
         }
     }
 }
@@ -80,24 +243,61 @@ pub enum CompactionFilter {
     Prefix(Bytes),
 }
 
+/// A column family is an isolated keyspace with its own memtables, levels and options. All
+/// column families share the wal and the manifest, and their memtables are frozen together.
+pub(crate) struct ColumnFamily {
+    pub(crate) id: usize,
+    pub(crate) name: String,
+    pub(crate) state: Arc<RwLock<Arc<LsmStorageState>>>,
+    pub(crate) options: Arc<LsmStorageOptions>,
+    pub(crate) compaction_controller: CompactionController,
+}
+
 /// The storage interface of the LSM tree.
 pub(crate) struct LsmStorageInner {
+    /// State of the default column family.
     pub(crate) state: Arc<RwLock<Arc<LsmStorageState>>>,
     pub(crate) state_lock: Mutex<()>,
     path: PathBuf,
-    // todo need a `BlockCache`, just use () for now
-    pub(crate) block_cache: Arc<()>,
+    pub(crate) file_system: Arc<dyn FileSystem>,
+    /// The lock on the storage directory, released on close.
+    dir_lock: Mutex<Option<FileLock>>,
+    pub(crate) block_cache: Option<Arc<BlockCache>>,
+    /// Keeps the SST files open, unless every SST keeps its own file.
+    pub(crate) table_cache: Option<Arc<TableCache>>,
     next_sst_id: AtomicUsize,
+    /// Options of the default column family, and of the storage engine itself.
     pub(crate) options: Arc<LsmStorageOptions>,
-    // todo need a `CompactionController`, just use () for now
-    pub(crate) compaction_controller: (),
-    // todo need a `Manifest`, just use () for now
-    pub(crate) manifest: Option<()>,
+    /// Serializes compaction, blob gc and ingestion, which all rewrite the levels. Taken before
+    /// `state_lock`.
+    pub(crate) compaction_lock: Mutex<()>,
+    pub(crate) manifest: Option<Manifest>,
+    /// All column families ordered by id, the default one first.
+    pub(crate) column_families: RwLock<Vec<Arc<ColumnFamily>>>,
+    /// The wal of the current memtables. Writers hold the lock while writing a batch to both the
+    /// wal and the memtables, so the wal order is the memtable order.
+    wal: Mutex<Option<Wal>>,
     #[allow(dead_code)]
     // todo need a `LsmMvccInner`, just use () for now
     pub(crate) mvcc: Option<()>,
     #[allow(dead_code)]
     pub(crate) compaction_filters: Arc<Mutex<Vec<CompactionFilter>>>,
+    write_stall: WriteStallCounters,
+    /// Writers blocked by a stop trigger wait on `stall_cvar`.
+    stall_mutex: Mutex<()>,
+    stall_cvar: Condvar,
+    /// The first error of a background flush or compaction, returned to every write until the
+    /// job that failed succeeds.
+    background_error: Mutex<Option<(BackgroundJob, Error)>>,
+    /// Runs the subcompactions of every column family, `None` when `max_subcompactions` is 1.
+    subcompaction_pool: Option<ThreadPool>,
+}
+
+/// The background jobs whose errors are returned to writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BackgroundJob {
+    Flush,
+    Compaction,
 }
 
 /// A thin wrapper for `LsmStorageInner` and the user interace for MiniLSM.
@@ -111,6 +311,8 @@ pub struct MiniLsm {
     compaction_notifier: crossbeam_channel::Sender<()>,
     /// The handle for the compaction thread.
     compaction_thread: Mutex<Option<std::thread::JoinHandle<()>>>,
+    /// Set once the storage is closed.
+    closed: AtomicBool,
 }
 
 impl Drop for MiniLsm {
@@ -123,16 +325,45 @@ impl Drop for MiniLsm {
 }
 
 impl MiniLsm {
-    pub fn close(&self) -> Result<()> {
-        // todo!()
-        Ok(())
+    /// Stop the flush and compaction threads, and make the memtables durable: the wal is synced,
+    /// or the memtables are flushed when the wal is disabled. Every later call returns
+    /// `Error::Closed`.
+    pub fn close(&self) -> error::Result<()> {
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        self.flush_notifier.send(()).ok();
+        self.compaction_notifier.send(()).ok();
+        if let Some(flush_thread) = self.flush_thread.lock().take() {
+            flush_thread
+                .join()
+                .map_err(|e| Error::Internal(format!("flush thread panicked: {:?}", e)))?;
+        }
+        if let Some(compaction_thread) = self.compaction_thread.lock().take() {
+            compaction_thread
+                .join()
+                .map_err(|e| Error::Internal(format!("compaction thread panicked: {:?}", e)))?;
+        }
+        Ok(self.inner.close()?)
     }
 
     /// Start the storage engine by either loading an existing directory or creating a new one if the directory does
     /// not exist.
     ///
-    pub fn open(path: impl AsRef<Path>, option: LsmStorageOptions) -> Result<Arc<Self>> {
-        let inner = Arc::new(LsmStorageInner::open(path, option)?);
+    pub fn open(path: impl AsRef<Path>, option: LsmStorageOptions) -> error::Result<Arc<Self>> {
+        Self::open_with_column_families(path, option, HashMap::new())
+    }
+
+    /// Start the storage engine with options for the column families. Column families found in
+    /// the directory without options in `cf_options` use `option`.
+    pub fn open_with_column_families(
+        path: impl AsRef<Path>,
+        option: LsmStorageOptions,
+        cf_options: HashMap<String, LsmStorageOptions>,
+    ) -> error::Result<Arc<Self>> {
+        let inner = Arc::new(LsmStorageInner::open_with_column_families(
+            path, option, cf_options,
+        )?);
         let (tx1, rx) = crossbeam_channel::unbounded();
         let compaction_thread = inner.spawn_compation_thread(rx)?;
         let (tx2, rx) = crossbeam_channel::unbounded();
@@ -143,98 +374,656 @@ impl MiniLsm {
             flush_thread: Mutex::new(flus_thread),
             compaction_notifier: tx1,
             compaction_thread: Mutex::new(compaction_thread),
+            closed: AtomicBool::new(false),
         };
         Ok(Arc::new(lsm))
     }
 
+    fn check_open(&self) -> error::Result<()> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(Error::Closed);
+        }
+        Ok(())
+    }
+
     /// Get a key from the storage.
-    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
-        self.inner.get(key)
+    pub fn get(&self, key: &[u8]) -> error::Result<Option<Bytes>> {
+        self.check_open()?;
+        Ok(self.inner.get(key)?)
     }
 
     /// Put a key-value pair into the storage.
-    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
-        self.inner.put(key, value)
+    pub fn put(&self, key: &[u8], value: &[u8]) -> error::Result<()> {
+        self.check_open()?;
+        Ok(self.inner.put(key, value)?)
     }
     /// Delete a key from the storage.
-    pub fn delete(&self, key: &[u8]) -> Result<()> {
-        self.inner.delete(key)
+    pub fn delete(&self, key: &[u8]) -> error::Result<()> {
+        self.check_open()?;
+        Ok(self.inner.delete(key)?)
+    }
+
+    /// Apply a batch of writes to the default column family atomically.
+    pub fn write_batch<T: AsRef<[u8]>>(&self, batch: &[WriteBatchRecord<T>]) -> error::Result<()> {
+        self.check_open()?;
+        Ok(self.inner.write_batch(batch)?)
+    }
+
+    /// Create a column family with its own options.
+    pub fn create_column_family(&self, name: &str, option: LsmStorageOptions) -> error::Result<()> {
+        self.check_open()?;
+        Ok(self.inner.create_column_family(name, option)?)
+    }
+
+    /// Names of all column families, the default one first.
+    pub fn column_family_names(&self) -> Vec<String> {
+        self.inner
+            .column_families
+            .read()
+            .iter()
+            .map(|cf| cf.name.clone())
+            .collect()
+    }
+
+    /// Get a key from a column family.
+    pub fn get_cf(&self, cf: &str, key: &[u8]) -> error::Result<Option<Bytes>> {
+        self.check_open()?;
+        Ok(self
+            .inner
+            .get_cf(self.inner.column_family(cf)?.as_ref(), key)?)
+    }
+
+    /// Put a key-value pair into a column family.
+    pub fn put_cf(&self, cf: &str, key: &[u8], value: &[u8]) -> error::Result<()> {
+        self.write_batch_cf(&[(cf, WriteBatchRecord::Put(key, value))])
+    }
+
+    /// Delete a key from a column family.
+    pub fn delete_cf(&self, cf: &str, key: &[u8]) -> error::Result<()> {
+        self.write_batch_cf(&[(cf, WriteBatchRecord::Del(key))])
+    }
+
+    /// Apply a batch of writes to several column families atomically.
+    pub fn write_batch_cf<T: AsRef<[u8]>>(
+        &self,
+        batch: &[(&str, WriteBatchRecord<T>)],
+    ) -> error::Result<()> {
+        self.check_open()?;
+        Ok(self.inner.write_batch_cf(batch)?)
+    }
+
+    /// Garbage collect a blob file: live values are moved to a new blob file and the pointers to
+    /// them are updated, values that have been overwritten or deleted are dropped. With
+    /// `blob_gc_garbage_ratio` set, the compaction thread does it once a blob file is mostly
+    /// garbage.
+    pub fn gc_blob_file(&self, blob_id: usize) -> error::Result<()> {
+        self.check_open()?;
+        Ok(self.inner.gc_blob_file(blob_id)?)
+    }
+
+    /// Ingest SSTs built by `SstFileWriter` into the default column family.
+    pub fn ingest_external_files(
+        &self,
+        paths: &[impl AsRef<Path>],
+        options: &IngestExternalFileOptions,
+    ) -> error::Result<()> {
+        self.ingest_external_files_cf(DEFAULT_COLUMN_FAMILY, paths, options)
+    }
+
+    /// Ingest SSTs built by `SstFileWriter` into a column family.
+    pub fn ingest_external_files_cf(
+        &self,
+        cf: &str,
+        paths: &[impl AsRef<Path>],
+        options: &IngestExternalFileOptions,
+    ) -> error::Result<()> {
+        self.check_open()?;
+        Ok(self.inner.ingest_external_files(
+            self.inner.column_family(cf)?.as_ref(),
+            paths,
+            options,
+        )?)
+    }
+
+    /// Create a checkpoint in `dir`, a new directory that `MiniLsm::open` can open as an
+    /// independent storage holding the data written so far.
+    pub fn create_checkpoint(&self, dir: impl AsRef<Path>) -> error::Result<()> {
+        self.check_open()?;
+        Ok(self.inner.create_checkpoint(dir)?)
+    }
+
+    /// Check the checksums and key order of every live SST, that the blob pointers fall inside
+    /// their blob file, and that the wal files hold complete batches. Blob values and wal batches
+    /// have no checksum, so their corruption is not found. Problems are listed in the report, an
+    /// error is only returned when the check itself fails.
+    pub fn verify(&self) -> error::Result<VerifyReport> {
+        self.check_open()?;
+        Ok(self.inner.verify()?)
+    }
+
+    /// Flush the current and all immutable memtables of every column family to L0.
+    pub fn force_flush(&self) -> error::Result<()> {
+        self.check_open()?;
+        Ok(self.inner.flush_all_memtables()?)
+    }
+
+    /// Compact every SST of the default column family overlapping `[start, end]` down to
+    /// `target_level`, waiting for the compaction to finish.
+    pub fn compact_range(
+        &self,
+        start: &[u8],
+        end: &[u8],
+        target_level: usize,
+    ) -> error::Result<()> {
+        self.compact_range_cf(DEFAULT_COLUMN_FAMILY, start, end, target_level)
+    }
+
+    /// Compact every SST of a column family overlapping `[start, end]` down to `target_level`.
+    pub fn compact_range_cf(
+        &self,
+        cf: &str,
+        start: &[u8],
+        end: &[u8],
+        target_level: usize,
+    ) -> error::Result<()> {
+        self.check_open()?;
+        Ok(self
+            .inner
+            .compact_range(&self.inner.column_family(cf)?, start, end, target_level)?)
+    }
+
+    /// Writes delayed or blocked because flush or compaction fell behind.
+    pub fn write_stall_stats(&self) -> WriteStallStats {
+        self.inner.write_stall.stats()
     }
 }
 
+/// A column family being rebuilt from the manifest.
+struct RecoveredColumnFamily {
+    id: usize,
+    name: String,
+    state: LsmStorageState,
+    options: Arc<LsmStorageOptions>,
+    blob_ids: BTreeSet<usize>,
+}
+
 impl LsmStorageInner {
     fn next_sst_id(&self) -> usize {
         self.next_sst_id
             .fetch_add(1, std::sync::atomic::Ordering::SeqCst)
     }
 
-    fn open(path: impl AsRef<Path>, option: LsmStorageOptions) -> Result<Self> {
-        //todo
-        todo!()
+    #[cfg(test)]
+    fn open(path: impl AsRef<Path>, option: LsmStorageOptions) -> Result<Arc<Self>> {
+        Ok(Arc::new(Self::open_with_column_families(
+            path,
+            option,
+            HashMap::new(),
+        )?))
+    }
+
+    /// Open the storage, rebuilding the column families from the manifest and replaying the wal
+    /// of the memtables that were not flushed.
+    fn open_with_column_families(
+        path: impl AsRef<Path>,
+        option: LsmStorageOptions,
+        mut cf_options: HashMap<String, LsmStorageOptions>,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let fs = option
+            .file_system
+            .clone()
+            .unwrap_or_else(|| Arc::new(StdFileSystem));
+        if !fs.exists(path) {
+            fs.create_dir_all(path)
+                .context("Failed to create storage directory")?;
+        }
+        let dir_lock = fs
+            .lock(&path.join("LOCK"))
+            .with_context(|| format!("Failed to lock {}", path.display()))?;
+        let option = Arc::new(option);
+        let mut cfs = vec![RecoveredColumnFamily {
+            id: 0,
+            name: DEFAULT_COLUMN_FAMILY.to_string(),
+            state: LsmStorageState::create(&option),
+            options: option.clone(),
+            blob_ids: BTreeSet::new(),
+        }];
+        // the next id of memtables, SSTs and blob files
+        let mut next_sst_id = 0;
+        // memtable generations, from the earliest to the latest
+        let mut memtables = Vec::new();
+        let mut flushed_memtables = HashSet::new();
+
+        let manifest_path = Self::path_of_manifest_static(path);
+        let manifest = if fs.exists(&manifest_path) {
+            let (manifest, records) = Manifest::recover(fs.as_ref(), &manifest_path)?;
+            for record in records {
+                match record {
+                    ManifestRecord::NewMemtable(memtable_id) => {
+                        memtables.push(memtable_id);
+                        next_sst_id = next_sst_id.max(memtable_id + 1);
+                    }
+                    ManifestRecord::Flush(memtable_id, tables) => {
+                        flushed_memtables.insert(memtable_id);
+                        for table in tables {
+                            let cf = Self::recovered_cf(&mut cfs, table.cf_id)?;
+                            cf.state.l0_sstable.insert(0, table.sst_id);
+                            cf.blob_ids.extend(table.blob_id);
+                            next_sst_id = next_sst_id.max(table.sst_id + 1);
+                        }
+                    }
+                    ManifestRecord::Ingest(cf_id, tables) => {
+                        let cf = Self::recovered_cf(&mut cfs, cf_id)?;
+                        for (level, sst_id) in tables {
+                            let ids = Self::level_ids_mut(&mut cf.state, level)?;
+                            // L0 is kept from the latest to the earliest SST
+                            match level {
+                                0 => ids.insert(0, sst_id),
+                                _ => ids.push(sst_id),
+                            }
+                            next_sst_id = next_sst_id.max(sst_id + 1);
+                        }
+                    }
+                    ManifestRecord::CreateColumnFamily(cf_id, name) => {
+                        let options = Arc::new(
+                            cf_options
+                                .remove(&name)
+                                .unwrap_or_else(|| option.as_ref().clone()),
+                        );
+                        cfs.push(RecoveredColumnFamily {
+                            id: cf_id,
+                            name,
+                            state: LsmStorageState::create(&options),
+                            options,
+                            blob_ids: BTreeSet::new(),
+                        });
+                    }
+                    ManifestRecord::BlobGc {
+                        cf_id,
+                        blob_id,
+                        new_blob_id,
+                        replaced,
+                    } => {
+                        let cf = Self::recovered_cf(&mut cfs, cf_id)?;
+                        for (old_sst_id, new_sst_id) in replaced {
+                            Self::replace_sst_id(&mut cf.state.l0_sstable, old_sst_id, new_sst_id);
+                            for (_, ids) in cf.state.levels.iter_mut() {
+                                Self::replace_sst_id(ids, old_sst_id, new_sst_id);
+                            }
+                            next_sst_id = next_sst_id.max(new_sst_id.unwrap_or(0) + 1);
+                        }
+                        cf.blob_ids.remove(&blob_id);
+                        cf.blob_ids.extend(new_blob_id);
+                        next_sst_id = next_sst_id.max(new_blob_id.unwrap_or(0) + 1);
+                    }
+                    ManifestRecord::Compaction {
+                        cf_id,
+                        task,
+                        output,
+                    } => {
+                        let cf = Self::recovered_cf(&mut cfs, cf_id)?;
+                        let controller = CompactionController::new(&cf.options.compaction_options);
+                        (cf.state, _) =
+                            controller.apply_compaction_result(&cf.state, &task, &output, true)?;
+                        next_sst_id = next_sst_id.max(output.iter().max().map_or(0, |id| id + 1));
+                    }
+                }
+            }
+            manifest
+        } else {
+            Manifest::create(fs.as_ref(), &manifest_path)?
+        };
+
+        // open the SSTs and blob files
+        let table_cache = option
+            .max_open_files
+            .map(|max_open_files| Arc::new(TableCache::new(max_open_files, fs.clone())));
+        for cf in cfs.iter_mut() {
+            for sst_id in Self::sst_ids(&cf.state) {
+                let sst = SsTable::open(
+                    sst_id,
+                    FileObject::open(fs.as_ref(), &Self::path_of_sst_static(path, sst_id))?,
+                )?
+                .with_verify_checksums(cf.options.verify_checksums)
+                .with_block_cache(option.block_cache.clone())
+                .with_table_cache(table_cache.clone());
+                cf.state.sstables.insert(sst_id, Arc::new(sst));
+            }
+            // a compaction may have dropped the last pointers to a blob file before its removal
+            let live_bytes = Self::blob_live_bytes(&cf.state);
+            for blob_id in &cf.blob_ids {
+                let blob_path = Self::path_of_blob_static(path, *blob_id);
+                if !live_bytes.contains_key(blob_id) {
+                    if fs.exists(&blob_path) {
+                        fs.remove_file(&blob_path)?;
+                    }
+                    continue;
+                }
+                let blob = BlobFile::open(fs.as_ref(), *blob_id, blob_path)?;
+                cf.state.blob_files.insert(*blob_id, Arc::new(blob));
+            }
+            // ingested SSTs are appended to their level, keep the levels sorted by key
+            let sstables = &cf.state.sstables;
+            for (_, ids) in cf.state.levels.iter_mut() {
+                ids.sort_by(|a, b| sstables[a].first_key().cmp(sstables[b].first_key()));
+            }
+        }
+
+        // replay the wal of the memtables not flushed yet, they become immutable memtables
+        for memtable_id in memtables {
+            if flushed_memtables.contains(&memtable_id) {
+                continue;
+            }
+            let cf_memtables = cfs
+                .iter()
+                .map(|cf| (cf.id, MemTable::create(memtable_id)))
+                .collect::<HashMap<_, _>>();
+            let wal_path = Self::path_of_wal_static(path, memtable_id);
+            if fs.exists(&wal_path) {
+                for (cf_id, key, value) in Wal::read_records(fs.as_ref(), &wal_path)? {
+                    cf_memtables
+                        .get(&cf_id)
+                        .with_context(|| format!("column family {} not found", cf_id))?
+                        .put_value(&key, value)?;
+                }
+            }
+            for (cf_id, memtable) in cf_memtables {
+                let cf = Self::recovered_cf(&mut cfs, cf_id)?;
+                cf.state.imm_memtable.insert(0, Arc::new(memtable));
+            }
+        }
+
+        // start a new memtable generation
+        let memtable_id = next_sst_id;
+        let wal = if option.enable_wal {
+            Some(Wal::create(
+                fs.as_ref(),
+                Self::path_of_wal_static(path, memtable_id),
+            )?)
+        } else {
+            None
+        };
+        manifest.add_record_when_init(ManifestRecord::NewMemtable(memtable_id))?;
+
+        let column_families = cfs
+            .into_iter()
+            .map(|mut cf| {
+                cf.state.memtable = Arc::new(MemTable::create(memtable_id));
+                Arc::new(ColumnFamily {
+                    id: cf.id,
+                    name: cf.name,
+                    state: Arc::new(RwLock::new(Arc::new(cf.state))),
+                    compaction_controller: CompactionController::new(
+                        &cf.options.compaction_options,
+                    ),
+                    options: cf.options,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        Ok(Self {
+            state: column_families[0].state.clone(),
+            state_lock: Mutex::new(()),
+            path: path.to_path_buf(),
+            file_system: fs,
+            dir_lock: Mutex::new(Some(dir_lock)),
+            block_cache: option.block_cache.clone(),
+            table_cache,
+            next_sst_id: AtomicUsize::new(memtable_id + 1),
+            subcompaction_pool: (option.max_subcompactions > 1)
+                .then(|| ThreadPool::new(option.max_subcompactions)),
+            options: option,
+            compaction_lock: Mutex::new(()),
+            manifest: Some(manifest),
+            column_families: RwLock::new(column_families),
+            wal: Mutex::new(wal),
+            mvcc: None,
+            compaction_filters: Arc::new(Mutex::new(Vec::new())),
+            write_stall: WriteStallCounters::default(),
+            stall_mutex: Mutex::new(()),
+            stall_cvar: Condvar::new(),
+            background_error: Mutex::new(None),
+        })
+    }
+
+    fn recovered_cf(
+        cfs: &mut [RecoveredColumnFamily],
+        cf_id: usize,
+    ) -> Result<&mut RecoveredColumnFamily> {
+        cfs.iter_mut()
+            .find(|cf| cf.id == cf_id)
+            .with_context(|| format!("column family {} not found", cf_id))
+    }
+
+    /// SST ids of L0 (`level` 0) or of a level.
+    fn level_ids_mut(state: &mut LsmStorageState, level: usize) -> Result<&mut Vec<usize>> {
+        if level == 0 {
+            return Ok(&mut state.l0_sstable);
+        }
+        state
+            .levels
+            .iter_mut()
+            .find(|(l, _)| *l == level)
+            .map(|(_, ids)| ids)
+            .with_context(|| format!("level {} not found", level))
+    }
+
+    /// Make the memtables durable: sync the wal, or flush the memtables when the wal is disabled.
+    fn close(&self) -> Result<()> {
+        if self.options.enable_wal {
+            if let Some(wal) = self.wal.lock().as_ref() {
+                wal.sync()?;
+            }
+        } else {
+            self.flush_all_memtables()?;
+        }
+        // the directory can be opened again
+        self.dir_lock.lock().take();
+        Ok(())
+    }
+
+    /// Freeze the memtables and flush every immutable memtable.
+    fn flush_all_memtables(&self) -> Result<()> {
+        let has_data = self
+            .column_families
+            .read()
+            .iter()
+            .any(|cf| !cf.state.read().memtable.is_empty());
+        if has_data {
+            self.force_freeze_memtable(&self.state_lock.lock())?;
+        }
+        while self
+            .column_families
+            .read()
+            .iter()
+            .any(|cf| !cf.state.read().imm_memtable.is_empty())
+        {
+            self.force_flush_next_imm_memtable()?;
+        }
+        Ok(())
+    }
+
+    /// Find a column family by name.
+    pub(crate) fn column_family(&self, name: &str) -> Result<Arc<ColumnFamily>> {
+        self.column_families
+            .read()
+            .iter()
+            .find(|cf| cf.name == name)
+            .cloned()
+            .ok_or_else(|| {
+                Error::InvalidArgument(format!("column family {} not found", name)).into()
+            })
+    }
+
+    /// Create a column family, its memtable joins the current memtable generation.
+    fn create_column_family(&self, name: &str, option: LsmStorageOptions) -> Result<()> {
+        let state_lock = self.state_lock.lock();
+        // no write can happen while the column family is added
+        let _wal = self.wal.lock();
+        let mut column_families = self.column_families.write();
+        if column_families.iter().any(|cf| cf.name == name) {
+            bail!(Error::InvalidArgument(format!(
+                "column family {} already exists",
+                name
+            )));
+        }
+        let cf_id = column_families.iter().map(|cf| cf.id).max().unwrap_or(0) + 1;
+        let mut state = LsmStorageState::create(&option);
+        state.memtable = Arc::new(MemTable::create(self.state.read().memtable.id()));
+        if let Some(manifest) = &self.manifest {
+            manifest.add_record(
+                &state_lock,
+                ManifestRecord::CreateColumnFamily(cf_id, name.to_string()),
+            )?;
+        }
+        column_families.push(Arc::new(ColumnFamily {
+            id: cf_id,
+            name: name.to_string(),
+            state: Arc::new(RwLock::new(Arc::new(state))),
+            compaction_controller: CompactionController::new(&option.compaction_options),
+            options: Arc::new(option),
+        }));
+        Ok(())
     }
 
     /// Get the value for the given key.
     fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        self.get_cf(self.column_family(DEFAULT_COLUMN_FAMILY)?.as_ref(), key)
+    }
+
+    /// Get the value for the given key in a column family.
+    fn get_cf(&self, cf: &ColumnFamily, key: &[u8]) -> Result<Option<Bytes>> {
         let snapshot = {
-            let guard = self.state.read();
+            let guard = cf.state.read();
             Arc::clone(&guard)
         }; //drop global lock here
-        // search on the current memtable
+        // search on the current memtable, a tombstone means the key does not exist
         if let Some(value) = snapshot.memtable.get(key) {
-            if value.is_empty() {
-                // found tomestone, return key not exists
-                return Ok(None);
-            }
-            return Ok(Some(value));
+            return Ok(value.into_put());
         }
 
-        /// search on immutable memtablse.
+        // search on immutable memtablse.
         for memtable in snapshot.imm_memtable.iter() {
             if let Some(value) = memtable.get(key) {
-                if value.is_empty() {
-                    // found tomestone, return key not exists
-                    return Ok(None);
-                }
-                return Ok(Some(value));
+                return Ok(value.into_put());
             }
         }
 
-        /// search on sstables. todo
+        // search on sstables, from the latest to the earliest
+        for table_id in Self::sst_ids(&snapshot) {
+            if let Some(value) = snapshot.sstables[&table_id].get(key)? {
+                return Self::resolve_value(&snapshot, value);
+            }
+        }
         Ok(None)
     }
 
+    /// All SST ids, from the latest to the earliest.
+    fn sst_ids(snapshot: &LsmStorageState) -> Vec<usize> {
+        snapshot
+            .l0_sstable
+            .iter()
+            .chain(snapshot.levels.iter().flat_map(|(_, ids)| ids.iter()))
+            .copied()
+            .collect()
+    }
+
+    /// Turn a value read from an SST into the user value, reading the blob file if needed.
+    fn resolve_value(snapshot: &LsmStorageState, value: TableValue) -> Result<Option<Bytes>> {
+        match value {
+            TableValue::Delete => Ok(None),
+            TableValue::Inline(value) => Ok(Some(value)),
+            TableValue::Blob(pointer) => {
+                let blob = snapshot
+                    .blob_files
+                    .get(&pointer.file_id)
+                    .with_context(|| format!("blob file {} not found", pointer.file_id))?;
+                Ok(Some(blob.read(&pointer)?))
+            }
+        }
+    }
+
     pub fn write_batch<T: AsRef<[u8]>>(&self, batch: &[WriteBatchRecord<T>]) -> Result<()> {
-        for record in batch {
-            match record {
-                WriteBatchRecord::Del(key) => {
-                    let key = key.as_ref();
-                    assert!(!key.is_empty(), "key cannot be empty");
-                    let size;
-                    {
-                        let guard = self.state.read();
-                        guard.memtable.put(key, b"")?;
-                        size = guard.memtable.approximate_size();
-                    }
-                    self.try_freeze(size)?;
-                }
-                WriteBatchRecord::Put(key, value) => {
-                    let key = key.as_ref();
-                    let value = value.as_ref();
-                    assert!(!key.is_empty(), "key cannot be empty");
-                    assert!(!value.is_empty(), "value cannot be empty");
-                    let size;
-                    {
-                        let guard = self.state.read();
-                        guard.memtable.put(key, value)?;
-                        size = guard.memtable.approximate_size();
+        let batch = batch
+            .iter()
+            .map(|record| {
+                let record = match record {
+                    WriteBatchRecord::Put(key, value) => {
+                        WriteBatchRecord::Put(key.as_ref(), value.as_ref())
                     }
-                    self.try_freeze(size)?;
+                    WriteBatchRecord::Del(key) => WriteBatchRecord::Del(key.as_ref()),
+                };
+                (DEFAULT_COLUMN_FAMILY, record)
+            })
+            .collect::<Vec<_>>();
+        self.write_batch_cf(&batch)
+    }
+
+    /// Apply a batch of writes to several column families atomically: the whole batch is a
+    /// single wal record.
+    pub fn write_batch_cf<T: AsRef<[u8]>>(
+        &self,
+        batch: &[(&str, WriteBatchRecord<T>)],
+    ) -> Result<()> {
+        let mut records = Vec::with_capacity(batch.len());
+        for (cf, record) in batch {
+            let cf = self.column_family(cf)?;
+            let (key, value) = match record {
+                WriteBatchRecord::Del(key) => (key.as_ref(), Value::Delete),
+                WriteBatchRecord::Put(key, value) => (
+                    key.as_ref(),
+                    Value::Put(Bytes::copy_from_slice(value.as_ref())),
+                ),
+            };
+            Self::check_key_value(&cf.options, key, value.data())?;
+            records.push((cf, key, value));
+        }
+        let mut column_families = records
+            .iter()
+            .map(|(cf, _, _)| cf.as_ref())
+            .collect::<Vec<_>>();
+        column_families.sort_by_key(|cf| cf.id);
+        column_families.dedup_by_key(|cf| cf.id);
+        if let Some((_, error)) = self.background_error.lock().as_ref() {
+            bail!(error.clone());
+        }
+        self.maybe_stall_write(&column_families)?;
+
+        {
+            let wal = self.wal.lock();
+            if let Some(wal) = wal.as_ref() {
+                let wal_records = records
+                    .iter()
+                    .map(|(cf, key, value)| (cf.id, *key, value))
+                    .collect::<Vec<_>>();
+                wal.put_batch(&wal_records)?;
+                if self.options.sync_writes {
+                    wal.sync()?;
                 }
             }
+            for (cf, key, value) in records.iter() {
+                cf.state.read().memtable.put_value(key, value.clone())?;
+            }
+        }
+
+        let mut checked = HashSet::new();
+        for (cf, _, _) in &records {
+            if checked.insert(cf.id) {
+                let size = cf.state.read().memtable.approximate_size();
+                self.try_freeze(cf, size)?;
+            }
         }
         Ok(())
     }
 
+    /// Check the sizes of a key and value against the options, nothing of the batch is written
+    /// when one is invalid.
+    fn check_key_value(options: &LsmStorageOptions, key: &[u8], value: &[u8]) -> Result<()> {
+        check_key(key, options.max_key_size)?;
+        check_value(value, options.max_value_size)
+    }
+
     /// Put a key-value pair into the storage.
     ///
     pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
@@ -247,12 +1036,12 @@ impl LsmStorageInner {
         self.write_batch(&[WriteBatchRecord::Del(key)])
     }
 
-    fn try_freeze(&self, estimated_size: usize) -> Result<()> {
-        if estimated_size >= self.options.target_sst_size {
+    fn try_freeze(&self, cf: &ColumnFamily, estimated_size: usize) -> Result<()> {
+        if estimated_size >= cf.options.target_sst_size {
             let state_lock = self.state_lock.lock();
-            let guard = self.state.read();
+            let guard = cf.state.read();
             // the memetable could hava already been frozen, check again to ensure we really need to freeze
-            if guard.memtable.approximate_size() >= self.options.target_sst_size {
+            if guard.memtable.approximate_size() >= cf.options.target_sst_size {
                 drop(guard);
                 self.force_freeze_memtable(&state_lock)?;
             }
@@ -262,137 +1051,1764 @@ impl LsmStorageInner {
 
     /// Spawn the compaction thread.
     fn spawn_compation_thread(
-        &self,
+        self: &Arc<Self>,
         rx: crossbeam_channel::Receiver<()>,
     ) -> Result<Option<std::thread::JoinHandle<()>>> {
-        //todo
-        todo!()
+        let this = self.clone();
+        let handle = std::thread::spawn(move || {
+            let ticker = crossbeam_channel::tick(Duration::from_millis(50));
+            loop {
+                crossbeam_channel::select! {
+                    recv(ticker) -> _ => match this.trigger_compaction() {
+                        Ok(()) => this.clear_background_error(BackgroundJob::Compaction),
+                        Err(e) => this.set_background_error(
+                            BackgroundJob::Compaction,
+                            e.context("compaction failed"),
+                        ),
+                    },
+                    recv(rx) -> _ => return
+                }
+            }
+        });
+        Ok(Some(handle))
     }
 
     /// Spawn the flush thread.
     fn spawn_flush_thread(
-        &self,
+        self: &Arc<Self>,
         rx: crossbeam_channel::Receiver<()>,
     ) -> Result<Option<std::thread::JoinHandle<()>>> {
-        //todo
-        todo!()
+        let this = self.clone();
+        let handle = std::thread::spawn(move || {
+            let ticker = crossbeam_channel::tick(Duration::from_millis(50));
+            loop {
+                crossbeam_channel::select! {
+                    recv(ticker) -> _ => match this.trigger_flush() {
+                        Ok(()) => this.clear_background_error(BackgroundJob::Flush),
+                        Err(e) => {
+                            this.set_background_error(BackgroundJob::Flush, e.context("flush failed"))
+                        }
+                    },
+                    recv(rx) -> _ => return
+                }
+            }
+        });
+        Ok(Some(handle))
     }
 
-    /// Force freeze the current memtable to an immutable memtable
-    fn force_freeze_memtable(&self, state_lock_observer: &MutexGuard<'_, ()>) -> Result<()> {
-        let memtable_id = self.next_sst_id();
-        let memtable = if self.options.enable_wal {
-            Arc::new(MemTable::create_with_wal(
-                memtable_id,
-                self.path_of_wal(memtable_id),
-            )?)
-        } else {
-            Arc::new(MemTable::create(memtable_id))
+    /// Keep the error of a background flush or compaction for the writes, unless an earlier one
+    /// is still there.
+    fn set_background_error(&self, job: BackgroundJob, error: anyhow::Error) {
+        self.background_error
+            .lock()
+            .get_or_insert((job, error.into()));
+    }
+
+    /// Let writes go on once `job` succeeded, if it is the one that failed.
+    fn clear_background_error(&self, job: BackgroundJob) {
+        let mut background_error = self.background_error.lock();
+        if background_error
+            .as_ref()
+            .is_some_and(|(failed_job, _)| *failed_job == job)
+        {
+            *background_error = None;
+        }
+    }
+
+    /// Flush the earliest immutable memtables until no column family has too many of them.
+    fn trigger_flush(&self) -> Result<()> {
+        let need_flush = || {
+            self.column_families
+                .read()
+                .iter()
+                .any(|cf| cf.state.read().imm_memtable.len() >= cf.options.num_memttable_limit)
+        };
+        while need_flush() {
+            self.force_flush_next_imm_memtable()?;
+        }
+        Ok(())
+    }
+
+    /// Flush the earliest memtable generation: the earliest immutable memtable of every column
+    /// family goes to an L0 SST, then the wal of the generation is removed. Nothing happens when
+    /// another flush got there first.
+    pub(crate) fn force_flush_next_imm_memtable(&self) -> Result<()> {
+        let state_lock = self.state_lock.lock();
+        let column_families = self.column_families.read().clone();
+        let Some(memtable_id) = column_families
+            .iter()
+            .filter_map(|cf| cf.state.read().imm_memtable.last().map(|m| m.id()))
+            .min()
+        else {
+            return Ok(());
         };
 
-        self.freeze_memtable_with_memtable(memtable)?;
+        let mut flushed = Vec::new();
+        for cf in &column_families {
+            let flush_memtable = {
+                let guard = cf.state.read();
+                match guard.imm_memtable.last() {
+                    Some(memtable) if memtable.id() == memtable_id => memtable.clone(),
+                    _ => continue,
+                }
+            };
+
+            let sst_id = self.next_sst_id();
+            let rate_limiter = self.options.rate_limiter.clone();
+            let mut builder = SsTableBuilder::new(cf.options.block_size)
+                .with_compression(cf.options.compression.for_level(0))
+                .with_rate_limiter(rate_limiter.clone(), IoPriority::High)
+                .with_file_system(self.file_system.clone());
+            let mut blob_builder = match cf.options.min_blob_size {
+                Some(min_blob_size) => Some(
+                    BlobFileBuilder::create(
+                        self.file_system.clone(),
+                        sst_id,
+                        self.path_of_blob(sst_id),
+                        min_blob_size,
+                    )?
+                    .with_rate_limiter(rate_limiter, IoPriority::High),
+                ),
+                None => None,
+            };
+            flush_memtable.flush(&mut builder, blob_builder.as_mut())?;
+            let blob = match blob_builder {
+                Some(blob_builder) => blob_builder.build()?,
+                None => None,
+            };
+            let sst = if builder.is_empty() {
+                None
+            } else {
+                Some(Arc::new(
+                    builder
+                        .build(sst_id, self.path_of_sst(sst_id))?
+                        .with_verify_checksums(cf.options.verify_checksums)
+                        .with_block_cache(self.block_cache.clone())
+                        .with_table_cache(self.table_cache.clone()),
+                ))
+            };
 
-        //todo
+            {
+                let mut guard = cf.state.write();
+                let mut snapshot = guard.as_ref().clone();
+                let memtable = snapshot
+                    .imm_memtable
+                    .pop()
+                    .context("no immutable memtable to flush")?;
+                assert_eq!(memtable.id(), memtable_id);
+                if let Some(sst) = sst {
+                    snapshot.l0_sstable.insert(0, sst_id);
+                    snapshot.sstables.insert(sst_id, sst);
+                    flushed.push(FlushedTable {
+                        cf_id: cf.id,
+                        sst_id,
+                        blob_id: blob.as_ref().map(|blob| blob.id()),
+                    });
+                }
+                if let Some(blob) = blob {
+                    snapshot.blob_files.insert(blob.id(), Arc::new(blob));
+                }
+                *guard = Arc::new(snapshot);
+            }
+        }
 
+        if let Some(manifest) = &self.manifest {
+            manifest.add_record(&state_lock, ManifestRecord::Flush(memtable_id, flushed))?;
+        }
+        let wal_path = self.path_of_wal(memtable_id);
+        if self.file_system.exists(&wal_path) {
+            self.file_system.remove_file(&wal_path)?;
+        }
+        self.notify_write_stall();
         Ok(())
     }
 
-    pub(crate) fn path_of_wal_static(path: impl AsRef<Path>, id: usize) -> PathBuf {
-        path.as_ref().join(format!("{:05}.wal", id))
+    /// The level of SST `sst_id`, 0 for L0.
+    fn level_of(snapshot: &LsmStorageState, sst_id: usize) -> usize {
+        snapshot
+            .levels
+            .iter()
+            .find(|(_, ids)| ids.contains(&sst_id))
+            .map_or(0, |(level, _)| *level)
     }
 
-    pub(crate) fn path_of_wal(&self, id: usize) -> PathBuf {
-        Self::path_of_wal_static(&self.path, id)
+    /// Check whether `key` has a newer version than the one in SST `sst_id`.
+    fn has_newer_version(snapshot: &LsmStorageState, key: &[u8], sst_id: usize) -> Result<bool> {
+        if snapshot.memtable.get(key).is_some()
+            || snapshot.imm_memtable.iter().any(|m| m.get(key).is_some())
+        {
+            return Ok(true);
+        }
+        for table_id in Self::sst_ids(snapshot) {
+            if table_id == sst_id {
+                return Ok(false);
+            }
+            if snapshot.sstables[&table_id].get(key)?.is_some() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
     }
 
-    fn freeze_memtable_with_memtable(&self, memtable: Arc<MemTable>) -> Result<()> {
-        let mut guard = self.state.write();
-        // Swap the current memtable with a new one
-        let mut snapshot = guard.as_ref().clone();
-        let old_memtable = std::mem::replace(&mut snapshot.memtable, memtable);
-        // Add the old memtable to the immutable memtable list
-        snapshot.imm_memtable.insert(0, old_memtable.clone());
-        // Update the snapshot.
-        *guard = Arc::new(snapshot);
-        drop(guard);
-        // todo 同步wal
-        //old_memtable.sys_wal()?;
-        Ok(())
+    /// Garbage collect a blob file.
+    ///
+    /// Every SST referencing the blob file is rewritten: live values are copied to a new blob file
+    /// and their pointers updated, pointers to overwritten or deleted values are dropped.
+    ///
+    /// Like a compaction, the SSTs are rewritten under `compaction_lock` only, so that flushes go
+    /// on. The SSTs flushed meanwhile have their own blob files.
+    pub fn gc_blob_file(&self, blob_id: usize) -> Result<()> {
+        let _compaction_lock = self.compaction_lock.lock();
+        let cf = self
+            .column_families
+            .read()
+            .iter()
+            .find(|cf| cf.state.read().blob_files.contains_key(&blob_id))
+            .cloned()
+            .ok_or_else(|| Error::InvalidArgument(format!("blob file {} not found", blob_id)))?;
+        self.run_blob_gc(&cf, blob_id)
     }
-}
 
-/// 测试
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// The bytes of the values the SSTs of `snapshot` point to in each blob file.
+    pub(crate) fn blob_live_bytes(snapshot: &LsmStorageState) -> HashMap<usize, u64> {
+        let mut live_bytes = HashMap::new();
+        for table in snapshot.sstables.values() {
+            for (blob_id, bytes) in table.blob_references() {
+                *live_bytes.entry(*blob_id).or_default() += bytes;
+            }
+        }
+        live_bytes
+    }
 
-    /// Test format!
-    ///
-    #[test]
-    fn test_format() {
-        let s = format!("{:05}.wal", &1);
-        assert_eq!(s, "00001.wal");
+    /// Take the blob files no SST points into out of `snapshot`.
+    pub(crate) fn take_unreferenced_blob_files(
+        snapshot: &mut LsmStorageState,
+    ) -> Vec<Arc<BlobFile>> {
+        let live_bytes = Self::blob_live_bytes(snapshot);
+        let unreferenced = snapshot
+            .blob_files
+            .keys()
+            .filter(|blob_id| !live_bytes.contains_key(blob_id))
+            .copied()
+            .collect::<Vec<_>>();
+        unreferenced
+            .iter()
+            .filter_map(|blob_id| snapshot.blob_files.remove(blob_id))
+            .collect()
     }
 
-    /// Test lsmStorageState create
-    ///
-    #[test]
-    fn test_lsm_storage_state_create() {
-        let option = LsmStorageOptions {
-            block_size: 1024,
-            target_sst_size: 1024 * 1024,
-            num_memttable_limit: 10,
-            compaction_options: CompactionOptions::Simple(SimpleLeveledCompactionOptions {
-                size_ratio_percent: 10,
-                level0_file_num_compaction_trigger: 10,
-                max_levels: 10,
-            }),
-            enable_wal: true,
-            serialized: true,
-        };
-        let state = LsmStorageState::create(&option);
-        assert_eq!(state.memtable.id(), 0);
-        assert_eq!(state.imm_memtable.len(), 0);
-        assert_eq!(state.l0_sstable.len(), 0);
-        assert_eq!(state.levels.len(), 10);
-        assert_eq!(state.sstables.len(), 0);
+    /// The blob file with the largest share of garbage, once it passes `blob_gc_garbage_ratio`.
+    pub(crate) fn blob_gc_candidate(cf: &ColumnFamily) -> Option<usize> {
+        let threshold = cf.options.blob_gc_garbage_ratio?;
+        let snapshot = cf.state.read().clone();
+        let live_bytes = Self::blob_live_bytes(&snapshot);
+        let live = |blob: &BlobFile| live_bytes.get(&blob.id()).copied().unwrap_or_default();
+        let garbage_ratio = |blob: &BlobFile| 1.0 - live(blob) as f64 / blob.size().max(1) as f64;
+        snapshot
+            .blob_files
+            .values()
+            // a collected blob file holds no garbage, it is not collected again
+            .filter(|blob| live(blob) < blob.size() && garbage_ratio(blob) >= threshold)
+            .max_by(|a, b| garbage_ratio(a).total_cmp(&garbage_ratio(b)))
+            .map(|blob| blob.id())
     }
 
-    /// Test MiniLsm open
-    ///
-    //#[test]
-    fn test_minilsm_open() {
-        let path = PathBuf::from("test");
-        let option = LsmStorageOptions {
-            block_size: 1024,
-            target_sst_size: 1024 * 1024,
-            num_memttable_limit: 10,
+    /// Garbage collect the blob file `blob_id` of `cf`. The caller holds `compaction_lock`.
+    pub(crate) fn run_blob_gc(&self, cf: &ColumnFamily, blob_id: usize) -> Result<()> {
+        let snapshot = cf.state.read().clone();
+        let old_blob = snapshot.blob_files[&blob_id].clone();
+
+        let new_blob_id = self.next_sst_id();
+        // every live value goes to the new blob file, whatever its size
+        let mut new_blob = BlobFileBuilder::create(
+            self.file_system.clone(),
+            new_blob_id,
+            self.path_of_blob(new_blob_id),
+            0,
+        )?
+        .with_rate_limiter(self.options.rate_limiter.clone(), IoPriority::Low);
+        // (old sst id, rewritten sst)
+        let mut rewritten = Vec::new();
+        for sst_id in Self::sst_ids(&snapshot) {
+            let table = snapshot.sstables[&sst_id].clone();
+            let mut iter = SsTableIterator::create_and_seek_to_first(table)?;
+            let mut builder = SsTableBuilder::new(cf.options.block_size)
+                .with_compression(
+                    cf.options
+                        .compression
+                        .for_level(Self::level_of(&snapshot, sst_id)),
+                )
+                .with_rate_limiter(self.options.rate_limiter.clone(), IoPriority::Low)
+                .with_file_system(self.file_system.clone());
+            let mut referenced = false;
+            while iter.is_valid() {
+                match TableValue::decode(iter.value())? {
+                    TableValue::Blob(pointer) if pointer.file_id == blob_id => {
+                        referenced = true;
+                        if !Self::has_newer_version(&snapshot, iter.key(), sst_id)? {
+                            let value = old_blob.read(&pointer)?;
+                            builder.add_blob(iter.key(), new_blob.add(&value)?);
+                        }
+                    }
+                    _ => builder.add_raw(iter.key(), iter.value()),
+                }
+                iter.next()?;
+            }
+            if !referenced {
+                continue;
+            }
+            let new_sst = if builder.is_empty() {
+                None
+            } else {
+                let new_sst_id = self.next_sst_id();
+                Some(Arc::new(
+                    builder
+                        .build(new_sst_id, self.path_of_sst(new_sst_id))?
+                        .with_verify_checksums(cf.options.verify_checksums)
+                        .with_block_cache(self.block_cache.clone())
+                        .with_table_cache(self.table_cache.clone()),
+                ))
+            };
+            rewritten.push((sst_id, new_sst));
+        }
+        let new_blob = new_blob.build()?.map(Arc::new);
+
+        let state_lock = self.state_lock.lock();
+        let mut new_snapshot = cf.state.read().as_ref().clone();
+        for (old_sst_id, new_sst) in &rewritten {
+            let new_sst_id = new_sst.as_ref().map(|sst| sst.sst_id());
+            Self::replace_sst_id(&mut new_snapshot.l0_sstable, *old_sst_id, new_sst_id);
+            for (_, ids) in new_snapshot.levels.iter_mut() {
+                Self::replace_sst_id(ids, *old_sst_id, new_sst_id);
+            }
+            new_snapshot.sstables.remove(old_sst_id);
+            if let Some(new_sst) = new_sst {
+                new_snapshot
+                    .sstables
+                    .insert(new_sst.sst_id(), new_sst.clone());
+            }
+        }
+        new_snapshot.blob_files.remove(&blob_id);
+        if let Some(new_blob) = &new_blob {
+            new_snapshot
+                .blob_files
+                .insert(new_blob_id, new_blob.clone());
+        }
+        if let Some(manifest) = &self.manifest {
+            let record = ManifestRecord::BlobGc {
+                cf_id: cf.id,
+                blob_id,
+                new_blob_id: new_blob.as_ref().map(|_| new_blob_id),
+                replaced: rewritten
+                    .iter()
+                    .map(|(old, new)| (*old, new.as_ref().map(|sst| sst.sst_id())))
+                    .collect(),
+            };
+            if let Err(e) = manifest.add_record(&state_lock, record) {
+                for new_sst in rewritten.iter().filter_map(|(_, new_sst)| new_sst.as_ref()) {
+                    self.remove_sst(new_sst);
+                }
+                if let Some(new_blob) = &new_blob {
+                    new_blob.mark_obsolete(self.file_system.clone());
+                }
+                return Err(e);
+            }
+        }
+        *cf.state.write() = Arc::new(new_snapshot);
+        drop(state_lock);
+
+        for (old_sst_id, _) in &rewritten {
+            self.remove_sst(&snapshot.sstables[old_sst_id]);
+        }
+        old_blob.mark_obsolete(self.file_system.clone());
+        Ok(())
+    }
+
+    /// Ingest SSTs built by `SstFileWriter`.
+    ///
+    /// Each file goes to the lowest level where it overlaps nothing in that level and above, or to
+    /// L0 when it overlaps L0 or the memtables. Overlapping files are refused unless
+    /// `allow_overlap` is set.
+    fn ingest_external_files(
+        &self,
+        cf: &ColumnFamily,
+        paths: &[impl AsRef<Path>],
+        options: &IngestExternalFileOptions,
+    ) -> Result<()> {
+        let mut tables = Vec::with_capacity(paths.len());
+        let result = self.ingest_tables(cf, paths, options, &mut tables);
+        if result.is_err() {
+            for table in &tables {
+                self.remove_sst(table);
+            }
+        }
+        result
+    }
+
+    fn ingest_tables(
+        &self,
+        cf: &ColumnFamily,
+        paths: &[impl AsRef<Path>],
+        options: &IngestExternalFileOptions,
+        tables: &mut Vec<Arc<SsTable>>,
+    ) -> Result<()> {
+        // bring the files into the storage directory, the originals are only removed once ingested
+        for path in paths {
+            let path = path.as_ref();
+            let sst_id = self.next_sst_id();
+            let sst_path = self.path_of_sst(sst_id);
+            if !options.move_files || self.file_system.hard_link(path, &sst_path).is_err() {
+                self.file_system
+                    .copy(path, &sst_path)
+                    .with_context(|| format!("Failed to copy {}", path.display()))?;
+            }
+            let table = SsTable::open(
+                sst_id,
+                FileObject::open(self.file_system.as_ref(), &sst_path)?,
+            )
+            .with_context(|| format!("Invalid sst {}", path.display()))?
+            .with_verify_checksums(cf.options.verify_checksums)
+            .with_block_cache(self.block_cache.clone())
+            .with_table_cache(self.table_cache.clone());
+            tables.push(Arc::new(table));
+        }
+        // the files are durable before the manifest refers to them
+        self.file_system.sync_dir(&self.path)?;
+        if !options.allow_overlap {
+            let mut ranges = tables
+                .iter()
+                .map(|table| (table.first_key(), table.last_key()))
+                .collect::<Vec<_>>();
+            ranges.sort();
+            if ranges.windows(2).any(|w| w[0].1 >= w[1].0) {
+                bail!(Error::InvalidArgument(
+                    "ingested files overlap each other".to_string()
+                ));
+            }
+        }
+
+        let overlaps_memtables = |state: &LsmStorageState| {
+            tables.iter().any(|table| {
+                std::iter::once(&state.memtable)
+                    .chain(state.imm_memtable.iter())
+                    .any(|memtable| memtable.overlaps(table.first_key(), table.last_key()))
+            })
+        };
+        if overlaps_memtables(&cf.state.read()) {
+            if !options.allow_overlap {
+                bail!(Error::InvalidArgument(
+                    "ingested files overlap the memtables".to_string()
+                ));
+            }
+            // the ingested data is newer than the data in the memtables
+            self.flush_all_memtables()?;
+        }
+
+        let _compaction_lock = self.compaction_lock.lock();
+        let state_lock = self.state_lock.lock();
+        let mut snapshot = cf.state.read().as_ref().clone();
+        let mut placed = Vec::with_capacity(tables.len());
+        for table in tables.iter() {
+            let overlaps = |ids: &[usize]| {
+                ids.iter().any(|id| {
+                    let sst = &snapshot.sstables[id];
+                    sst.first_key() <= table.last_key() && table.first_key() <= sst.last_key()
+                })
+            };
+            let overlaps_l0 = overlaps(&snapshot.l0_sstable);
+            let overlaps_levels = snapshot.levels.iter().any(|(_, ids)| overlaps(ids));
+            if !options.allow_overlap && (overlaps_l0 || overlaps_levels) {
+                bail!(Error::InvalidArgument(
+                    "ingested file overlaps existing data".to_string()
+                ));
+            }
+            let mut level = 0;
+            if !overlaps_l0
+                && matches!(
+                    cf.options.compaction_options,
+                    CompactionOptions::Leveled(_) | CompactionOptions::Simple(_)
+                )
+            {
+                for (l, ids) in snapshot.levels.iter() {
+                    if overlaps(ids) {
+                        break;
+                    }
+                    level = *l;
+                }
+            }
+
+            snapshot.sstables.insert(table.sst_id(), table.clone());
+            if level == 0 {
+                snapshot.l0_sstable.insert(0, table.sst_id());
+            } else {
+                let sstables = &snapshot.sstables;
+                let ids = &mut snapshot.levels[level - 1].1;
+                let pos = ids.partition_point(|id| sstables[id].first_key() < table.first_key());
+                ids.insert(pos, table.sst_id());
+            }
+            placed.push((level, table.sst_id()));
+        }
+
+        if let Some(manifest) = &self.manifest {
+            manifest.add_record(&state_lock, ManifestRecord::Ingest(cf.id, placed))?;
+        }
+        *cf.state.write() = Arc::new(snapshot);
+        drop(state_lock);
+
+        if options.move_files {
+            for path in paths {
+                self.file_system.remove_file(path.as_ref()).ok();
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace `old` with `new` in a list of SST ids, or remove it when there is no new SST.
+    fn replace_sst_id(ids: &mut Vec<usize>, old: usize, new: Option<usize>) {
+        match new {
+            Some(new) => ids
+                .iter_mut()
+                .filter(|id| **id == old)
+                .for_each(|id| *id = new),
+            None => ids.retain(|id| *id != old),
+        }
+    }
+
+    /// Force freeze the current memtables of all column families to immutable memtables, they
+    /// start a new memtable generation with a new wal.
+    pub(crate) fn force_freeze_memtable(
+        &self,
+        state_lock_observer: &MutexGuard<'_, ()>,
+    ) -> Result<()> {
+        let memtable_id = self.next_sst_id();
+        let mut wal = self.wal.lock();
+        let new_wal = if self.options.enable_wal {
+            Some(Wal::create(
+                self.file_system.as_ref(),
+                self.path_of_wal(memtable_id),
+            )?)
+        } else {
+            None
+        };
+        if let Some(old_wal) = wal.as_ref() {
+            old_wal.sync()?;
+        }
+        // the writes only go to the new wal once the manifest replays it, on a failure they keep
+        // going to the old one
+        if let Some(manifest) = &self.manifest {
+            manifest.add_record(
+                state_lock_observer,
+                ManifestRecord::NewMemtable(memtable_id),
+            )?;
+        }
+        if let Some(new_wal) = new_wal {
+            *wal = Some(new_wal);
+        }
+
+        for cf in self.column_families.read().iter() {
+            Self::freeze_memtable_with_memtable(cf, Arc::new(MemTable::create(memtable_id)))?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn path_of_manifest_static(path: impl AsRef<Path>) -> PathBuf {
+        path.as_ref().join("MANIFEST")
+    }
+
+    pub(crate) fn path_of_wal_static(path: impl AsRef<Path>, id: usize) -> PathBuf {
+        path.as_ref().join(format!("{:05}.wal", id))
+    }
+
+    pub(crate) fn path_of_wal(&self, id: usize) -> PathBuf {
+        Self::path_of_wal_static(&self.path, id)
+    }
+
+    pub(crate) fn path_of_sst_static(path: impl AsRef<Path>, id: usize) -> PathBuf {
+        path.as_ref().join(format!("{:05}.sst", id))
+    }
+
+    pub(crate) fn path_of_sst(&self, id: usize) -> PathBuf {
+        Self::path_of_sst_static(&self.path, id)
+    }
+
+    /// Remove the file of an SST that left the state, once the snapshots and iterators still
+    /// reading it are dropped.
+    pub(crate) fn remove_sst(&self, table: &SsTable) {
+        table.mark_obsolete(self.file_system.clone());
+    }
+
+    pub(crate) fn path_of_blob_static(path: impl AsRef<Path>, id: usize) -> PathBuf {
+        path.as_ref().join(format!("{:05}.blob", id))
+    }
+
+    pub(crate) fn path_of_blob(&self, id: usize) -> PathBuf {
+        Self::path_of_blob_static(&self.path, id)
+    }
+
+    fn freeze_memtable_with_memtable(cf: &ColumnFamily, memtable: Arc<MemTable>) -> Result<()> {
+        let mut guard = cf.state.write();
+        // Swap the current memtable with a new one
+        let mut snapshot = guard.as_ref().clone();
+        let old_memtable = std::mem::replace(&mut snapshot.memtable, memtable);
+        // Add the old memtable to the immutable memtable list
+        snapshot.imm_memtable.insert(0, old_memtable.clone());
+        // Update the snapshot.
+        *guard = Arc::new(snapshot);
+        drop(guard);
+        Ok(())
+    }
+}
+
+/// 测试
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::{
+        compress::CompressionType,
+        fs::{FaultInjectionFs, FileOp, InMemoryFileSystem},
+    };
+
+    /// Test format!
+    ///
+    #[test]
+    fn test_format() {
+        let s = format!("{:05}.wal", &1);
+        assert_eq!(s, "00001.wal");
+    }
+
+    /// Test lsmStorageState create
+    ///
+    #[test]
+    fn test_lsm_storage_state_create() {
+        let option = LsmStorageOptions {
+            block_size: 1024,
+            target_sst_size: 1024 * 1024,
             compaction_options: CompactionOptions::Simple(SimpleLeveledCompactionOptions {
                 size_ratio_percent: 10,
                 level0_file_num_compaction_trigger: 10,
                 max_levels: 10,
             }),
-            enable_wal: true,
             serialized: true,
+            ..Default::default()
         };
-        let lsm = MiniLsm::open(path, option).unwrap();
-        //assert_eq!(lsm.inner.path, path);
-        assert_eq!(lsm.inner.block_cache, Arc::new(()));
+        let state = LsmStorageState::create(&option);
+        assert_eq!(state.memtable.id(), 0);
+        assert_eq!(state.imm_memtable.len(), 0);
+        assert_eq!(state.l0_sstable.len(), 0);
+        assert_eq!(state.levels.len(), 10);
+        assert_eq!(state.sstables.len(), 0);
+    }
+
+    /// Test MiniLsm open
+    ///
+    #[test]
+    fn test_minilsm_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test");
+        let option = LsmStorageOptions {
+            block_size: 1024,
+            target_sst_size: 1024 * 1024,
+            compaction_options: CompactionOptions::Simple(SimpleLeveledCompactionOptions {
+                size_ratio_percent: 10,
+                level0_file_num_compaction_trigger: 10,
+                max_levels: 10,
+            }),
+            serialized: true,
+            ..Default::default()
+        };
+        let lsm = MiniLsm::open(&path, option).unwrap();
+        assert_eq!(lsm.inner.path, path);
+        assert!(lsm.inner.block_cache.is_none());
+        // id 0 is taken by the initial memtable
         assert_eq!(
             lsm.inner
                 .next_sst_id
                 .load(std::sync::atomic::Ordering::Relaxed),
-            0
+            1
         );
-        //assert_eq!(lsm.inner.options, option);
-        assert_eq!(lsm.inner.compaction_controller, ());
-        assert_eq!(lsm.inner.manifest, None);
+        assert!(matches!(
+            lsm.inner
+                .column_family(DEFAULT_COLUMN_FAMILY)
+                .unwrap()
+                .compaction_controller,
+            CompactionController::Simple(_)
+        ));
+        assert!(lsm.inner.manifest.is_some());
         assert_eq!(lsm.inner.mvcc, None);
-        /* assert_eq!(
-            lsm.inner.compaction_filters,
-            Arc::new(Mutex::new(Vec::new()))
-        ); */
-        //删除目录
-        //std::fs::remove_dir_all(path).unwrap();
+        lsm.close().unwrap();
+    }
+
+    fn blob_option(min_blob_size: Option<usize>) -> LsmStorageOptions {
+        LsmStorageOptions {
+            target_sst_size: 1024 * 1024,
+            enable_wal: false,
+            min_blob_size,
+            ..Default::default()
+        }
+    }
+
+    fn flush(storage: &LsmStorageInner) -> Result<()> {
+        storage.force_freeze_memtable(&storage.state_lock.lock())?;
+        storage.force_flush_next_imm_memtable()
+    }
+
+    /// Test large values are separated into blob files on flush
+    ///
+    #[test]
+    fn test_flush_with_blob() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let storage = LsmStorageInner::open(dir.path(), blob_option(Some(16)))?;
+        let large = vec![b'x'; 64];
+        storage.put(b"small", b"value")?;
+        storage.put(b"large", &large)?;
+        storage.put(b"deleted", &large)?;
+        storage.delete(b"deleted")?;
+        flush(&storage)?;
+
+        let snapshot = storage.state.read().clone();
+        assert_eq!(snapshot.l0_sstable.len(), 1);
+        assert_eq!(snapshot.blob_files.len(), 1);
+        let sst = &snapshot.sstables[&snapshot.l0_sstable[0]];
+        assert!(matches!(sst.get(b"large")?, Some(TableValue::Blob(_))));
+        assert!(matches!(sst.get(b"small")?, Some(TableValue::Inline(_))));
+
+        assert_eq!(storage.get(b"small")?, Some(Bytes::from_static(b"value")));
+        assert_eq!(storage.get(b"large")?, Some(Bytes::from(large)));
+        assert_eq!(storage.get(b"deleted")?, None);
+        Ok(())
+    }
+
+    /// Test flush without key-value separation does not create blob files
+    ///
+    #[test]
+    fn test_flush_without_blob() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let storage = LsmStorageInner::open(dir.path(), blob_option(None))?;
+        storage.put(b"large", &[b'x'; 64])?;
+        flush(&storage)?;
+        let snapshot = storage.state.read().clone();
+        assert_eq!(snapshot.l0_sstable.len(), 1);
+        assert!(snapshot.blob_files.is_empty());
+        assert!(!storage.path_of_blob(snapshot.l0_sstable[0]).exists());
+        Ok(())
+    }
+
+    /// Test blob gc keeps live values and drops overwritten ones
+    ///
+    #[test]
+    fn test_gc_blob_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let storage = LsmStorageInner::open(dir.path(), blob_option(Some(16)))?;
+        storage.put(b"live", &[b'a'; 32])?;
+        storage.put(b"overwritten", &[b'b'; 32])?;
+        storage.put(b"deleted", &[b'c'; 32])?;
+        flush(&storage)?;
+        let old_blob_id = storage.state.read().l0_sstable[0];
+        let old_blob_size = storage.state.read().blob_files[&old_blob_id].size();
+
+        storage.put(b"overwritten", b"new")?;
+        storage.delete(b"deleted")?;
+        flush(&storage)?;
+
+        storage.gc_blob_file(old_blob_id)?;
+        let snapshot = storage.state.read().clone();
+        assert!(!snapshot.blob_files.contains_key(&old_blob_id));
+        assert!(!storage.path_of_blob(old_blob_id).exists());
+        assert!(!storage.path_of_sst(old_blob_id).exists());
+        assert_eq!(snapshot.blob_files.len(), 1);
+        let new_blob = snapshot.blob_files.values().next().unwrap();
+        assert_eq!(new_blob.size(), 32);
+        assert!(new_blob.size() < old_blob_size);
+        assert_eq!(snapshot.l0_sstable.len(), 2);
+
+        assert_eq!(storage.get(b"live")?, Some(Bytes::from(vec![b'a'; 32])));
+        assert_eq!(
+            storage.get(b"overwritten")?,
+            Some(Bytes::from_static(b"new"))
+        );
+        assert_eq!(storage.get(b"deleted")?, None);
+        assert!(storage.gc_blob_file(old_blob_id).is_err());
+        Ok(())
+    }
+
+    /// Test blob gc runs alongside flushes, the SSTs they add are kept when gc installs its own
+    ///
+    #[test]
+    fn test_gc_blob_file_with_flushes() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let storage = LsmStorageInner::open(dir.path(), blob_option(Some(16)))?;
+        for idx in 0..200 {
+            storage.put(format!("key_{:03}", idx).as_bytes(), &[b'a'; 32])?;
+        }
+        flush(&storage)?;
+        let old_blob_id = storage.state.read().l0_sstable[0];
+        std::thread::scope(|scope| -> Result<()> {
+            let gc = scope.spawn(|| storage.gc_blob_file(old_blob_id));
+            for round in 0..5 {
+                storage.put(format!("new_{}", round).as_bytes(), &[b'b'; 32])?;
+                flush(&storage)?;
+            }
+            gc.join().unwrap()
+        })?;
+
+        let snapshot = storage.state.read().clone();
+        assert!(!snapshot.blob_files.contains_key(&old_blob_id));
+        assert_eq!(snapshot.l0_sstable.len(), 6);
+        assert_eq!(snapshot.blob_files.len(), 6);
+        assert_eq!(storage.get(b"key_042")?, Some(Bytes::from(vec![b'a'; 32])));
+        for round in 0..5 {
+            assert_eq!(
+                storage.get(format!("new_{}", round).as_bytes())?,
+                Some(Bytes::from(vec![b'b'; 32]))
+            );
+        }
+        Ok(())
+    }
+
+    /// Test compaction removes the blob files no SST points into, and has the ones mostly
+    /// garbage collected, without calling gc by hand
+    ///
+    #[test]
+    fn test_blob_space_reclaimed_by_compaction() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let option = LsmStorageOptions {
+            compaction_options: CompactionOptions::Simple(SimpleLeveledCompactionOptions {
+                size_ratio_percent: 200,
+                level0_file_num_compaction_trigger: 1,
+                max_levels: 1,
+            }),
+            blob_gc_garbage_ratio: Some(0.5),
+            ..blob_option(Some(16))
+        };
+        let blob_bytes = |storage: &LsmStorageInner| {
+            let snapshot = storage.state.read().clone();
+            let ids = snapshot.blob_files.keys().copied().collect::<BTreeSet<_>>();
+            let size = snapshot
+                .blob_files
+                .values()
+                .map(|blob| blob.size())
+                .sum::<u64>();
+            (ids, size)
+        };
+        let put_round =
+            |storage: &Arc<LsmStorageInner>, keys: std::ops::Range<usize>, value: u8| {
+                for idx in keys {
+                    storage.put(format!("key_{:02}", idx).as_bytes(), &[value; 32])?;
+                }
+                flush(storage)?;
+                storage.trigger_compaction()?;
+                Ok::<_, anyhow::Error>(storage.state.read().levels[0].1.clone())
+            };
+        {
+            let storage = LsmStorageInner::open(dir.path(), option.clone())?;
+            let first = put_round(&storage, 0..10, b'a')?;
+            assert_eq!(blob_bytes(&storage), (BTreeSet::from([first[0]]), 320));
+
+            // every value of the first blob file is overwritten
+            let second = put_round(&storage, 0..10, b'b')?;
+            let (ids, size) = blob_bytes(&storage);
+            assert!(!ids.contains(&first[0]));
+            assert_eq!((ids.len(), size), (1, 320));
+            assert!(!storage.path_of_blob(first[0]).exists());
+
+            // half of the second one is, it is collected
+            let second_blob = *ids.first().unwrap();
+            put_round(&storage, 0..5, b'c')?;
+            let (ids, size) = blob_bytes(&storage);
+            assert!(!ids.contains(&second_blob));
+            assert_eq!((ids.len(), size), (2, 320));
+            assert!(!storage.path_of_blob(second_blob).exists());
+            assert!(second.iter().all(|id| !storage.path_of_sst(*id).exists()));
+        }
+
+        let storage = LsmStorageInner::open(dir.path(), option)?;
+        assert_eq!(blob_bytes(&storage).1, 320);
+        for idx in 0..10 {
+            let value = if idx < 5 { b'c' } else { b'b' };
+            assert_eq!(
+                storage.get(format!("key_{:02}", idx).as_bytes())?,
+                Some(Bytes::from(vec![value; 32]))
+            );
+        }
+        Ok(())
+    }
+
+    fn cf_option(target_sst_size: usize) -> LsmStorageOptions {
+        LsmStorageOptions {
+            target_sst_size,
+            ..Default::default()
+        }
+    }
+
+    /// Test invalid writes are rejected with a typed error, and a closed storage refuses calls
+    ///
+    #[test]
+    fn test_invalid_argument_and_closed() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut option = cf_option(1024 * 1024);
+        option.max_key_size = 8;
+        option.max_value_size = 16;
+        let lsm = MiniLsm::open(dir.path(), option)?;
+        let is_invalid =
+            |result: error::Result<()>| matches!(result, Err(Error::InvalidArgument(_)));
+        assert!(is_invalid(lsm.put(b"", b"value")));
+        assert!(is_invalid(lsm.delete(b"")));
+        assert!(is_invalid(lsm.put(b"too_long_key", b"value")));
+        assert!(is_invalid(lsm.put(b"key", &[b'x'; 17])));
+        assert!(is_invalid(lsm.put_cf("missing", b"key", b"value")));
+        // nothing of a batch is written when one record is invalid
+        assert!(is_invalid(lsm.write_batch(&[
+            WriteBatchRecord::Put(&b"key1"[..], &b"value"[..]),
+            WriteBatchRecord::Put(&b""[..], &b"value"[..]),
+        ])));
+        assert_eq!(lsm.get(b"key1")?, None);
+        lsm.put(b"key", &[b'x'; 16])?;
+
+        lsm.close()?;
+        assert_eq!(lsm.get(b"key"), Err(Error::Closed));
+        assert_eq!(lsm.put(b"key", b"value"), Err(Error::Closed));
+        lsm.close()?;
+        Ok(())
+    }
+
+    /// Test blocks are read through the block cache, and iterators can leave it alone
+    ///
+    #[test]
+    fn test_block_cache() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let cache = Arc::new(BlockCache::new(1 << 20, 2, 0.5));
+        let option = LsmStorageOptions {
+            block_cache: Some(cache.clone()),
+            ..cf_option(1024 * 1024)
+        };
+        let lsm = MiniLsm::open(dir.path(), option)?;
+        for idx in 0..100 {
+            lsm.put(format!("key_{:03}", idx).as_bytes(), b"value")?;
+        }
+        lsm.force_flush()?;
+        let table = {
+            let snapshot = lsm.inner.state.read();
+            snapshot.sstables[&snapshot.l0_sstable[0]].clone()
+        };
+        // only the meta section is cached, with high priority
+        let meta_usage = cache.usage();
+        assert!(meta_usage > 0);
+        assert_eq!(
+            cache
+                .shard_stats()
+                .iter()
+                .map(|stats| stats.high_pri_usage)
+                .sum::<usize>(),
+            meta_usage
+        );
+        let mut iter = SsTableIterator::create_and_seek_to_first_with_fill_cache(table, false)?;
+        while iter.is_valid() {
+            iter.next()?;
+        }
+        assert_eq!(cache.usage(), meta_usage);
+
+        let hits = || {
+            cache
+                .shard_stats()
+                .iter()
+                .map(|stats| stats.hits)
+                .sum::<u64>()
+        };
+        assert_eq!(lsm.get(b"key_001")?, Some(Bytes::from_static(b"value")));
+        let usage = cache.usage();
+        assert!(usage > 0);
+        assert_eq!(hits(), 0);
+        assert_eq!(lsm.get(b"key_002")?, Some(Bytes::from_static(b"value")));
+        assert_eq!(hits(), 1);
+        assert_eq!(cache.usage(), usage);
+        lsm.close()?;
+        Ok(())
+    }
+
+    /// Test a large scan does not evict the meta sections of the SSTs from the block cache, so the
+    /// SSTs evicted from the table cache open again without reading them
+    ///
+    #[test]
+    fn test_block_cache_keeps_meta() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let cache = Arc::new(BlockCache::new(16 << 10, 0, 0.5));
+        let option = LsmStorageOptions {
+            block_size: 256,
+            block_cache: Some(cache.clone()),
+            max_open_files: Some(1),
+            ..cf_option(1024 * 1024)
+        };
+        let lsm = MiniLsm::open(dir.path(), option)?;
+        for sst in 0..5 {
+            for idx in 0..200 {
+                lsm.put(format!("key_{}_{:03}", sst, idx).as_bytes(), b"value")?;
+            }
+            lsm.force_flush()?;
+        }
+        let high_pri_usage = || cache.shard_stats()[0].high_pri_usage;
+        let meta_usage = high_pri_usage();
+        assert!(meta_usage > 0);
+
+        let tables = {
+            let snapshot = lsm.inner.state.read();
+            snapshot
+                .l0_sstable
+                .iter()
+                .map(|id| snapshot.sstables[id].clone())
+                .collect::<Vec<_>>()
+        };
+        for table in tables {
+            let mut iter = SsTableIterator::create_and_seek_to_first(table)?;
+            while iter.is_valid() {
+                iter.next()?;
+            }
+        }
+        // the scan went through the low priority pool and filled the cache
+        assert!(cache.usage() > 12 << 10);
+        assert_eq!(high_pri_usage(), meta_usage);
+
+        let table_cache = lsm.inner.table_cache.clone().unwrap();
+        let table_misses = table_cache.stats().misses;
+        let misses = cache.shard_stats()[0].misses;
+        for sst in 0..5 {
+            let key = format!("key_{}_000", sst);
+            assert_eq!(lsm.get(key.as_bytes())?, Some(Bytes::from_static(b"value")));
+        }
+        // the SSTs opened again, only data blocks were missing from the block cache
+        assert!(table_cache.stats().misses >= table_misses + 4);
+        assert!(cache.shard_stats()[0].misses <= misses + 5);
+        lsm.close()?;
+        Ok(())
+    }
+
+    /// Test the storage runs on an in-memory file system, and locks its directory
+    ///
+    #[test]
+    fn test_in_memory_file_system() -> Result<()> {
+        let fs = Arc::new(InMemoryFileSystem::new());
+        let option = LsmStorageOptions {
+            file_system: Some(fs.clone()),
+            min_blob_size: Some(64),
+            ..cf_option(1024 * 1024)
+        };
+        let path = Path::new("/db");
+        let lsm = MiniLsm::open(path, option.clone())?;
+        assert!(MiniLsm::open(path, option.clone()).is_err());
+        for idx in 0..100 {
+            let value = format!("value_{:0width$}", idx, width = idx % 2 * 100);
+            lsm.put(format!("key_{:03}", idx).as_bytes(), value.as_bytes())?;
+        }
+        lsm.force_flush()?;
+        lsm.put(b"key_100", b"in the wal")?;
+        lsm.close()?;
+        let mut files = fs.list(path)?;
+        files.sort();
+        assert_eq!(
+            files,
+            ["00001.wal", "00002.blob", "00002.sst", "LOCK", "MANIFEST"]
+                .map(|name| path.join(name))
+        );
+
+        let lsm = MiniLsm::open(path, option)?;
+        assert_eq!(
+            lsm.get(b"key_001")?,
+            Some(Bytes::from(format!("value_{:0100}", 1)))
+        );
+        assert_eq!(lsm.get(b"key_002")?, Some(Bytes::from_static(b"value_2")));
+        assert_eq!(
+            lsm.get(b"key_100")?,
+            Some(Bytes::from_static(b"in the wal"))
+        );
+        lsm.close()?;
+        Ok(())
+    }
+
+    /// Test a failed background flush is returned to every write until a flush succeeds
+    ///
+    #[test]
+    fn test_background_error() -> Result<()> {
+        let fs = Arc::new(FaultInjectionFs::new(Arc::new(InMemoryFileSystem::new())));
+        let option = LsmStorageOptions {
+            num_memttable_limit: 1,
+            file_system: Some(fs.clone()),
+            ..cf_option(1024)
+        };
+        let lsm = MiniLsm::open("/db", option)?;
+        fs.fail(FileOp::Create, ".sst");
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        let error = loop {
+            assert!(std::time::Instant::now() < deadline, "no background error");
+            match lsm.put(b"key", &[b'v'; 256]) {
+                Ok(()) => std::thread::sleep(Duration::from_millis(10)),
+                Err(error) => break error,
+            }
+        };
+        assert!(matches!(error, Error::Io { .. }), "{}", error);
+        assert!(error.to_string().contains("flush failed"), "{}", error);
+        // writes keep failing while the flush does
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(lsm.put(b"key", b"value"), Err(error));
+
+        fs.clear_faults();
+        while lsm.put(b"key", b"value").is_err() {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "the flush did not recover"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!lsm.inner.state.read().l0_sstable.is_empty());
+        assert_eq!(lsm.get(b"key")?, Some(Bytes::from_static(b"value")));
+        lsm.close()?;
+        Ok(())
+    }
+
+    /// Test every write acknowledged with `sync_writes` survives power losses at random points,
+    /// while writing, flushing, compacting, collecting blob files or opening the storage
+    ///
+    #[test]
+    fn test_crash_consistency() -> Result<()> {
+        let fs = Arc::new(FaultInjectionFs::new(Arc::new(InMemoryFileSystem::new())));
+        let option = LsmStorageOptions {
+            compaction_options: CompactionOptions::Simple(SimpleLeveledCompactionOptions {
+                size_ratio_percent: 200,
+                level0_file_num_compaction_trigger: 2,
+                max_levels: 3,
+            }),
+            min_blob_size: Some(64),
+            // nothing flushes in the background
+            write_stall: WriteStallOptions {
+                imm_memtable_slowdown_trigger: usize::MAX,
+                imm_memtable_stop_trigger: usize::MAX,
+                l0_slowdown_trigger: usize::MAX,
+                l0_stop_trigger: usize::MAX,
+                ..WriteStallOptions::default()
+            },
+            file_system: Some(fs.clone()),
+            sync_writes: true,
+            ..cf_option(2048)
+        };
+        let path = Path::new("/db");
+        let key_of = |key: u64| format!("key_{:03}", key).into_bytes();
+        // xorshift64 with a fixed seed, every run crashes at the same points
+        let mut rng = 0x9e37_79b9_7f4a_7c15_u64;
+        let mut random = move |n: u64| {
+            rng ^= rng << 13;
+            rng ^= rng >> 7;
+            rng ^= rng << 17;
+            rng % n
+        };
+        // the last acknowledged value of every key, and the write that failed in the crash
+        let mut acked = BTreeMap::<u64, Option<Bytes>>::new();
+        let mut pending: Option<(u64, Option<Bytes>)> = None;
+        let mut crashes = 0;
+        for round in 0..100 {
+            if random(2) == 0 {
+                // crash while the storage is opened
+                fs.crash_after(random(40) as usize);
+                drop(LsmStorageInner::open(path, option.clone()));
+                fs.crash()?;
+            }
+            let storage = LsmStorageInner::open(path, option.clone())?;
+            // the failed write may or may not have survived
+            if let Some((key, value)) = pending.take() {
+                let recovered = storage.get(&key_of(key))?;
+                let before = acked.get(&key).cloned().flatten();
+                assert!(recovered == value || recovered == before, "round {}", round);
+                acked.insert(key, recovered);
+            }
+            for key in 0..50 {
+                let value = acked.get(&key).cloned().flatten();
+                assert_eq!(storage.get(&key_of(key))?, value, "round {}", round);
+            }
+
+            fs.crash_after(random(500) as usize);
+            for _ in 0..300 {
+                let key = random(50);
+                let result = match random(20) {
+                    0 => flush(&storage),
+                    1 => storage.force_flush_next_imm_memtable(),
+                    2 => storage.trigger_compaction(),
+                    3 => {
+                        let blob_id = storage.state.read().blob_files.keys().min().copied();
+                        blob_id.map_or(Ok(()), |blob_id| storage.gc_blob_file(blob_id))
+                    }
+                    4 | 5 => {
+                        pending = Some((key, None));
+                        storage.delete(&key_of(key))
+                    }
+                    _ => {
+                        let value = format!("{}_{}|", key, round).repeat(1 + random(12) as usize);
+                        pending = Some((key, Some(Bytes::from(value.clone()))));
+                        storage.put(&key_of(key), value.as_bytes())
+                    }
+                };
+                if result.is_err() {
+                    crashes += 1;
+                    break;
+                }
+                if let Some((key, value)) = pending.take() {
+                    acked.insert(key, value);
+                }
+            }
+            drop(storage);
+            if random(2) == 0 {
+                fs.crash()?;
+            } else {
+                fs.crash_with_torn_writes(|_, len| random(len + 1))?;
+            }
+        }
+        assert!(crashes > 50);
+        Ok(())
+    }
+
+    /// Test at most `max_open_files` SSTs are open, the others are opened again when read
+    ///
+    #[test]
+    fn test_max_open_files() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let option = LsmStorageOptions {
+            max_open_files: Some(2),
+            ..cf_option(1024 * 1024)
+        };
+        let lsm = MiniLsm::open(dir.path(), option.clone())?;
+        for sst in 0..5 {
+            for idx in 0..10 {
+                lsm.put(format!("key_{}_{:02}", sst, idx).as_bytes(), b"value")?;
+            }
+            lsm.force_flush()?;
+        }
+        let table_cache = lsm.inner.table_cache.clone().unwrap();
+        assert_eq!(table_cache.stats().usage, 2);
+        for sst in 0..5 {
+            let key = format!("key_{}_07", sst);
+            assert_eq!(lsm.get(key.as_bytes())?, Some(Bytes::from_static(b"value")));
+        }
+        let stats = table_cache.stats();
+        assert_eq!(stats.usage, 2);
+        assert!(stats.misses >= 3);
+
+        // an iterator keeps its SST open once it is evicted
+        let table = {
+            let snapshot = lsm.inner.state.read();
+            snapshot.sstables[&snapshot.l0_sstable[4]].clone()
+        };
+        let mut iter = SsTableIterator::create_and_seek_to_first(table)?;
+        for sst in 1..5 {
+            lsm.get(format!("key_{}_00", sst).as_bytes())?;
+        }
+        let mut count = 0;
+        while iter.is_valid() {
+            count += 1;
+            iter.next()?;
+        }
+        assert_eq!(count, 10);
+        lsm.close()?;
+
+        let lsm = MiniLsm::open(dir.path(), option)?;
+        assert_eq!(lsm.inner.table_cache.as_ref().unwrap().stats().usage, 2);
+        assert_eq!(lsm.get(b"key_0_00")?, Some(Bytes::from_static(b"value")));
+        lsm.close()?;
+        Ok(())
+    }
+
+    /// Test memtables are flushed on demand and a range is compacted down to the target level
+    ///
+    #[test]
+    fn test_force_flush_and_compact_range() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let option = LsmStorageOptions {
+            // the background compaction leaves the layout alone
+            compaction_options: CompactionOptions::Simple(SimpleLeveledCompactionOptions {
+                size_ratio_percent: 0,
+                level0_file_num_compaction_trigger: 100,
+                max_levels: 3,
+            }),
+            ..cf_option(1024 * 1024)
+        };
+        let lsm = MiniLsm::open(dir.path(), option)?;
+        for idx in 0..50 {
+            lsm.put(format!("key_{:03}", idx).as_bytes(), b"value")?;
+        }
+        lsm.force_flush()?;
+        for idx in 10..20 {
+            lsm.delete(format!("key_{:03}", idx).as_bytes())?;
+        }
+        lsm.force_flush()?;
+        {
+            let snapshot = lsm.inner.state.read();
+            assert!(snapshot.memtable.is_empty());
+            assert!(snapshot.imm_memtable.is_empty());
+            assert_eq!(snapshot.l0_sstable.len(), 2);
+        }
+
+        let is_invalid =
+            |result: error::Result<()>| matches!(result, Err(Error::InvalidArgument(_)));
+        assert!(is_invalid(lsm.compact_range(b"key_010", b"key_000", 2)));
+        assert!(is_invalid(lsm.compact_range(b"key_000", b"key_010", 4)));
+        lsm.compact_range(b"key_015", b"key_015", 2)?;
+        let snapshot = lsm.inner.state.read().clone();
+        assert!(snapshot.l0_sstable.is_empty());
+        assert!(snapshot.levels[0].1.is_empty());
+        assert_eq!(snapshot.levels[1].1.len(), 1);
+        // nothing is below L2, the tombstones are dropped
+        let table = snapshot.sstables[&snapshot.levels[1].1[0]].clone();
+        let mut iter = SsTableIterator::create_and_seek_to_first(table)?;
+        let mut entries = 0;
+        while iter.is_valid() {
+            assert_ne!(TableValue::decode(iter.value())?, TableValue::Delete);
+            entries += 1;
+            iter.next()?;
+        }
+        assert_eq!(entries, 40);
+        assert_eq!(lsm.get(b"key_015")?, None);
+        assert_eq!(lsm.get(b"key_025")?, Some(Bytes::from_static(b"value")));
+        lsm.close()?;
+        Ok(())
+    }
+
+    /// Test empty values stay apart from deletes in the memtables, the wal and the SSTs
+    ///
+    #[test]
+    fn test_empty_value() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let check = |storage: &LsmStorageInner| -> Result<()> {
+            for prefix in ["flushed", "frozen", "current"] {
+                let empty = format!("{}_empty", prefix);
+                let deleted = format!("{}_deleted", prefix);
+                assert_eq!(storage.get(empty.as_bytes())?, Some(Bytes::new()));
+                assert_eq!(storage.get(deleted.as_bytes())?, None);
+            }
+            Ok(())
+        };
+        {
+            let storage = LsmStorageInner::open(dir.path(), cf_option(1024 * 1024))?;
+            for prefix in ["flushed", "frozen", "current"] {
+                let empty = format!("{}_empty", prefix);
+                let deleted = format!("{}_deleted", prefix);
+                storage.put(deleted.as_bytes(), b"value")?;
+                storage.put(empty.as_bytes(), b"")?;
+                storage.delete(deleted.as_bytes())?;
+                match prefix {
+                    "flushed" => flush(&storage)?,
+                    "frozen" => storage.force_freeze_memtable(&storage.state_lock.lock())?,
+                    _ => {}
+                }
+            }
+            check(&storage)?;
+            storage.wal.lock().as_ref().unwrap().sync()?;
+        }
+        let storage = LsmStorageInner::open(dir.path(), cf_option(1024 * 1024))?;
+        check(&storage)?;
+        storage.flush_all_memtables()?;
+        check(&storage)
+    }
+
+    /// Test SSTs written with different codecs are read together after reopen
+    ///
+    #[test]
+    fn test_flush_with_compression() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let value = br#"{"name":"value","count":1}"#.repeat(64);
+        let mut option = cf_option(1024 * 1024);
+        {
+            let storage = LsmStorageInner::open(dir.path(), option.clone())?;
+            storage.put(b"plain", &value)?;
+            storage.flush_all_memtables()?;
+        }
+        option.compression = CompressionOptions::uniform(CompressionType::Zstd);
+        {
+            let storage = LsmStorageInner::open(dir.path(), option.clone())?;
+            storage.put(b"zstd", &value)?;
+            storage.flush_all_memtables()?;
+            option.compression = CompressionOptions::uniform(CompressionType::Lz4);
+        }
+        let storage = LsmStorageInner::open(dir.path(), option)?;
+        storage.put(b"lz4", &value)?;
+        storage.flush_all_memtables()?;
+        let snapshot = storage.state.read().clone();
+        let sizes = snapshot
+            .l0_sstable
+            .iter()
+            .map(|id| snapshot.sstables[id].table_size())
+            .collect::<Vec<_>>();
+        // latest first: lz4, zstd, plain
+        assert!(sizes[0] < sizes[2] && sizes[1] < sizes[2]);
+        for key in [b"plain".as_ref(), b"zstd", b"lz4"] {
+            assert_eq!(storage.get(key)?, Some(Bytes::from(value.clone())));
+        }
+        Ok(())
+    }
+
+    /// Test flush and blob gc write through the shared rate limiter with their priorities
+    ///
+    #[test]
+    fn test_rate_limited_flush_and_gc() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let rate_limiter = Arc::new(RateLimiter::new(0));
+        let mut option = blob_option(Some(16));
+        option.rate_limiter = Some(rate_limiter.clone());
+        let storage = LsmStorageInner::open(dir.path(), option)?;
+        storage.put(b"blob", &[b'a'; 32])?;
+        storage.put(b"inline", b"value")?;
+        flush(&storage)?;
+        let flushed = rate_limiter.total_bytes(IoPriority::High);
+        let blob_id = storage.state.read().l0_sstable[0];
+        let sst_size = storage.state.read().sstables[&blob_id].table_size();
+        assert_eq!(flushed, sst_size + 32);
+        assert_eq!(rate_limiter.total_bytes(IoPriority::Low), 0);
+
+        rate_limiter.set_bytes_per_second(1024 * 1024);
+        storage.gc_blob_file(blob_id)?;
+        assert_eq!(rate_limiter.total_bytes(IoPriority::High), flushed);
+        assert!(rate_limiter.total_bytes(IoPriority::Low) > 32);
+        assert_eq!(storage.get(b"blob")?, Some(Bytes::from_static(&[b'a'; 32])));
+        Ok(())
+    }
+
+    /// Test the memtables are recovered from the wal and the SSTs from the manifest
+    ///
+    #[test]
+    fn test_recover_from_wal_and_manifest() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        {
+            let storage = LsmStorageInner::open(dir.path(), cf_option(1024 * 1024))?;
+            storage.put(b"flushed", b"1")?;
+            storage.put(b"deleted", b"1")?;
+            flush(&storage)?;
+            storage.put(b"frozen", b"2")?;
+            storage.delete(b"deleted")?;
+            storage.force_freeze_memtable(&storage.state_lock.lock())?;
+            storage.put(b"current", b"3")?;
+            storage.wal.lock().as_ref().unwrap().sync()?;
+        }
+        let storage = LsmStorageInner::open(dir.path(), cf_option(1024 * 1024))?;
+        {
+            let snapshot = storage.state.read();
+            assert_eq!(snapshot.l0_sstable.len(), 1);
+            assert_eq!(snapshot.imm_memtable.len(), 2);
+        }
+        assert_eq!(storage.get(b"flushed")?, Some(Bytes::from_static(b"1")));
+        assert_eq!(storage.get(b"frozen")?, Some(Bytes::from_static(b"2")));
+        assert_eq!(storage.get(b"current")?, Some(Bytes::from_static(b"3")));
+        assert_eq!(storage.get(b"deleted")?, None);
+
+        // flushing the recovered memtables removes their wal
+        let imm_ids = storage
+            .state
+            .read()
+            .imm_memtable
+            .iter()
+            .map(|m| m.id())
+            .collect::<Vec<_>>();
+        storage.force_flush_next_imm_memtable()?;
+        storage.force_flush_next_imm_memtable()?;
+        for id in imm_ids {
+            assert!(!storage.path_of_wal(id).exists());
+        }
+        assert_eq!(storage.get(b"current")?, Some(Bytes::from_static(b"3")));
+        Ok(())
+    }
+
+    /// Test column families are isolated and share the wal
+    ///
+    #[test]
+    fn test_column_families() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let storage = LsmStorageInner::open(dir.path(), cf_option(1024 * 1024))?;
+        storage.create_column_family("meta", cf_option(1024 * 1024))?;
+        assert!(
+            storage
+                .create_column_family("meta", cf_option(1024))
+                .is_err()
+        );
+        let meta = storage.column_family("meta")?;
+        assert_eq!(meta.id, 1);
+        assert!(storage.column_family("missing").is_err());
+
+        storage.write_batch_cf(&[
+            (
+                DEFAULT_COLUMN_FAMILY,
+                WriteBatchRecord::<&[u8]>::Put(b"key", b"data"),
+            ),
+            ("meta", WriteBatchRecord::<&[u8]>::Put(b"key", b"meta")),
+        ])?;
+        assert_eq!(storage.get(b"key")?, Some(Bytes::from_static(b"data")));
+        assert_eq!(
+            storage.get_cf(&meta, b"key")?,
+            Some(Bytes::from_static(b"meta"))
+        );
+        storage.write_batch_cf(&[("meta", WriteBatchRecord::<&[u8]>::Del(b"key"))])?;
+        assert_eq!(storage.get_cf(&meta, b"key")?, None);
+        assert_eq!(storage.get(b"key")?, Some(Bytes::from_static(b"data")));
+
+        // a batch touching an unknown column family is rejected as a whole
+        assert!(
+            storage
+                .write_batch_cf(&[
+                    (
+                        DEFAULT_COLUMN_FAMILY,
+                        WriteBatchRecord::<&[u8]>::Put(b"other", b"data")
+                    ),
+                    ("missing", WriteBatchRecord::<&[u8]>::Put(b"other", b"data")),
+                ])
+                .is_err()
+        );
+        assert_eq!(storage.get(b"other")?, None);
+
+        // memtables of all column families are frozen and flushed together
+        flush(&storage)?;
+        assert_eq!(storage.state.read().l0_sstable.len(), 1);
+        assert_eq!(meta.state.read().l0_sstable.len(), 1);
+        assert!(meta.state.read().imm_memtable.is_empty());
+        assert_eq!(storage.get(b"key")?, Some(Bytes::from_static(b"data")));
+        assert_eq!(storage.get_cf(&meta, b"key")?, None);
+        Ok(())
+    }
+
+    /// Test column families and their data are recovered with their own options
+    ///
+    #[test]
+    fn test_column_families_recover() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        {
+            let storage = LsmStorageInner::open(dir.path(), cf_option(1024 * 1024))?;
+            storage.create_column_family("meta", cf_option(1024 * 1024))?;
+            storage.write_batch_cf(&[
+                ("meta", WriteBatchRecord::<&[u8]>::Put(b"flushed", b"1")),
+                (
+                    DEFAULT_COLUMN_FAMILY,
+                    WriteBatchRecord::<&[u8]>::Put(b"flushed", b"0"),
+                ),
+            ])?;
+            flush(&storage)?;
+            storage.write_batch_cf(&[("meta", WriteBatchRecord::<&[u8]>::Put(b"in_wal", b"1"))])?;
+            storage.wal.lock().as_ref().unwrap().sync()?;
+        }
+        let mut cf_options = HashMap::new();
+        cf_options.insert("meta".to_string(), cf_option(16));
+        let storage = LsmStorageInner::open_with_column_families(
+            dir.path(),
+            cf_option(1024 * 1024),
+            cf_options,
+        )?;
+        let meta = storage.column_family("meta")?;
+        assert_eq!(meta.options.target_sst_size, 16);
+        assert_eq!(meta.state.read().l0_sstable.len(), 1);
+        assert_eq!(
+            storage.get_cf(&meta, b"flushed")?,
+            Some(Bytes::from_static(b"1"))
+        );
+        assert_eq!(
+            storage.get_cf(&meta, b"in_wal")?,
+            Some(Bytes::from_static(b"1"))
+        );
+        assert_eq!(storage.get(b"flushed")?, Some(Bytes::from_static(b"0")));
+        assert_eq!(storage.get(b"in_wal")?, None);
+
+        // the small target size of meta freezes the memtables of every column family
+        let memtable_id = storage.state.read().memtable.id();
+        storage.write_batch_cf(&[(
+            "meta",
+            WriteBatchRecord::<&[u8]>::Put(b"large", &[b'x'; 32]),
+        )])?;
+        assert_ne!(storage.state.read().memtable.id(), memtable_id);
+        assert_eq!(
+            meta.state.read().memtable.id(),
+            storage.state.read().memtable.id()
+        );
+        Ok(())
+    }
+
+    /// Test blob gc survives reopen
+    ///
+    #[test]
+    fn test_gc_blob_file_recover() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut option = cf_option(1024 * 1024);
+        option.min_blob_size = Some(16);
+        {
+            let storage = LsmStorageInner::open(dir.path(), option.clone())?;
+            storage.put(b"live", &[b'a'; 32])?;
+            storage.put(b"overwritten", &[b'b'; 32])?;
+            flush(&storage)?;
+            let blob_id = storage.state.read().l0_sstable[0];
+            storage.put(b"overwritten", b"new")?;
+            flush(&storage)?;
+            storage.gc_blob_file(blob_id)?;
+        }
+        let storage = LsmStorageInner::open(dir.path(), option)?;
+        assert_eq!(storage.state.read().blob_files.len(), 1);
+        assert_eq!(storage.get(b"live")?, Some(Bytes::from(vec![b'a'; 32])));
+        assert_eq!(
+            storage.get(b"overwritten")?,
+            Some(Bytes::from_static(b"new"))
+        );
+        Ok(())
+    }
+
+    fn leveled_option() -> LsmStorageOptions {
+        LsmStorageOptions {
+            compaction_options: CompactionOptions::Simple(SimpleLeveledCompactionOptions {
+                size_ratio_percent: 200,
+                level0_file_num_compaction_trigger: 2,
+                max_levels: 3,
+            }),
+            ..cf_option(1024 * 1024)
+        }
+    }
+
+    fn write_external_sst(
+        path: &Path,
+        keys: std::ops::Range<usize>,
+        value: &[u8],
+    ) -> Result<PathBuf> {
+        let mut writer = crate::table::SstFileWriter::create(path, 4096);
+        for idx in keys {
+            writer.put(format!("key_{:03}", idx).as_bytes(), value)?;
+        }
+        Ok(writer.finish()?.path)
+    }
+
+    /// Test ingested files go to the lowest level they do not overlap
+    ///
+    #[test]
+    fn test_ingest_external_files_level() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let external = tempfile::tempdir()?;
+        let storage = LsmStorageInner::open(dir.path(), leveled_option())?;
+        let cf = storage.column_family(DEFAULT_COLUMN_FAMILY)?;
+        let options = IngestExternalFileOptions::default();
+
+        let first = write_external_sst(&external.path().join("1.sst"), 0..10, b"v1")?;
+        storage.ingest_external_files(&cf, &[&first], &options)?;
+        assert!(first.exists());
+        {
+            let snapshot = storage.state.read();
+            assert!(snapshot.l0_sstable.is_empty());
+            assert_eq!(snapshot.levels[2].1.len(), 1);
+        }
+
+        // overlaps L3, goes to L2
+        let second = write_external_sst(&external.path().join("2.sst"), 5..15, b"v2")?;
+        // does not overlap anything, goes to L3 before the first file
+        let third = write_external_sst(&external.path().join("3.sst"), 20..30, b"v3")?;
+        storage.ingest_external_files(&cf, &[&second, &third], &options)?;
+        {
+            let snapshot = storage.state.read();
+            assert_eq!(snapshot.levels[1].1.len(), 1);
+            assert_eq!(snapshot.levels[2].1.len(), 2);
+        }
+        assert_eq!(storage.get(b"key_001")?, Some(Bytes::from_static(b"v1")));
+        assert_eq!(storage.get(b"key_007")?, Some(Bytes::from_static(b"v2")));
+        assert_eq!(storage.get(b"key_025")?, Some(Bytes::from_static(b"v3")));
+
+        // overlapping files are refused when overlap is disallowed
+        let fourth = write_external_sst(&external.path().join("4.sst"), 0..3, b"v4")?;
+        let disallow = IngestExternalFileOptions {
+            allow_overlap: false,
+            ..Default::default()
+        };
+        assert!(
+            storage
+                .ingest_external_files(&cf, &[&fourth], &disallow)
+                .is_err()
+        );
+        assert_eq!(storage.get(b"key_001")?, Some(Bytes::from_static(b"v1")));
+
+        // the state is recovered from the manifest
+        drop(storage);
+        let storage = LsmStorageInner::open(dir.path(), leveled_option())?;
+        {
+            let snapshot = storage.state.read();
+            assert_eq!(snapshot.levels[1].1.len(), 1);
+            let l3 = &snapshot.levels[2].1;
+            assert_eq!(l3.len(), 2);
+            assert!(snapshot.sstables[&l3[0]].last_key() < snapshot.sstables[&l3[1]].first_key());
+        }
+        assert_eq!(storage.get(b"key_007")?, Some(Bytes::from_static(b"v2")));
+        // only the ingested files are in the storage directory
+        let num_ssts = std::fs::read_dir(dir.path())?
+            .filter(|entry| {
+                entry
+                    .as_ref()
+                    .is_ok_and(|e| e.path().extension().is_some_and(|ext| ext == "sst"))
+            })
+            .count();
+        assert_eq!(num_ssts, 3);
+        Ok(())
+    }
+
+    /// Test ingested files overlapping memtables or L0 go to L0 and win
+    ///
+    #[test]
+    fn test_ingest_external_files_overlap() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let external = tempfile::tempdir()?;
+        let storage = LsmStorageInner::open(dir.path(), leveled_option())?;
+        let cf = storage.column_family(DEFAULT_COLUMN_FAMILY)?;
+        storage.put(b"key_001", b"memtable")?;
+
+        let file = write_external_sst(&external.path().join("1.sst"), 0..10, b"v1")?;
+        let disallow = IngestExternalFileOptions {
+            allow_overlap: false,
+            ..Default::default()
+        };
+        assert!(
+            storage
+                .ingest_external_files(&cf, &[&file], &disallow)
+                .is_err()
+        );
+        assert_eq!(storage.state.read().sstables.len(), 0);
+
+        let move_files = IngestExternalFileOptions {
+            allow_overlap: true,
+            move_files: true,
+        };
+        storage.ingest_external_files(&cf, &[&file], &move_files)?;
+        assert!(!file.exists());
+        {
+            let snapshot = storage.state.read();
+            assert!(snapshot.memtable.is_empty());
+            assert!(snapshot.imm_memtable.is_empty());
+            assert_eq!(snapshot.l0_sstable.len(), 2);
+        }
+        assert_eq!(storage.get(b"key_001")?, Some(Bytes::from_static(b"v1")));
+
+        // files overlapping each other
+        let a = write_external_sst(&external.path().join("a.sst"), 20..30, b"a")?;
+        let b = write_external_sst(&external.path().join("b.sst"), 25..35, b"b")?;
+        assert!(
+            storage
+                .ingest_external_files(&cf, &[&a, &b], &disallow)
+                .is_err()
+        );
+        storage.ingest_external_files(&cf, &[&a, &b], &IngestExternalFileOptions::default())?;
+        assert_eq!(storage.get(b"key_027")?, Some(Bytes::from_static(b"b")));
+        assert_eq!(storage.get(b"key_021")?, Some(Bytes::from_static(b"a")));
+        Ok(())
+    }
+
+    /// Test files ingested into L0 still shadow the older L0 SSTs after a reopen
+    ///
+    #[test]
+    fn test_ingest_external_files_recover() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let external = tempfile::tempdir()?;
+        {
+            let storage = LsmStorageInner::open(dir.path(), leveled_option())?;
+            let cf = storage.column_family(DEFAULT_COLUMN_FAMILY)?;
+            storage.put(b"key_001", b"old")?;
+            flush(&storage)?;
+            let file = write_external_sst(&external.path().join("1.sst"), 0..10, b"new")?;
+            storage.ingest_external_files(&cf, &[&file], &IngestExternalFileOptions::default())?;
+            assert_eq!(storage.state.read().l0_sstable.len(), 2);
+            assert_eq!(storage.get(b"key_001")?, Some(Bytes::from_static(b"new")));
+        }
+        let storage = LsmStorageInner::open(dir.path(), leveled_option())?;
+        assert_eq!(storage.get(b"key_001")?, Some(Bytes::from_static(b"new")));
+        Ok(())
     }
 }
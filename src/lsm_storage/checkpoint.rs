@@ -0,0 +1,163 @@
+use std::path::Path;
+
+use anyhow::{Context, Result, bail};
+
+use super::LsmStorageInner;
+use crate::error::Error;
+
+impl LsmStorageInner {
+    /// Create a checkpoint in `dir`, a directory that can be opened as an independent storage
+    /// with the data written so far.
+    ///
+    /// SSTs and blob files are immutable, so they are hard linked (or copied when the directory is
+    /// on another file system), the manifest and the wal of the memtables are copied.
+    pub(crate) fn create_checkpoint(&self, dir: impl AsRef<Path>) -> Result<()> {
+        let dir = dir.as_ref();
+        if self.file_system.exists(dir) {
+            bail!(Error::InvalidArgument(format!(
+                "checkpoint directory {} already exists",
+                dir.display()
+            )));
+        }
+        if !self.options.enable_wal {
+            // the memtables only reach the checkpoint through SSTs
+            self.flush_all_memtables()?;
+        }
+        self.file_system
+            .create_dir_all(dir)
+            .context("Failed to create checkpoint directory")?;
+
+        // no flush, compaction or manifest record while the files are collected
+        let _state_lock = self.state_lock.lock();
+        // no write while the wal is copied
+        let wal = self.wal.lock();
+        if let Some(wal) = wal.as_ref() {
+            wal.sync()?;
+        }
+
+        let mut memtable_ids = Vec::new();
+        for cf in self.column_families.read().iter() {
+            let snapshot = cf.state.read().clone();
+            for sst_id in Self::sst_ids(&snapshot) {
+                self.link_or_copy(
+                    &self.path_of_sst(sst_id),
+                    &Self::path_of_sst_static(dir, sst_id),
+                )?;
+            }
+            for blob_id in snapshot.blob_files.keys() {
+                self.link_or_copy(
+                    &self.path_of_blob(*blob_id),
+                    &Self::path_of_blob_static(dir, *blob_id),
+                )?;
+            }
+            memtable_ids.push(snapshot.memtable.id());
+            memtable_ids.extend(snapshot.imm_memtable.iter().map(|memtable| memtable.id()));
+        }
+        memtable_ids.sort_unstable();
+        memtable_ids.dedup();
+        for memtable_id in memtable_ids {
+            let wal_path = self.path_of_wal(memtable_id);
+            if self.file_system.exists(&wal_path) {
+                self.copy_and_sync(&wal_path, &Self::path_of_wal_static(dir, memtable_id))?;
+            }
+        }
+        self.copy_and_sync(
+            &Self::path_of_manifest_static(&self.path),
+            &Self::path_of_manifest_static(dir),
+        )?;
+        self.file_system.sync_dir(dir)?;
+        Ok(())
+    }
+
+    fn link_or_copy(&self, from: &Path, to: &Path) -> Result<()> {
+        if self.file_system.hard_link(from, to).is_err() {
+            self.copy_and_sync(from, to)?;
+        }
+        Ok(())
+    }
+
+    fn copy_and_sync(&self, from: &Path, to: &Path) -> Result<()> {
+        self.file_system
+            .copy(from, to)
+            .with_context(|| format!("Failed to copy {} to {}", from.display(), to.display()))?;
+        Ok(())
+    }
+}
+
+/// Checkpoint test
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::{
+        compact::CompactionOptions,
+        lsm_storage::{DEFAULT_COLUMN_FAMILY, LsmStorageOptions, WriteBatchRecord},
+    };
+
+    fn option(enable_wal: bool) -> LsmStorageOptions {
+        LsmStorageOptions {
+            target_sst_size: 1024 * 1024,
+            compaction_options: CompactionOptions::NoCompaction,
+            enable_wal,
+            min_blob_size: Some(16),
+            ..Default::default()
+        }
+    }
+
+    /// Test a checkpoint holds the data written before it and is independent of the storage
+    #[test]
+    fn test_create_checkpoint() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let checkpoint = dir.path().join("checkpoint");
+        let storage = LsmStorageInner::open(dir.path().join("db"), option(true))?;
+        storage.create_column_family("meta", option(true))?;
+        storage.put(b"flushed", &[b'x'; 32])?;
+        storage.force_freeze_memtable(&storage.state_lock.lock())?;
+        storage.force_flush_next_imm_memtable()?;
+        storage.put(b"frozen", b"1")?;
+        storage.force_freeze_memtable(&storage.state_lock.lock())?;
+        storage.write_batch_cf(&[
+            (
+                DEFAULT_COLUMN_FAMILY,
+                WriteBatchRecord::<&[u8]>::Put(b"current", b"2"),
+            ),
+            ("meta", WriteBatchRecord::<&[u8]>::Put(b"current", b"3")),
+        ])?;
+
+        storage.create_checkpoint(&checkpoint)?;
+        assert!(storage.create_checkpoint(&checkpoint).is_err());
+        storage.put(b"after", b"4")?;
+
+        let copy = LsmStorageInner::open(&checkpoint, option(true))?;
+        assert_eq!(copy.get(b"flushed")?, Some(Bytes::from(vec![b'x'; 32])));
+        assert_eq!(copy.get(b"frozen")?, Some(Bytes::from_static(b"1")));
+        assert_eq!(copy.get(b"current")?, Some(Bytes::from_static(b"2")));
+        assert_eq!(
+            copy.get_cf(copy.column_family("meta")?.as_ref(), b"current")?,
+            Some(Bytes::from_static(b"3"))
+        );
+        assert_eq!(copy.get(b"after")?, None);
+
+        // writes to the checkpoint do not reach the storage
+        copy.put(b"copy", b"5")?;
+        copy.flush_all_memtables()?;
+        assert_eq!(storage.get(b"copy")?, None);
+        assert_eq!(storage.get(b"after")?, Some(Bytes::from_static(b"4")));
+        Ok(())
+    }
+
+    /// Test the memtables are flushed to the checkpoint when the wal is disabled
+    #[test]
+    fn test_create_checkpoint_without_wal() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let checkpoint = dir.path().join("checkpoint");
+        let storage = LsmStorageInner::open(dir.path().join("db"), option(false))?;
+        storage.put(b"key", b"value")?;
+        storage.create_checkpoint(&checkpoint)?;
+
+        let copy = LsmStorageInner::open(&checkpoint, option(false))?;
+        assert_eq!(copy.get(b"key")?, Some(Bytes::from_static(b"value")));
+        Ok(())
+    }
+}
@@ -0,0 +1,1112 @@
+use std::{sync::Arc, time::SystemTime};
+
+use anyhow::{Context, Result, anyhow, bail};
+use bytes::Bytes;
+
+use super::{ColumnFamily, CompactionFilter, LsmStorageInner, LsmStorageState};
+use crate::{
+    compact::{CompactionController, CompactionTask, LeveledCompactionTask, TieredCompactionTask},
+    error::Error,
+    iterators::{MergeIterator, StorageIterator},
+    manifest::ManifestRecord,
+    rate_limiter::IoPriority,
+    table::{SsTable, SsTableBuilder, SsTableIterator, TableValue},
+};
+
+/// The key range `[start, end)` of a subcompaction, unbounded on a side that is `None`.
+type KeyRange = (Option<Bytes>, Option<Bytes>);
+
+impl LsmStorageInner {
+    /// Run a compaction for every column family whose controller asks for one.
+    pub(crate) fn trigger_compaction(self: &Arc<Self>) -> Result<()> {
+        let column_families = self.column_families.read().clone();
+        for cf in column_families {
+            let _compaction_lock = self.compaction_lock.lock();
+            let task = Self::tombstone_compaction_task(&cf)
+                .or_else(|| {
+                    cf.compaction_controller
+                        .generate_compaction_task(&cf.state.read())
+                })
+                .or_else(|| Self::periodic_compaction_task(&cf));
+            if let Some(task) = task {
+                self.run_compaction(&cf, &task)?;
+            }
+            if let Some(blob_id) = Self::blob_gc_candidate(&cf) {
+                self.run_blob_gc(&cf, blob_id)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `table` was written more than `periodic_compaction_seconds` ago.
+    fn is_stale(cf: &ColumnFamily, table: &SsTable) -> bool {
+        let period = cf.options.periodic_compaction_seconds;
+        period > 0
+            && SystemTime::now()
+                .duration_since(table.file_creation_time())
+                .is_ok_and(|age| age.as_secs() >= period)
+    }
+
+    /// A task rewriting the stalest SST.
+    fn periodic_compaction_task(cf: &ColumnFamily) -> Option<CompactionTask> {
+        let snapshot = cf.state.read().clone();
+        let (level, sst_id) = Self::ssts_by_level(&snapshot)
+            .filter(|(_, id)| Self::is_stale(cf, &snapshot.sstables[id]))
+            .min_by_key(|(_, id)| snapshot.sstables[id].file_creation_time())?;
+        Self::sst_compaction_task(cf, &snapshot, level, sst_id)
+    }
+
+    /// The share of the entries of `table` that are tombstones.
+    fn tombstone_ratio(table: &SsTable) -> f64 {
+        table.num_tombstones() as f64 / table.num_entries().max(1) as f64
+    }
+
+    /// Whether tombstones are more than `tombstone_compaction_ratio` of the entries of `table`.
+    fn is_tombstone_dense(cf: &ColumnFamily, table: &SsTable) -> bool {
+        cf.options
+            .tombstone_compaction_ratio
+            .is_some_and(|threshold| Self::tombstone_ratio(table) >= threshold)
+    }
+
+    /// A task rewriting the SST with the most tombstones for its size, once they are more than
+    /// `tombstone_compaction_ratio` of its entries.
+    fn tombstone_compaction_task(cf: &ColumnFamily) -> Option<CompactionTask> {
+        let snapshot = cf.state.read().clone();
+        let tombstone_ratio = |id: &usize| Self::tombstone_ratio(&snapshot.sstables[id]);
+        let (level, sst_id) = Self::ssts_by_level(&snapshot)
+            .filter(|(_, id)| Self::is_tombstone_dense(cf, &snapshot.sstables[id]))
+            .max_by(|(_, a), (_, b)| tombstone_ratio(a).total_cmp(&tombstone_ratio(b)))?;
+        Self::sst_compaction_task(cf, &snapshot, level, sst_id)
+    }
+
+    /// Every SST with its level, 0 for L0.
+    fn ssts_by_level(snapshot: &LsmStorageState) -> impl Iterator<Item = (usize, usize)> + '_ {
+        let l0 = snapshot.l0_sstable.iter().map(|id| (0, *id));
+        let levels = (snapshot.levels.iter().enumerate())
+            .flat_map(|(idx, (_, ids))| ids.iter().map(move |id| (idx + 1, *id)));
+        l0.chain(levels)
+    }
+
+    /// A task rewriting `sst_id` of `level`: into the next level, within the last level, or
+    /// with every other run for tiered compaction.
+    fn sst_compaction_task(
+        cf: &ColumnFamily,
+        snapshot: &LsmStorageState,
+        level: usize,
+        sst_id: usize,
+    ) -> Option<CompactionTask> {
+        match cf.compaction_controller {
+            CompactionController::Tiered(_) => Some(CompactionTask::Tiered(TieredCompactionTask {
+                l0_sstables: snapshot.l0_sstable.clone(),
+                tiers: snapshot.levels.clone(),
+                bottom_tier_included: true,
+            })),
+            CompactionController::Leveled(_) | CompactionController::Simple(_) => {
+                // all of L0 goes down together, so no newer data is left above the SST
+                let upper_level_sst_ids = match level {
+                    0 => snapshot.l0_sstable.clone(),
+                    _ => vec![sst_id],
+                };
+                let lower_level = (level + 1).min(snapshot.levels.len());
+                Self::level_task(snapshot, level, upper_level_sst_ids, lower_level)
+            }
+            // FIFO deletes SSTs instead
+            CompactionController::Fifo(_) | CompactionController::NoCompaction => None,
+        }
+    }
+
+    /// A task merging `upper_level_sst_ids` of `upper_level` with the SSTs they overlap in
+    /// `lower_level`, which may be the same level.
+    fn level_task(
+        snapshot: &LsmStorageState,
+        upper_level: usize,
+        upper_level_sst_ids: Vec<usize>,
+        lower_level: usize,
+    ) -> Option<CompactionTask> {
+        let upper_tables = upper_level_sst_ids
+            .iter()
+            .map(|id| snapshot.sstables[id].as_ref())
+            .collect::<Vec<_>>();
+        let first_key = upper_tables.iter().map(|table| table.first_key()).min()?;
+        let last_key = upper_tables.iter().map(|table| table.last_key()).max()?;
+        let overlapping = |ids: &[usize]| {
+            ids.iter()
+                .filter(|id| {
+                    let table = &snapshot.sstables[*id];
+                    table.first_key() <= last_key && first_key <= table.last_key()
+                })
+                .filter(|id| !upper_level_sst_ids.contains(id))
+                .copied()
+                .collect::<Vec<_>>()
+        };
+        // nothing below the output can be hidden by its tombstones
+        let is_lower_level_bottom_level = snapshot.levels[lower_level..]
+            .iter()
+            .all(|(_, ids)| overlapping(ids).is_empty());
+        Some(CompactionTask::Range(LeveledCompactionTask {
+            upper_level: (upper_level > 0).then_some(upper_level),
+            lower_level_sst_ids: overlapping(&snapshot.levels[lower_level - 1].1),
+            upper_level_sst_ids,
+            lower_level,
+            is_lower_level_bottom_level,
+        }))
+    }
+
+    /// Merge the input SSTs of `task` and install the output. The caller holds
+    /// `compaction_lock`, so the inputs are not replaced while they are merged.
+    pub(crate) fn run_compaction(
+        self: &Arc<Self>,
+        cf: &Arc<ColumnFamily>,
+        task: &CompactionTask,
+    ) -> Result<()> {
+        let (output, moved) = match Self::trivial_move(cf, task) {
+            Some(output) => (output, true),
+            None => (self.compact(cf, task)?, false),
+        };
+        let removed = match self.install_compaction(cf, task, &output) {
+            Ok(removed) => removed,
+            Err(e) => {
+                if !moved {
+                    self.remove_ssts(&output);
+                }
+                return Err(e);
+            }
+        };
+        for table in removed {
+            self.remove_sst(&table);
+        }
+        self.notify_write_stall();
+        Ok(())
+    }
+
+    /// Compact every SST overlapping `[start, end]` down to `target_level`, one level at a time.
+    /// All of L0 is compacted when part of it overlaps the range, so no newer data is left above
+    /// the moved data. With tiered compaction everything is merged into a single tier.
+    pub(crate) fn compact_range(
+        self: &Arc<Self>,
+        cf: &Arc<ColumnFamily>,
+        start: &[u8],
+        end: &[u8],
+        target_level: usize,
+    ) -> Result<()> {
+        if start > end {
+            bail!(Error::InvalidArgument(
+                "compaction range start is after its end".to_string()
+            ));
+        }
+        let _compaction_lock = self.compaction_lock.lock();
+        let overlaps = |table: &SsTable| {
+            table.first_key().as_ref() <= end && start <= table.last_key().as_ref()
+        };
+        match cf.compaction_controller {
+            CompactionController::NoCompaction | CompactionController::Fifo(_) => {
+                bail!(Error::InvalidArgument(
+                    "compact_range needs a leveled or tiered compaction".to_string()
+                ))
+            }
+            CompactionController::Tiered(_) => {
+                let snapshot = cf.state.read().clone();
+                let sst_ids = Self::sst_ids(&snapshot);
+                if sst_ids.iter().any(|id| overlaps(&snapshot.sstables[id])) {
+                    let task = CompactionTask::Tiered(TieredCompactionTask {
+                        l0_sstables: snapshot.l0_sstable.clone(),
+                        tiers: snapshot.levels.clone(),
+                        bottom_tier_included: true,
+                    });
+                    self.run_compaction(cf, &task)?;
+                }
+            }
+            CompactionController::Leveled(_) | CompactionController::Simple(_) => {
+                let max_levels = cf.state.read().levels.len();
+                if target_level == 0 || target_level > max_levels {
+                    bail!(Error::InvalidArgument(format!(
+                        "target level {} is not in 1..={}",
+                        target_level, max_levels
+                    )));
+                }
+                for level in 0..target_level {
+                    let snapshot = cf.state.read().clone();
+                    let upper_level_sst_ids = if level == 0 {
+                        if snapshot
+                            .l0_sstable
+                            .iter()
+                            .any(|id| overlaps(&snapshot.sstables[id]))
+                        {
+                            snapshot.l0_sstable.clone()
+                        } else {
+                            Vec::new()
+                        }
+                    } else {
+                        snapshot.levels[level - 1]
+                            .1
+                            .iter()
+                            .filter(|id| overlaps(&snapshot.sstables[*id]))
+                            .copied()
+                            .collect()
+                    };
+                    if let Some(task) =
+                        Self::level_task(&snapshot, level, upper_level_sst_ids, level + 1)
+                    {
+                        self.run_compaction(cf, &task)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The input SSTs of `task` in key order when none of them overlap, so they can be moved to
+    /// the output level without being rewritten. Tasks already hold the SSTs of the output level
+    /// their inputs overlap.
+    fn trivial_move(cf: &ColumnFamily, task: &CompactionTask) -> Option<Vec<Arc<SsTable>>> {
+        match task {
+            // an intra-L0 merge is about the number of SSTs, and a deletion has no input
+            CompactionTask::Fifo(_) => return None,
+            // a compaction within the last level is meant to rewrite it
+            CompactionTask::Range(task) if task.upper_level == Some(task.lower_level) => {
+                return None;
+            }
+            _ => {}
+        }
+        let snapshot = cf.state.read().clone();
+        let mut tables = task
+            .input_sst_ids()
+            .iter()
+            .map(|id| snapshot.sstables.get(id).cloned())
+            .collect::<Option<Vec<_>>>()?;
+        // stale SSTs are rewritten for the compaction filters and tombstone cleanup to reach them,
+        // and tombstone-dense ones for their tombstones to be dropped, or they are picked again
+        if tables
+            .iter()
+            .any(|table| Self::is_stale(cf, table) || Self::is_tombstone_dense(cf, table))
+        {
+            return None;
+        }
+        tables.sort_by(|a, b| a.first_key().cmp(b.first_key()));
+        tables
+            .windows(2)
+            .all(|pair| pair[0].last_key() < pair[1].first_key())
+            .then_some(tables)
+    }
+
+    /// Write the merged input SSTs of `task`. The key range is split at SST boundaries into at
+    /// most `max_subcompactions` ranges merged in parallel on the subcompaction pool, the output
+    /// SSTs are in key order.
+    fn compact(
+        self: &Arc<Self>,
+        cf: &Arc<ColumnFamily>,
+        task: &CompactionTask,
+    ) -> Result<Vec<Arc<SsTable>>> {
+        let snapshot = cf.state.read().clone();
+        let tables = task
+            .input_sst_ids()
+            .iter()
+            .map(|id| {
+                snapshot
+                    .sstables
+                    .get(id)
+                    .cloned()
+                    .with_context(|| format!("sst {} not found", id))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let filters = self.compaction_filters.lock().clone();
+        // an intra-L0 merge writes a single SST, or the number of SSTs it bounds may not go down
+        let max_subcompactions = match task {
+            CompactionTask::Fifo(_) => 1,
+            _ => cf.options.max_subcompactions,
+        };
+        let ranges = Self::subcompaction_ranges(&tables, max_subcompactions);
+        let results = match &self.subcompaction_pool {
+            Some(pool) if ranges.len() > 1 => {
+                let receivers = ranges
+                    .into_iter()
+                    .map(|range| {
+                        let (this, cf, task) = (self.clone(), cf.clone(), task.clone());
+                        let (tables, filters) = (tables.clone(), filters.clone());
+                        pool.spawn(move || {
+                            this.run_subcompaction(&cf, &task, &tables, &filters, &range)
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                receivers
+                    .into_iter()
+                    .map(|receiver| {
+                        receiver
+                            .recv()
+                            .unwrap_or_else(|_| Err(anyhow!("subcompaction pool is gone")))
+                    })
+                    .collect::<Vec<_>>()
+            }
+            // one after another on the compaction thread
+            _ => ranges
+                .iter()
+                .map(|range| self.run_subcompaction(cf, task, &tables, &filters, range))
+                .collect(),
+        };
+
+        let mut output = Vec::new();
+        let mut error = None;
+        for result in results {
+            match result {
+                Ok(tables) => output.extend(tables),
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        if let Some(e) = error {
+            self.remove_ssts(&output);
+            return Err(e);
+        }
+        Ok(output)
+    }
+
+    /// Split the key range of `tables` at the first keys of the SSTs into at most
+    /// `max_subcompactions` ranges with about as many boundaries each.
+    fn subcompaction_ranges(tables: &[Arc<SsTable>], max_subcompactions: usize) -> Vec<KeyRange> {
+        let mut boundaries = tables
+            .iter()
+            .map(|table| table.first_key().clone())
+            .collect::<Vec<_>>();
+        boundaries.sort();
+        boundaries.dedup();
+        // nothing is below the smallest first key
+        if !boundaries.is_empty() {
+            boundaries.remove(0);
+        }
+        let num_ranges = max_subcompactions.clamp(1, boundaries.len() + 1);
+        let mut starts = vec![None];
+        starts.extend(
+            (1..num_ranges).map(|i| Some(boundaries[i * boundaries.len() / num_ranges].clone())),
+        );
+        starts.dedup();
+        let ends = starts.iter().skip(1).cloned().chain(std::iter::once(None));
+        starts.iter().cloned().zip(ends).collect()
+    }
+
+    /// Merge the entries of `tables` in `range`, removing what was written on error.
+    fn run_subcompaction(
+        &self,
+        cf: &ColumnFamily,
+        task: &CompactionTask,
+        tables: &[Arc<SsTable>],
+        filters: &[CompactionFilter],
+        range: &KeyRange,
+    ) -> Result<Vec<Arc<SsTable>>> {
+        let mut output = Vec::new();
+        let result = self.write_subcompaction(cf, task, tables, filters, range, &mut output);
+        if result.is_err() {
+            self.remove_ssts(&output);
+        }
+        result.map(|_| output)
+    }
+
+    fn write_subcompaction(
+        &self,
+        cf: &ColumnFamily,
+        task: &CompactionTask,
+        tables: &[Arc<SsTable>],
+        filters: &[CompactionFilter],
+        (start, end): &KeyRange,
+        output: &mut Vec<Arc<SsTable>>,
+    ) -> Result<()> {
+        let mut iters = Vec::new();
+        for table in tables {
+            if end.as_ref().is_some_and(|end| table.first_key() >= end)
+                || start.as_ref().is_some_and(|start| table.last_key() < start)
+            {
+                continue;
+            }
+            // the inputs are read once, they would only evict the blocks of the readers
+            let iter = match start {
+                Some(start) => SsTableIterator::create_and_seek_to_key_with_fill_cache(
+                    table.clone(),
+                    start,
+                    false,
+                )?,
+                None => {
+                    SsTableIterator::create_and_seek_to_first_with_fill_cache(table.clone(), false)?
+                }
+            };
+            iters.push(Box::new(iter));
+        }
+        let mut iter = MergeIterator::create(iters);
+
+        let bottom_level = task.compact_to_bottom_level();
+        let split_output = !matches!(task, CompactionTask::Fifo(_));
+        let creation_time = tables.iter().map(|table| table.creation_time()).max();
+        let mut builder = None;
+        while iter.is_valid() && end.as_ref().is_none_or(|end| iter.key() < end.as_ref()) {
+            // nothing older is left for a tombstone to hide at the bottom level
+            let dropped = (bottom_level
+                && matches!(TableValue::decode(iter.value())?, TableValue::Delete))
+                || filters.iter().any(|filter| match filter {
+                    CompactionFilter::Prefix(prefix) => iter.key().starts_with(prefix),
+                });
+            if !dropped {
+                let current =
+                    builder.get_or_insert_with(|| self.compaction_builder(cf, task, creation_time));
+                current.add_raw(iter.key(), iter.value());
+                if split_output && current.estimated_size() >= cf.options.target_sst_size {
+                    output.push(self.build_compaction_output(cf, builder.take().unwrap())?);
+                }
+            }
+            iter.next()?;
+        }
+        if let Some(builder) = builder.filter(|builder| !builder.is_empty()) {
+            output.push(self.build_compaction_output(cf, builder)?);
+        }
+        Ok(())
+    }
+
+    fn compaction_builder(
+        &self,
+        cf: &ColumnFamily,
+        task: &CompactionTask,
+        creation_time: Option<SystemTime>,
+    ) -> SsTableBuilder {
+        let compression = &cf.options.compression;
+        let mut builder = SsTableBuilder::new(cf.options.block_size)
+            .with_compression(compression.for_level(task.output_level()))
+            .with_rate_limiter(self.options.rate_limiter.clone(), IoPriority::Low)
+            .with_file_system(self.file_system.clone());
+        // the output is as old as the newest data it holds
+        if let Some(creation_time) = creation_time {
+            builder = builder.with_creation_time(creation_time);
+        }
+        if task.compact_to_bottom_level() {
+            // the bottommost level holds most of the data, and is rewritten the least often
+            builder.with_zstd_dictionary(compression.zstd_max_dict_bytes)
+        } else {
+            builder
+        }
+    }
+
+    fn build_compaction_output(
+        &self,
+        cf: &ColumnFamily,
+        builder: SsTableBuilder,
+    ) -> Result<Arc<SsTable>> {
+        let sst_id = self.next_sst_id();
+        Ok(Arc::new(
+            builder
+                .build(sst_id, self.path_of_sst(sst_id))?
+                .with_verify_checksums(cf.options.verify_checksums)
+                .with_block_cache(self.block_cache.clone())
+                .with_table_cache(self.table_cache.clone()),
+        ))
+    }
+
+    /// Swap the input SSTs of `task` for `output` in a single manifest record, returning the SSTs
+    /// to remove. The blob files no SST points into anymore are removed once nothing reads them.
+    fn install_compaction(
+        &self,
+        cf: &ColumnFamily,
+        task: &CompactionTask,
+        output: &[Arc<SsTable>],
+    ) -> Result<Vec<Arc<SsTable>>> {
+        let state_lock = self.state_lock.lock();
+        let mut snapshot = cf.state.read().as_ref().clone();
+        for sst in output {
+            snapshot.sstables.insert(sst.sst_id(), sst.clone());
+        }
+        let output_ids = output.iter().map(|sst| sst.sst_id()).collect::<Vec<_>>();
+        let (mut snapshot, removed) = cf.compaction_controller.apply_compaction_result(
+            &snapshot,
+            task,
+            &output_ids,
+            false,
+        )?;
+        let removed = removed
+            .iter()
+            .filter_map(|sst_id| snapshot.sstables.remove(sst_id))
+            .collect();
+        let unreferenced_blobs = Self::take_unreferenced_blob_files(&mut snapshot);
+        if let Some(manifest) = &self.manifest {
+            manifest.add_record(
+                &state_lock,
+                ManifestRecord::Compaction {
+                    cf_id: cf.id,
+                    task: task.clone(),
+                    output: output_ids,
+                },
+            )?;
+        }
+        *cf.state.write() = Arc::new(snapshot);
+        for blob in unreferenced_blobs {
+            blob.mark_obsolete(self.file_system.clone());
+        }
+        Ok(removed)
+    }
+
+    fn remove_ssts(&self, tables: &[Arc<SsTable>]) {
+        for table in tables {
+            self.remove_sst(table);
+        }
+    }
+}
+
+/// Compaction test
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::{
+        compact::{
+            CompactionOptions, FifoCompactionOptions, LeveledCompactionOptions,
+            SimpleLeveledCompactionOptions, TieredCompactionOptions,
+        },
+        lsm_storage::{DEFAULT_COLUMN_FAMILY, LsmStorageOptions, LsmStorageState},
+    };
+
+    fn option(
+        compaction_options: CompactionOptions,
+        max_subcompactions: usize,
+    ) -> LsmStorageOptions {
+        LsmStorageOptions {
+            block_size: 64,
+            target_sst_size: 1024,
+            compaction_options,
+            max_subcompactions,
+            ..Default::default()
+        }
+    }
+
+    fn simple_option(max_subcompactions: usize) -> LsmStorageOptions {
+        option(
+            CompactionOptions::Simple(SimpleLeveledCompactionOptions {
+                size_ratio_percent: 200,
+                level0_file_num_compaction_trigger: 2,
+                max_levels: 3,
+            }),
+            max_subcompactions,
+        )
+    }
+
+    /// Write a few overlapping rounds of puts and deletes, flushing each one.
+    fn write_rounds(
+        storage: &LsmStorageInner,
+        rounds: std::ops::Range<usize>,
+        model: &mut BTreeMap<String, Option<String>>,
+    ) -> Result<()> {
+        for round in rounds {
+            for idx in (round * 20)..(round * 20 + 60) {
+                let key = format!("key_{:04}", idx);
+                if idx % 7 == round % 7 {
+                    storage.delete(key.as_bytes())?;
+                    model.insert(key, None);
+                } else {
+                    let value = format!("value_{}_{}", round, idx);
+                    storage.put(key.as_bytes(), value.as_bytes())?;
+                    model.insert(key, Some(value));
+                }
+            }
+            storage.flush_all_memtables()?;
+        }
+        Ok(())
+    }
+
+    fn compact_until_done(storage: &Arc<LsmStorageInner>) -> Result<usize> {
+        let cf = storage.column_family(DEFAULT_COLUMN_FAMILY)?;
+        let mut compactions = 0;
+        loop {
+            let task = cf
+                .compaction_controller
+                .generate_compaction_task(&cf.state.read());
+            let Some(task) = task else {
+                break;
+            };
+            storage.run_compaction(&cf, &task)?;
+            compactions += 1;
+            assert!(compactions < 100, "compaction does not converge");
+        }
+        Ok(compactions)
+    }
+
+    fn check(storage: &LsmStorageInner, model: &BTreeMap<String, Option<String>>) -> Result<()> {
+        for (key, value) in model {
+            let expected = value.as_ref().map(|v| Bytes::copy_from_slice(v.as_bytes()));
+            assert_eq!(storage.get(key.as_bytes())?, expected, "{}", key);
+        }
+        let snapshot = storage.state.read().clone();
+        for (_, ids) in &snapshot.levels {
+            for pair in ids.windows(2) {
+                let (prev, next) = (&snapshot.sstables[&pair[0]], &snapshot.sstables[&pair[1]]);
+                assert!(prev.last_key() < next.first_key());
+            }
+        }
+        for sst_id in LsmStorageInner::sst_ids(&snapshot) {
+            assert!(storage.path_of_sst(sst_id).exists());
+        }
+        Ok(())
+    }
+
+    fn layout(state: &LsmStorageState) -> (Vec<usize>, Vec<(usize, Vec<usize>)>) {
+        (state.l0_sstable.clone(), state.levels.clone())
+    }
+
+    /// The number of entries and tombstones in the SSTs of `snapshot`.
+    fn entries_and_tombstones(snapshot: &LsmStorageState) -> (u64, u64) {
+        LsmStorageInner::sst_ids(snapshot)
+            .iter()
+            .map(|id| &snapshot.sstables[id])
+            .fold((0, 0), |(entries, tombstones), table| {
+                (
+                    entries + table.num_entries(),
+                    tombstones + table.num_tombstones(),
+                )
+            })
+    }
+
+    /// Test every controller compacts the data without losing or resurrecting keys, and the
+    /// result is recovered from the manifest
+    #[test]
+    fn test_compaction() -> Result<()> {
+        let compaction_options = [
+            CompactionOptions::Simple(SimpleLeveledCompactionOptions {
+                size_ratio_percent: 200,
+                level0_file_num_compaction_trigger: 2,
+                max_levels: 3,
+            }),
+            CompactionOptions::Leveled(LeveledCompactionOptions {
+                level_size_multiplier: 2,
+                level0_file_num_compaction_trigger: 2,
+                max_levels: 4,
+                base_level_size_mb: 1,
+            }),
+            CompactionOptions::Tiered(TieredCompactionOptions {
+                num_tiers: 3,
+                max_size_amplification_percent: 200,
+                size_ratio: 1,
+                min_merge_width: 2,
+                max_merge_width: None,
+            }),
+        ];
+        for compaction_options in compaction_options {
+            let dir = tempfile::tempdir()?;
+            let option = option(compaction_options.clone(), 1);
+            let mut model = BTreeMap::new();
+            let layout_before_reopen = {
+                let storage = LsmStorageInner::open(dir.path(), option.clone())?;
+                for rounds in [0..3, 3..5, 5..8] {
+                    write_rounds(&storage, rounds, &mut model)?;
+                    assert!(
+                        compact_until_done(&storage)? > 0,
+                        "{:?}",
+                        compaction_options
+                    );
+                    check(&storage, &model)?;
+                }
+                layout(&storage.state.read())
+            };
+            let storage = LsmStorageInner::open(dir.path(), option)?;
+            assert_eq!(layout(&storage.state.read()), layout_before_reopen);
+            check(&storage, &model)?;
+        }
+        Ok(())
+    }
+
+    /// Test a compaction split into subcompactions writes the same data as a single one
+    #[test]
+    fn test_subcompactions() -> Result<()> {
+        let mut layouts = Vec::new();
+        for max_subcompactions in [1, 4] {
+            let dir = tempfile::tempdir()?;
+            let storage = LsmStorageInner::open(dir.path(), simple_option(max_subcompactions))?;
+            assert_eq!(storage.subcompaction_pool.is_some(), max_subcompactions > 1);
+            let mut model = BTreeMap::new();
+            write_rounds(&storage, 0..2, &mut model)?;
+            let tables = {
+                let snapshot = storage.state.read();
+                snapshot
+                    .l0_sstable
+                    .iter()
+                    .map(|id| snapshot.sstables[id].clone())
+                    .collect::<Vec<_>>()
+            };
+            let ranges = LsmStorageInner::subcompaction_ranges(&tables, max_subcompactions);
+            assert_eq!(ranges.len(), max_subcompactions.min(tables.len()));
+            assert_eq!(ranges[0].0, None);
+            assert_eq!(ranges[ranges.len() - 1].1, None);
+
+            compact_until_done(&storage)?;
+            check(&storage, &model)?;
+            let snapshot = storage.state.read().clone();
+            assert!(snapshot.l0_sstable.is_empty());
+            let mut entries = Vec::new();
+            for sst_id in LsmStorageInner::sst_ids(&snapshot) {
+                let mut iter =
+                    SsTableIterator::create_and_seek_to_first(snapshot.sstables[&sst_id].clone())?;
+                while iter.is_valid() {
+                    entries.push((iter.key().to_vec(), iter.value().to_vec()));
+                    iter.next()?;
+                }
+            }
+            layouts.push(entries);
+        }
+        assert_eq!(layouts[0], layouts[1]);
+        Ok(())
+    }
+
+    /// Test a snapshot taken before a compaction still reads the SSTs it replaced while the table
+    /// cache closes them, and their files are only removed once the snapshot is dropped
+    #[test]
+    fn test_compaction_with_snapshot() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let options = LsmStorageOptions {
+            max_open_files: Some(1),
+            ..simple_option(1)
+        };
+        let storage = LsmStorageInner::open(dir.path(), options)?;
+        let mut model = BTreeMap::new();
+        write_rounds(&storage, 0..3, &mut model)?;
+        let snapshot = storage.state.read().clone();
+        let snapshot_model = model.clone();
+        write_rounds(&storage, 3..6, &mut model)?;
+        compact_until_done(&storage)?;
+        check(&storage, &model)?;
+
+        let live = LsmStorageInner::sst_ids(&storage.state.read());
+        let replaced = LsmStorageInner::sst_ids(&snapshot)
+            .into_iter()
+            .filter(|id| !live.contains(id))
+            .collect::<Vec<_>>();
+        assert!(!replaced.is_empty());
+        for (key, value) in &snapshot_model {
+            let mut found = None;
+            for sst_id in LsmStorageInner::sst_ids(&snapshot) {
+                if let Some(value) = snapshot.sstables[&sst_id].get(key.as_bytes())? {
+                    found = LsmStorageInner::resolve_value(&snapshot, value)?;
+                    break;
+                }
+            }
+            let expected = value.as_ref().map(|v| Bytes::copy_from_slice(v.as_bytes()));
+            assert_eq!(found, expected, "{}", key);
+        }
+        assert!(replaced.iter().all(|id| storage.path_of_sst(*id).exists()));
+
+        drop(snapshot);
+        assert!(replaced.iter().all(|id| !storage.path_of_sst(*id).exists()));
+        Ok(())
+    }
+
+    /// Test a range compaction only rewrites the SSTs overlapping the range
+    #[test]
+    fn test_compact_range() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let storage = LsmStorageInner::open(dir.path(), simple_option(1))?;
+        let cf = storage.column_family(DEFAULT_COLUMN_FAMILY)?;
+        let mut model = BTreeMap::new();
+        write_rounds(&storage, 0..3, &mut model)?;
+        storage.compact_range(&cf, b"key_0000", b"key_9999", 1)?;
+        let before = storage.state.read().levels[0].1.clone();
+        assert!(before.len() > 2);
+
+        let key = storage.state.read().sstables[&before[0]]
+            .first_key()
+            .clone();
+        storage.put(&key, b"new")?;
+        model.insert(String::from_utf8(key.to_vec())?, Some("new".to_string()));
+        storage.flush_all_memtables()?;
+        storage.compact_range(&cf, &key, &key, 1)?;
+        let after = storage.state.read().levels[0].1.clone();
+        assert!(!after.contains(&before[0]));
+        assert!(before[1..].iter().all(|id| after.contains(id)));
+        check(&storage, &model)?;
+
+        let dir = tempfile::tempdir()?;
+        let storage =
+            LsmStorageInner::open(dir.path(), option(CompactionOptions::NoCompaction, 1))?;
+        let cf = storage.column_family(DEFAULT_COLUMN_FAMILY)?;
+        let error = Error::from(storage.compact_range(&cf, b"a", b"z", 1).unwrap_err());
+        assert!(matches!(error, Error::InvalidArgument(_)));
+        Ok(())
+    }
+
+    /// Test FIFO compaction bounds the number of SSTs with intra-L0 merges, and deletes the
+    /// oldest SSTs past the size cap or the ttl
+    #[test]
+    fn test_fifo_compaction() -> Result<()> {
+        let fifo = |max_table_files_size, ttl, intra_l0_compaction_trigger| {
+            option(
+                CompactionOptions::Fifo(FifoCompactionOptions {
+                    max_table_files_size,
+                    ttl,
+                    intra_l0_compaction_trigger,
+                    intra_l0_max_table_size: u64::MAX,
+                }),
+                1,
+            )
+        };
+        let dir = tempfile::tempdir()?;
+        let mut model = BTreeMap::new();
+        let merged = {
+            let storage = LsmStorageInner::open(dir.path(), fifo(u64::MAX, None, Some(3)))?;
+            write_rounds(&storage, 0..5, &mut model)?;
+            compact_until_done(&storage)?;
+            check(&storage, &model)?;
+            let l0_sstable = storage.state.read().l0_sstable.clone();
+            assert_eq!(l0_sstable.len(), 1);
+            l0_sstable[0]
+        };
+
+        let newest_size = {
+            let storage = LsmStorageInner::open(dir.path(), fifo(u64::MAX, None, None))?;
+            write_rounds(&storage, 5..7, &mut model)?;
+            let snapshot = storage.state.read().clone();
+            assert_eq!(snapshot.l0_sstable.last(), Some(&merged));
+            snapshot
+                .l0_sstable
+                .iter()
+                .filter(|id| **id != merged)
+                .map(|id| snapshot.sstables[id].table_size())
+                .sum::<u64>()
+        };
+
+        let storage = LsmStorageInner::open(dir.path(), fifo(newest_size, None, None))?;
+        compact_until_done(&storage)?;
+        let newest = storage.state.read().l0_sstable.clone();
+        assert!(!newest.contains(&merged));
+        assert!(!storage.path_of_sst(merged).exists());
+        // the oldest rounds are gone, the newest ones are left
+        assert_eq!(storage.get(b"key_0001")?, None);
+        let expected = &model["key_0121"];
+        assert_eq!(
+            storage.get(b"key_0121")?,
+            expected
+                .as_ref()
+                .map(|v| Bytes::copy_from_slice(v.as_bytes()))
+        );
+        drop(storage);
+
+        let ttl = std::time::Duration::from_millis(200);
+        let storage = LsmStorageInner::open(dir.path(), fifo(u64::MAX, Some(ttl), None))?;
+        assert_eq!(compact_until_done(&storage)?, 0);
+        std::thread::sleep(ttl);
+        compact_until_done(&storage)?;
+        assert!(storage.state.read().l0_sstable.is_empty());
+        assert!(newest.iter().all(|id| !storage.path_of_sst(*id).exists()));
+        assert_eq!(storage.get(b"key_0121")?, None);
+        Ok(())
+    }
+
+    /// Test FIFO intra-L0 compaction never grows a merged SST past the size bound
+    #[test]
+    fn test_fifo_intra_l0_size_bound() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let max_table_size = 8 << 10;
+        let options = option(
+            CompactionOptions::Fifo(FifoCompactionOptions {
+                max_table_files_size: u64::MAX,
+                ttl: None,
+                intra_l0_compaction_trigger: Some(2),
+                intra_l0_max_table_size: max_table_size,
+            }),
+            1,
+        );
+        let storage = LsmStorageInner::open(dir.path(), options)?;
+        let mut model = BTreeMap::new();
+        let mut compactions = 0;
+        for round in 0..30 {
+            write_rounds(&storage, round..round + 1, &mut model)?;
+            compactions += compact_until_done(&storage)?;
+        }
+        check(&storage, &model)?;
+        let snapshot = storage.state.read().clone();
+        assert!(compactions > 0);
+        assert!(snapshot.l0_sstable.len() > 1);
+        for id in &snapshot.l0_sstable {
+            assert!(snapshot.sstables[id].table_size() < max_table_size);
+        }
+        Ok(())
+    }
+
+    /// Test SSTs that overlap nothing are moved to the output level without being rewritten
+    #[test]
+    fn test_trivial_move() -> Result<()> {
+        let compaction_options = [
+            CompactionOptions::Simple(SimpleLeveledCompactionOptions {
+                size_ratio_percent: 200,
+                level0_file_num_compaction_trigger: 2,
+                max_levels: 3,
+            }),
+            CompactionOptions::Tiered(TieredCompactionOptions {
+                num_tiers: 3,
+                max_size_amplification_percent: 200,
+                size_ratio: 1,
+                min_merge_width: 2,
+                max_merge_width: None,
+            }),
+        ];
+        for compaction_options in compaction_options {
+            let dir = tempfile::tempdir()?;
+            let option = option(compaction_options.clone(), 1);
+            let mut model = BTreeMap::new();
+            let layout_before_reopen = {
+                let storage = LsmStorageInner::open(dir.path(), option.clone())?;
+                // sequential keys, every flush is after the previous one
+                for round in 0..6 {
+                    for idx in (round * 20)..(round * 20 + 20) {
+                        let key = format!("key_{:04}", idx);
+                        storage.put(key.as_bytes(), b"value")?;
+                        model.insert(key, Some("value".to_string()));
+                    }
+                    storage.flush_all_memtables()?;
+                }
+                let mut flushed = LsmStorageInner::sst_ids(&storage.state.read());
+                flushed.sort();
+                assert!(
+                    compact_until_done(&storage)? > 0,
+                    "{:?}",
+                    compaction_options
+                );
+                let snapshot = storage.state.read().clone();
+                assert!(snapshot.l0_sstable.is_empty());
+                let mut moved = LsmStorageInner::sst_ids(&snapshot);
+                moved.sort();
+                assert_eq!(moved, flushed, "{:?}", compaction_options);
+                check(&storage, &model)?;
+                layout(&snapshot)
+            };
+            let storage = LsmStorageInner::open(dir.path(), option)?;
+            assert_eq!(layout(&storage.state.read()), layout_before_reopen);
+            check(&storage, &model)?;
+        }
+        Ok(())
+    }
+
+    /// Test stale SSTs are rewritten while the levels are within their targets, so the
+    /// compaction filters and tombstone cleanup reach them
+    #[test]
+    fn test_periodic_compaction() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let option = LsmStorageOptions {
+            periodic_compaction_seconds: 1,
+            ..option(
+                CompactionOptions::Simple(SimpleLeveledCompactionOptions {
+                    size_ratio_percent: 0,
+                    level0_file_num_compaction_trigger: 100,
+                    max_levels: 2,
+                }),
+                1,
+            )
+        };
+        let storage = LsmStorageInner::open(dir.path(), option)?;
+        let mut model = BTreeMap::new();
+        write_rounds(&storage, 0..2, &mut model)?;
+        storage.trigger_compaction()?;
+        assert_eq!(storage.state.read().l0_sstable.len(), 2);
+
+        storage
+            .compaction_filters
+            .lock()
+            .push(CompactionFilter::Prefix(Bytes::from_static(b"key_001")));
+        model.retain(|key, _| !key.starts_with("key_001"));
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        storage.trigger_compaction()?;
+        let l1 = {
+            let snapshot = storage.state.read();
+            assert!(snapshot.l0_sstable.is_empty());
+            snapshot.levels[0].1.clone()
+        };
+        assert!(!l1.is_empty());
+        assert_eq!(storage.get(b"key_0015")?, None);
+        check(&storage, &model)?;
+
+        // nothing overlaps the L1 SSTs, they are still rewritten into L2
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        for _ in 0..l1.len() {
+            storage.trigger_compaction()?;
+        }
+        let snapshot = storage.state.read().clone();
+        assert!(snapshot.levels[0].1.is_empty());
+        let l2 = snapshot.levels[1].1.clone();
+        assert!(l2.iter().all(|id| !l1.contains(id)));
+        storage.trigger_compaction()?;
+        assert_eq!(storage.state.read().levels[1].1, l2);
+        check(&storage, &model)?;
+        Ok(())
+    }
+
+    /// Test an SST mostly made of tombstones is compacted while the levels are within their
+    /// targets, and rewritten so its tombstones are dropped even when it overlaps nothing
+    #[test]
+    fn test_tombstone_compaction() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let options = LsmStorageOptions {
+            tombstone_compaction_ratio: Some(0.5),
+            ..option(
+                CompactionOptions::Simple(SimpleLeveledCompactionOptions {
+                    size_ratio_percent: 0,
+                    level0_file_num_compaction_trigger: 100,
+                    max_levels: 2,
+                }),
+                1,
+            )
+        };
+        let storage = LsmStorageInner::open(dir.path(), options)?;
+        for idx in 0..50 {
+            storage.put(format!("key_{:04}", idx).as_bytes(), b"value")?;
+        }
+        storage.flush_all_memtables()?;
+        storage.trigger_compaction()?;
+        assert_eq!(storage.state.read().l0_sstable.len(), 1);
+
+        for idx in 0..40 {
+            storage.delete(format!("key_{:04}", idx).as_bytes())?;
+        }
+        storage.flush_all_memtables()?;
+        let dense = storage.state.read().l0_sstable[0];
+        let table = storage.state.read().sstables[&dense].clone();
+        assert_eq!((table.num_entries(), table.num_tombstones()), (40, 40));
+        storage.trigger_compaction()?;
+        let snapshot = storage.state.read().clone();
+        assert!(snapshot.l0_sstable.is_empty());
+        assert_eq!(entries_and_tombstones(&snapshot), (10, 0));
+        assert_eq!(storage.get(b"key_0000")?, None);
+        assert_eq!(
+            storage.get(b"key_0045")?,
+            Some(Bytes::from_static(b"value"))
+        );
+
+        // the tombstones overlap nothing, they are still rewritten instead of moved every time
+        let dir = tempfile::tempdir()?;
+        let options = LsmStorageOptions {
+            tombstone_compaction_ratio: Some(0.5),
+            ..option(
+                CompactionOptions::Tiered(TieredCompactionOptions {
+                    num_tiers: 100,
+                    max_size_amplification_percent: 200,
+                    size_ratio: 1,
+                    min_merge_width: 2,
+                    max_merge_width: None,
+                }),
+                1,
+            )
+        };
+        let storage = LsmStorageInner::open(dir.path(), options)?;
+        for idx in 0..50 {
+            storage.put(format!("key_{:04}", idx).as_bytes(), b"value")?;
+        }
+        storage.flush_all_memtables()?;
+        for idx in 100..140 {
+            storage.delete(format!("key_{:04}", idx).as_bytes())?;
+        }
+        storage.flush_all_memtables()?;
+        assert_eq!(entries_and_tombstones(&storage.state.read()), (90, 40));
+        storage.trigger_compaction()?;
+        assert_eq!(entries_and_tombstones(&storage.state.read()), (50, 0));
+        let manifest = dir.path().join("MANIFEST");
+        let manifest_size = std::fs::metadata(&manifest)?.len();
+        for _ in 0..3 {
+            storage.trigger_compaction()?;
+        }
+        assert_eq!(std::fs::metadata(&manifest)?.len(), manifest_size);
+        assert_eq!(
+            storage.get(b"key_0045")?,
+            Some(Bytes::from_static(b"value"))
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,45 @@
+use std::panic::{AssertUnwindSafe, catch_unwind};
+
+use anyhow::{Result, anyhow};
+use crossbeam_channel::{Receiver, Sender};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed number of threads running the jobs sent to them, shared by every compaction so the
+/// subcompactions running at once never outnumber the threads. The threads exit once the pool is
+/// dropped and the jobs already sent have run.
+pub(crate) struct ThreadPool {
+    sender: Sender<Job>,
+}
+
+impl ThreadPool {
+    pub(crate) fn new(num_threads: usize) -> Self {
+        let (sender, receiver) = crossbeam_channel::unbounded::<Job>();
+        for _ in 0..num_threads.max(1) {
+            let receiver = receiver.clone();
+            std::thread::spawn(move || {
+                for job in receiver {
+                    job();
+                }
+            });
+        }
+        Self { sender }
+    }
+
+    /// Run `job` on a thread of the pool, the result is received once it is done. A panic of
+    /// the job is received as an error, the thread goes on with the next job.
+    pub(crate) fn spawn<T: Send + 'static>(
+        &self,
+        job: impl FnOnce() -> Result<T> + Send + 'static,
+    ) -> Receiver<Result<T>> {
+        let (sender, receiver) = crossbeam_channel::bounded(1);
+        let job = Box::new(move || {
+            let result = catch_unwind(AssertUnwindSafe(job))
+                .unwrap_or_else(|e| Err(anyhow!("job panicked: {:?}", e)));
+            sender.send(result).ok();
+        });
+        // the threads only exit once the sender is dropped
+        self.sender.send(job).ok();
+        receiver
+    }
+}
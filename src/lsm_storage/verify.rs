@@ -0,0 +1,340 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use bytes::Bytes;
+
+use super::{LsmStorageInner, LsmStorageState};
+use crate::{
+    block::BlockIterator,
+    error::Error,
+    table::{SsTable, TableValue},
+    wal::Wal,
+};
+
+/// A problem found by `MiniLsm::verify`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyIssue {
+    /// A file listed in the manifest does not exist.
+    MissingFile { file: PathBuf },
+    /// The data of `file` at `offset` does not match its checksum, or cannot be decoded.
+    Corruption { file: PathBuf, offset: u64 },
+    /// `key` is not greater than the key before it in the SST.
+    OutOfOrder { file: PathBuf, key: Bytes },
+    /// The first and last keys recorded for an SST or one of its blocks do not match its
+    /// contents.
+    KeyRangeMismatch {
+        file: PathBuf,
+        block_idx: Option<usize>,
+        recorded: (Bytes, Bytes),
+        actual: (Bytes, Bytes),
+    },
+    /// Two SSTs of a sorted run overlap.
+    OverlappingTables {
+        column_family: String,
+        level: usize,
+        sst_ids: (usize, usize),
+    },
+    /// The value of `key` points to a missing blob file or past the end of one.
+    InvalidBlobPointer { file: PathBuf, key: Bytes },
+    /// A file could not be read.
+    Io { file: PathBuf, error: String },
+}
+
+/// The result of `MiniLsm::verify`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub sst_files: usize,
+    pub blob_files: usize,
+    pub wal_files: usize,
+    /// Entries read from the SSTs.
+    pub sst_entries: usize,
+    /// Entries read from the wal files.
+    pub wal_entries: usize,
+    pub issues: Vec<VerifyIssue>,
+}
+
+impl VerifyReport {
+    /// Whether no issue was found.
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+impl LsmStorageInner {
+    /// Check every live SST, blob file and wal file of all column families. No lock is held
+    /// while the files are read, the snapshots keep their SSTs and blob files from being removed.
+    pub(crate) fn verify(&self) -> Result<VerifyReport> {
+        let snapshots = self
+            .column_families
+            .read()
+            .iter()
+            .map(|cf| (cf.name.clone(), cf.state.read().clone()))
+            .collect::<Vec<_>>();
+        let mut memtable_ids = Vec::new();
+        for (_, snapshot) in &snapshots {
+            memtable_ids.push(snapshot.memtable.id());
+            memtable_ids.extend(snapshot.imm_memtable.iter().map(|memtable| memtable.id()));
+        }
+        memtable_ids.sort_unstable();
+        memtable_ids.dedup();
+        // the wal files are opened with no write half appended, and only read up to that length,
+        // so that the batches written since are not taken for a torn tail
+        let wal_files = {
+            let wal = self.wal.lock();
+            if let Some(wal) = wal.as_ref() {
+                wal.flush()?;
+            }
+            let mut wal_files = Vec::new();
+            for memtable_id in memtable_ids {
+                let path = self.path_of_wal(memtable_id);
+                // the wal is disabled, or the memtable was flushed and its wal removed
+                let Ok(file) = self.file_system.open(&path) else {
+                    continue;
+                };
+                let len = file.size();
+                wal_files.push((path, file, len));
+            }
+            wal_files
+        };
+
+        let mut report = VerifyReport::default();
+        for (column_family, snapshot) in &snapshots {
+            for sst_id in Self::sst_ids(snapshot) {
+                self.verify_sst(snapshot, &snapshot.sstables[&sst_id], &mut report);
+            }
+            for (level, sst_ids) in &snapshot.levels {
+                for pair in sst_ids.windows(2) {
+                    let (prev, next) = (&snapshot.sstables[&pair[0]], &snapshot.sstables[&pair[1]]);
+                    if prev.last_key() >= next.first_key() {
+                        report.issues.push(VerifyIssue::OverlappingTables {
+                            column_family: column_family.clone(),
+                            level: *level,
+                            sst_ids: (pair[0], pair[1]),
+                        });
+                    }
+                }
+            }
+            for blob_id in snapshot.blob_files.keys() {
+                report.blob_files += 1;
+                let file = self.path_of_blob(*blob_id);
+                if !self.file_system.exists(&file) {
+                    report.issues.push(VerifyIssue::MissingFile { file });
+                }
+            }
+        }
+
+        for (file, reader, len) in wal_files {
+            report.wal_files += 1;
+            let data = len.and_then(|len| {
+                let mut data = vec![0; len as usize];
+                reader.read_at(0, &mut data)?;
+                Ok(data)
+            });
+            match data {
+                Ok(data) => {
+                    let (entries, tail) = Wal::verify(&data);
+                    report.wal_entries += entries;
+                    if let Some(offset) = tail {
+                        report.issues.push(VerifyIssue::Corruption { file, offset });
+                    }
+                }
+                Err(error) => report.issues.push(VerifyIssue::Io {
+                    file,
+                    error: error.to_string(),
+                }),
+            }
+        }
+        Ok(report)
+    }
+
+    fn verify_sst(&self, snapshot: &LsmStorageState, table: &SsTable, report: &mut VerifyReport) {
+        report.sst_files += 1;
+        let file = self.path_of_sst(table.sst_id());
+        if !self.file_system.exists(&file) {
+            report.issues.push(VerifyIssue::MissingFile { file });
+            return;
+        }
+        let reader = match table.reader() {
+            Ok(reader) => reader,
+            Err(error) => {
+                report.issues.push(VerifyIssue::Io {
+                    file,
+                    error: error.to_string(),
+                });
+                return;
+            }
+        };
+        let mut prev_key: Option<Bytes> = None;
+        let mut first_key = None;
+        for block_idx in 0..table.num_of_blocks() {
+            let block = match table.read_block_verified(block_idx) {
+                Ok(block) => block,
+                Err(error) => {
+                    report.issues.push(match Error::from(error) {
+                        Error::Corruption { file, offset } => {
+                            VerifyIssue::Corruption { file, offset }
+                        }
+                        error => VerifyIssue::Io {
+                            file: file.clone(),
+                            error: error.to_string(),
+                        },
+                    });
+                    // the keys of the next block cannot be compared with this one
+                    prev_key = None;
+                    continue;
+                }
+            };
+            let mut iter = BlockIterator::create_and_seek_to_first(block);
+            let block_first_key = Bytes::copy_from_slice(iter.key());
+            while iter.is_valid() {
+                let key = Bytes::copy_from_slice(iter.key());
+                if prev_key.as_ref().is_some_and(|prev| *prev >= key) {
+                    report.issues.push(VerifyIssue::OutOfOrder {
+                        file: file.clone(),
+                        key: key.clone(),
+                    });
+                }
+                match TableValue::decode(iter.value()) {
+                    Ok(TableValue::Blob(pointer)) => {
+                        let valid = snapshot
+                            .blob_files
+                            .get(&pointer.file_id)
+                            .is_some_and(|blob| pointer.offset + pointer.len as u64 <= blob.size());
+                        if !valid {
+                            report.issues.push(VerifyIssue::InvalidBlobPointer {
+                                file: file.clone(),
+                                key: key.clone(),
+                            });
+                        }
+                    }
+                    Ok(TableValue::Inline(_) | TableValue::Delete) => {}
+                    Err(error) => report.issues.push(VerifyIssue::Io {
+                        file: file.clone(),
+                        error: error.to_string(),
+                    }),
+                }
+                report.sst_entries += 1;
+                prev_key = Some(key);
+                iter.next();
+            }
+            let meta = &reader.block_meta[block_idx];
+            let block_last_key = prev_key.clone().unwrap_or_default();
+            if meta.first_key != block_first_key || meta.last_key != block_last_key {
+                report.issues.push(VerifyIssue::KeyRangeMismatch {
+                    file: file.clone(),
+                    block_idx: Some(block_idx),
+                    recorded: (meta.first_key.clone(), meta.last_key.clone()),
+                    actual: (block_first_key.clone(), block_last_key),
+                });
+            }
+            first_key.get_or_insert(block_first_key);
+        }
+        if let (Some(first_key), Some(last_key)) = (first_key, prev_key)
+            && (*table.first_key() != first_key || *table.last_key() != last_key)
+        {
+            report.issues.push(VerifyIssue::KeyRangeMismatch {
+                file,
+                block_idx: None,
+                recorded: (table.first_key().clone(), table.last_key().clone()),
+                actual: (first_key, last_key),
+            });
+        }
+    }
+}
+
+/// Verify test
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, sync::Arc};
+
+    use super::*;
+    use crate::lsm_storage::LsmStorageOptions;
+
+    fn option() -> LsmStorageOptions {
+        LsmStorageOptions {
+            block_size: 128,
+            target_sst_size: 1024 * 1024,
+            min_blob_size: Some(32),
+            ..Default::default()
+        }
+    }
+
+    fn open_with_data(path: &std::path::Path) -> Result<Arc<LsmStorageInner>> {
+        let storage = LsmStorageInner::open(path, option())?;
+        for idx in 0..50 {
+            let value = format!("value_{:040}", idx);
+            storage.put(format!("key_{:03}", idx).as_bytes(), value.as_bytes())?;
+        }
+        storage.force_freeze_memtable(&storage.state_lock.lock())?;
+        storage.force_flush_next_imm_memtable()?;
+        storage.put(b"key_100", b"value")?;
+        storage.wal.lock().as_ref().unwrap().sync()?;
+        Ok(storage)
+    }
+
+    /// Test a healthy storage passes verification
+    #[test]
+    fn test_verify() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let storage = open_with_data(dir.path())?;
+        let report = storage.verify()?;
+        assert!(report.is_ok(), "{:?}", report.issues);
+        assert_eq!(report.sst_files, 1);
+        assert_eq!(report.blob_files, 1);
+        assert_eq!(report.wal_files, 1);
+        assert_eq!(report.sst_entries, 50);
+        assert_eq!(report.wal_entries, 1);
+        Ok(())
+    }
+
+    /// Test verification holds no state lock, so flushes and compactions go on meanwhile
+    #[test]
+    fn test_verify_without_state_lock() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let storage = open_with_data(dir.path())?;
+        let state_lock = storage.state_lock.lock();
+        let report = storage.verify()?;
+        drop(state_lock);
+        assert!(report.is_ok(), "{:?}", report.issues);
+        assert_eq!(report.sst_entries, 50);
+        Ok(())
+    }
+
+    /// Test corrupted SST blocks and wal tails are reported
+    #[test]
+    fn test_verify_corruption() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let storage = open_with_data(dir.path())?;
+        let (sst_id, block_offset) = {
+            let snapshot = storage.state.read();
+            let sst = &snapshot.sstables[&snapshot.l0_sstable[0]];
+            (sst.sst_id(), sst.reader()?.block_meta[1].offset)
+        };
+        let sst_path = storage.path_of_sst(sst_id);
+        let mut data = std::fs::read(&sst_path)?;
+        data[block_offset + 4] ^= 0xff;
+        std::fs::write(&sst_path, data)?;
+        let wal_path = storage.path_of_wal(storage.state.read().memtable.id());
+        let wal_len = std::fs::metadata(&wal_path)?.len();
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&wal_path)?
+            .write_all(&[0, 0, 1])?;
+
+        let report = storage.verify()?;
+        assert_eq!(
+            report.issues,
+            vec![
+                VerifyIssue::Corruption {
+                    file: sst_path,
+                    offset: block_offset as u64
+                },
+                VerifyIssue::Corruption {
+                    file: wal_path,
+                    offset: wal_len
+                },
+            ]
+        );
+        Ok(())
+    }
+}
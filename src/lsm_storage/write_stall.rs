@@ -0,0 +1,253 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, bail};
+
+use super::{ColumnFamily, LsmStorageInner};
+use crate::{
+    compact::{CompactionOptions, FifoCompactionOptions},
+    error::Error,
+};
+
+/// When writers are slowed down or stopped because flush or compaction falls behind.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WriteStallOptions {
+    /// Delay writes once a column family has this many immutable memtables.
+    pub imm_memtable_slowdown_trigger: usize,
+    /// Block writes once a column family has this many immutable memtables. Both memtable
+    /// triggers should be above `num_memttable_limit`, which is when memtables are flushed.
+    pub imm_memtable_stop_trigger: usize,
+    /// Delay writes once a column family has this many L0 SSTs.
+    pub l0_slowdown_trigger: usize,
+    /// Block writes once a column family has this many L0 SSTs. The L0 triggers only apply when
+    /// a compaction is configured.
+    pub l0_stop_trigger: usize,
+    /// How long a write is delayed when a slowdown trigger is reached.
+    pub slowdown_delay: Duration,
+    /// How long a blocked write waits before failing with `Error::Busy`.
+    pub stop_timeout: Duration,
+}
+
+impl Default for WriteStallOptions {
+    fn default() -> Self {
+        Self {
+            imm_memtable_slowdown_trigger: 16,
+            imm_memtable_stop_trigger: 32,
+            l0_slowdown_trigger: 20,
+            l0_stop_trigger: 36,
+            slowdown_delay: Duration::from_millis(1),
+            stop_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Write stalls since the storage was opened.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WriteStallStats {
+    /// Writes delayed by a slowdown trigger.
+    pub slowdowns: u64,
+    /// Time spent in those delays.
+    pub slowdown_duration: Duration,
+    /// Writes blocked by a stop trigger.
+    pub stops: u64,
+    /// Time spent blocked, including the writes that timed out.
+    pub stop_duration: Duration,
+    /// Blocked writes that failed with `Error::Busy`.
+    pub timeouts: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct WriteStallCounters {
+    slowdowns: AtomicU64,
+    slowdown_micros: AtomicU64,
+    stops: AtomicU64,
+    stop_micros: AtomicU64,
+    timeouts: AtomicU64,
+}
+
+impl WriteStallCounters {
+    pub(crate) fn stats(&self) -> WriteStallStats {
+        WriteStallStats {
+            slowdowns: self.slowdowns.load(Ordering::Relaxed),
+            slowdown_duration: Duration::from_micros(self.slowdown_micros.load(Ordering::Relaxed)),
+            stops: self.stops.load(Ordering::Relaxed),
+            stop_duration: Duration::from_micros(self.stop_micros.load(Ordering::Relaxed)),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// How far a column family is behind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum StallCondition {
+    Normal,
+    Slowdown,
+    Stop,
+}
+
+impl LsmStorageInner {
+    fn stall_condition(cf: &ColumnFamily) -> StallCondition {
+        let options = &cf.options.write_stall;
+        let (imm_memtables, l0_sstables) = {
+            let guard = cf.state.read();
+            (guard.imm_memtable.len(), guard.l0_sstable.len())
+        };
+        // nothing bounds the number of L0 SSTs without compaction or intra-L0 merges
+        let l0_sstables = match cf.options.compaction_options {
+            CompactionOptions::NoCompaction
+            | CompactionOptions::Fifo(FifoCompactionOptions {
+                intra_l0_compaction_trigger: None,
+                ..
+            }) => 0,
+            _ => l0_sstables,
+        };
+        if imm_memtables >= options.imm_memtable_stop_trigger
+            || l0_sstables >= options.l0_stop_trigger
+        {
+            StallCondition::Stop
+        } else if imm_memtables >= options.imm_memtable_slowdown_trigger
+            || l0_sstables >= options.l0_slowdown_trigger
+        {
+            StallCondition::Slowdown
+        } else {
+            StallCondition::Normal
+        }
+    }
+
+    /// Delay or block a write to `column_families` until flush and compaction catch up.
+    pub(crate) fn maybe_stall_write(&self, column_families: &[&ColumnFamily]) -> Result<()> {
+        let worst = || {
+            column_families
+                .iter()
+                .map(|cf| (Self::stall_condition(cf), *cf))
+                .max_by_key(|(condition, _)| *condition)
+        };
+        let Some((condition, cf)) = worst() else {
+            return Ok(());
+        };
+        match condition {
+            StallCondition::Normal => Ok(()),
+            StallCondition::Slowdown => {
+                let delay = cf.options.write_stall.slowdown_delay;
+                std::thread::sleep(delay);
+                let counters = &self.write_stall;
+                counters.slowdowns.fetch_add(1, Ordering::Relaxed);
+                counters
+                    .slowdown_micros
+                    .fetch_add(delay.as_micros() as u64, Ordering::Relaxed);
+                Ok(())
+            }
+            StallCondition::Stop => {
+                let start = Instant::now();
+                let deadline = start + cf.options.write_stall.stop_timeout;
+                let stopped = || worst().is_some_and(|(c, _)| c == StallCondition::Stop);
+                let mut guard = self.stall_mutex.lock();
+                // flush and compaction notify under the mutex once they change the state, so
+                // no wakeup is lost between the check and the wait
+                while stopped() {
+                    if self.stall_cvar.wait_until(&mut guard, deadline).timed_out() {
+                        break;
+                    }
+                }
+                drop(guard);
+                let still_stopped = stopped();
+                let counters = &self.write_stall;
+                counters.stops.fetch_add(1, Ordering::Relaxed);
+                counters
+                    .stop_micros
+                    .fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+                if still_stopped {
+                    counters.timeouts.fetch_add(1, Ordering::Relaxed);
+                    bail!(Error::Busy(format!(
+                        "writes to column family {} are stopped until flush or compaction catches up",
+                        cf.name
+                    )));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Wake up the writers blocked by a stop trigger, called after memtables or L0 SSTs are
+    /// removed.
+    pub(crate) fn notify_write_stall(&self) {
+        let _guard = self.stall_mutex.lock();
+        self.stall_cvar.notify_all();
+    }
+}
+
+/// Write stall test
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsm_storage::LsmStorageOptions;
+
+    fn option() -> LsmStorageOptions {
+        LsmStorageOptions {
+            target_sst_size: 1024 * 1024,
+            num_memttable_limit: 1,
+            enable_wal: false,
+            write_stall: WriteStallOptions {
+                imm_memtable_slowdown_trigger: 1,
+                imm_memtable_stop_trigger: 2,
+                slowdown_delay: Duration::from_millis(1),
+                stop_timeout: Duration::from_millis(50),
+                ..WriteStallOptions::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn freeze(storage: &LsmStorageInner) -> Result<()> {
+        storage.put(b"key", b"value")?;
+        storage.force_freeze_memtable(&storage.state_lock.lock())
+    }
+
+    /// Test writes are delayed at the slowdown trigger and fail at the stop trigger
+    #[test]
+    fn test_write_stall_slowdown_and_timeout() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let storage = LsmStorageInner::open(dir.path(), option())?;
+        storage.put(b"key", b"value")?;
+        assert_eq!(storage.write_stall.stats(), WriteStallStats::default());
+
+        freeze(&storage)?;
+        freeze(&storage)?;
+        let error = Error::from(storage.put(b"key", b"value").unwrap_err());
+        assert!(matches!(error, Error::Busy(_)), "{:?}", error);
+        let stats = storage.write_stall.stats();
+        assert_eq!((stats.slowdowns, stats.stops, stats.timeouts), (1, 1, 1));
+        assert!(stats.stop_duration >= Duration::from_millis(50));
+
+        storage.force_flush_next_imm_memtable()?;
+        storage.put(b"key", b"value")?;
+        let stats = storage.write_stall.stats();
+        assert_eq!((stats.slowdowns, stats.stops, stats.timeouts), (2, 1, 1));
+        assert!(stats.slowdown_duration >= Duration::from_millis(2));
+        Ok(())
+    }
+
+    /// Test a blocked write resumes once a flush catches up
+    #[test]
+    fn test_write_stall_unblocked_by_flush() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut option = option();
+        option.write_stall.stop_timeout = Duration::from_secs(10);
+        let storage = LsmStorageInner::open(dir.path(), option)?;
+        freeze(&storage)?;
+        freeze(&storage)?;
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                std::thread::sleep(Duration::from_millis(50));
+                storage.force_flush_next_imm_memtable().unwrap();
+            });
+            storage.put(b"key", b"value")
+        })?;
+        let stats = storage.write_stall.stats();
+        assert_eq!((stats.stops, stats.timeouts), (1, 0));
+        assert!(stats.stop_duration >= Duration::from_millis(50));
+        Ok(())
+    }
+}
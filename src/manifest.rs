@@ -0,0 +1,171 @@
+use std::{path::Path, sync::Arc};
+
+use anyhow::{Context, Result, bail};
+use bytes::{Buf, BufMut};
+use parking_lot::{Mutex, MutexGuard};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    compact::CompactionTask,
+    fs::{FileSystem, WritableFile},
+};
+
+/// The manifest records every change to the structure of the LSM tree, so that the state can be
+/// rebuilt on open. It is shared by all column families.
+///
+/// Each record is `| len (u32) | json |`.
+pub struct Manifest {
+    /// `None` once a write failed: the file may end with a torn record, the records added after
+    /// it would not be recovered.
+    file: Arc<Mutex<Option<Box<dyn WritableFile>>>>,
+}
+
+/// An SST produced by a flush.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FlushedTable {
+    /// The column family the SST belongs to.
+    pub cf_id: usize,
+    pub sst_id: usize,
+    /// The blob file written along with the SST, if any.
+    pub blob_id: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ManifestRecord {
+    /// A new memtable generation was created, every column family gets a memtable with this id
+    /// and they share the wal of the same id.
+    NewMemtable(usize),
+    /// A memtable generation was flushed, with the SSTs produced for each column family.
+    Flush(usize, Vec<FlushedTable>),
+    /// SSTs were ingested into a column family, as `(level, sst_id)` with level 0 for L0.
+    Ingest(usize, Vec<(usize, usize)>),
+    /// A column family was created with the given id and name.
+    CreateColumnFamily(usize, String),
+    /// A blob file was garbage collected: the SSTs referencing it were replaced by rewritten ones
+    /// (`None` when nothing was left in the SST), and the live values moved to a new blob file.
+    BlobGc {
+        cf_id: usize,
+        blob_id: usize,
+        new_blob_id: Option<usize>,
+        replaced: Vec<(usize, Option<usize>)>,
+    },
+    /// A compaction of a column family replaced the input SSTs of `task` with `output`.
+    Compaction {
+        cf_id: usize,
+        task: CompactionTask,
+        output: Vec<usize>,
+    },
+}
+
+impl Manifest {
+    /// Create a new manifest file.
+    pub fn create(fs: &dyn FileSystem, path: impl AsRef<Path>) -> Result<Self> {
+        let file = fs
+            .create(path.as_ref())
+            .context("Failed to create manifest")?;
+        fs.sync_parent_dir(path.as_ref())?;
+        Ok(Self {
+            file: Arc::new(Mutex::new(Some(file))),
+        })
+    }
+
+    /// Recover the manifest and return all complete records in it.
+    pub fn recover(
+        fs: &dyn FileSystem,
+        path: impl AsRef<Path>,
+    ) -> Result<(Self, Vec<ManifestRecord>)> {
+        let path = path.as_ref();
+        let data = fs.read(path).context("Failed to recover manifest")?;
+        let mut buf = &data[..];
+        let mut records = Vec::new();
+        while buf.remaining() >= 4 {
+            let len = (&buf[..4]).get_u32() as usize;
+            if buf.remaining() < 4 + len {
+                break;
+            }
+            records.push(serde_json::from_slice(&buf[4..4 + len])?);
+            buf.advance(4 + len);
+        }
+        // drop a torn write of the last record, so new records are appended after a complete one
+        fs.truncate(path, (data.len() - buf.remaining()) as u64)?;
+        let file = fs.open_append(path)?;
+        Ok((
+            Self {
+                file: Arc::new(Mutex::new(Some(file))),
+            },
+            records,
+        ))
+    }
+
+    /// Add a record, the caller must hold the state lock.
+    pub fn add_record(
+        &self,
+        _state_lock_observer: &MutexGuard<()>,
+        record: ManifestRecord,
+    ) -> Result<()> {
+        self.add_record_when_init(record)
+    }
+
+    /// Add a record while opening the storage, when no other thread can change the state.
+    pub fn add_record_when_init(&self, record: ManifestRecord) -> Result<()> {
+        let json = serde_json::to_vec(&record)?;
+        let mut buf = Vec::with_capacity(json.len() + 4);
+        buf.put_u32(json.len() as u32);
+        buf.put_slice(&json);
+        let mut file = self.file.lock();
+        let Some(writer) = file.as_mut() else {
+            bail!("manifest is unusable after a failed write");
+        };
+        if let Err(e) = writer.append(&buf).and_then(|()| writer.sync()) {
+            *file = None;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+}
+
+/// Manifest test
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::InMemoryFileSystem;
+
+    /// Test manifest records survive recovery
+    #[test]
+    fn test_manifest_recover() -> Result<()> {
+        let fs = InMemoryFileSystem::new();
+        let path = Path::new("MANIFEST");
+        let records = vec![
+            ManifestRecord::NewMemtable(0),
+            ManifestRecord::CreateColumnFamily(1, "meta".to_string()),
+            ManifestRecord::Flush(
+                0,
+                vec![FlushedTable {
+                    cf_id: 1,
+                    sst_id: 2,
+                    blob_id: None,
+                }],
+            ),
+        ];
+        {
+            let manifest = Manifest::create(&fs, path)?;
+            for record in &records {
+                manifest.add_record_when_init(record.clone())?;
+            }
+        }
+        let (manifest, recovered) = Manifest::recover(&fs, path)?;
+        assert_eq!(recovered, records);
+
+        // a torn record at the end is ignored, and new records are appended after it
+        manifest.add_record_when_init(ManifestRecord::NewMemtable(3))?;
+        let len = fs.open(path)?.size()?;
+        fs.truncate(path, len - 1)?;
+        let (manifest, recovered) = Manifest::recover(&fs, path)?;
+        assert_eq!(recovered, records);
+        manifest.add_record_when_init(ManifestRecord::NewMemtable(4))?;
+        let (_, recovered) = Manifest::recover(&fs, path)?;
+        assert_eq!(recovered.len(), records.len() + 1);
+        assert_eq!(recovered.last(), Some(&ManifestRecord::NewMemtable(4)));
+        Ok(())
+    }
+}
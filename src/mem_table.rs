@@ -1,5 +1,6 @@
 /// MemTable
 use std::{
+    ops::Bound,
     path::Path,
     sync::{Arc, atomic::AtomicUsize},
 };
@@ -8,11 +9,11 @@ use anyhow::Result;
 use bytes::Bytes;
 use crossbeam_skiplist::SkipMap;
 
-use crate::wal::Wal;
+use crate::{blob::BlobFileBuilder, fs::FileSystem, table::SsTableBuilder, value::Value, wal::Wal};
 
 /// A baic mem-table based on crossbeam-skiplist
 pub struct MemTable {
-    map: Arc<SkipMap<Bytes, Bytes>>,
+    map: Arc<SkipMap<Bytes, Value>>,
     wal: Option<Wal>,
     id: usize,
     approximate_size: Arc<AtomicUsize>,
@@ -30,10 +31,10 @@ impl MemTable {
     }
 
     /// Create a new mem-table with wal.
-    pub fn create_with_wal(id: usize, path: impl AsRef<Path>) -> Result<Self> {
+    pub fn create_with_wal(fs: &dyn FileSystem, id: usize, path: impl AsRef<Path>) -> Result<Self> {
         let mem_table = Self {
             map: Arc::new(SkipMap::new()),
-            wal: Some(Wal::create(path.as_ref())?),
+            wal: Some(Wal::create(fs, path.as_ref())?),
             id,
             approximate_size: Arc::new(AtomicUsize::new(0)),
         };
@@ -41,11 +42,15 @@ impl MemTable {
     }
 
     /// Recover a mem-table from wal.
-    pub fn recover_with_wal(id: usize, path: impl AsRef<Path>) -> Result<Self> {
+    pub fn recover_with_wal(
+        fs: &dyn FileSystem,
+        id: usize,
+        path: impl AsRef<Path>,
+    ) -> Result<Self> {
         let map = Arc::new(SkipMap::new());
 
         let mem_table = Self {
-            wal: Some(Wal::recover(path.as_ref(), &map)?),
+            wal: Some(Wal::recover(fs, path.as_ref(), &map)?),
             map,
             id,
             approximate_size: Arc::new(AtomicUsize::new(0)),
@@ -54,24 +59,71 @@ impl MemTable {
         Ok(mem_table)
     }
 
-    /// Get a value by key.
-    pub fn get(&self, key: &[u8]) -> Option<Bytes> {
+    /// Get a value by key, a delete is returned as `Value::Delete`.
+    pub fn get(&self, key: &[u8]) -> Option<Value> {
         self.map.get(key).map(|v| v.value().clone())
     }
 
     /// Put a key-value pair.
     pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
-        let estimated_size = key.len() + value.len();
-        self.map
-            .insert(Bytes::copy_from_slice(key), Bytes::copy_from_slice(value));
+        self.put_value(key, Value::Put(Bytes::copy_from_slice(value)))
+    }
+
+    /// Delete a key.
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        self.put_value(key, Value::Delete)
+    }
+
+    /// Write a typed value.
+    pub fn put_value(&self, key: &[u8], value: Value) -> Result<()> {
+        let estimated_size = key.len() + value.data().len();
+        if let Some(ref wal) = self.wal {
+            wal.put_value(key, &value)?;
+        }
+        self.map.insert(Bytes::copy_from_slice(key), value);
         self.approximate_size
             .fetch_add(estimated_size, std::sync::atomic::Ordering::Relaxed);
-        if let Some(ref wal) = self.wal {
-            wal.put(key, value)?;
+        Ok(())
+    }
+
+    /// Flush the mem-table to an SST.
+    ///
+    /// When a blob file builder is given, values of at least its `min_blob_size` are written to
+    /// the blob file and the SST only stores a pointer to them.
+    pub fn flush(
+        &self,
+        builder: &mut SsTableBuilder,
+        mut blob_builder: Option<&mut BlobFileBuilder>,
+    ) -> Result<()> {
+        for entry in self.map.iter() {
+            let Value::Put(value) = entry.value() else {
+                builder.add_delete(entry.key());
+                continue;
+            };
+            match blob_builder.as_deref_mut() {
+                Some(blob) if blob.should_separate(value) => {
+                    let pointer = blob.add(value)?;
+                    builder.add_blob(entry.key(), pointer);
+                }
+                _ => builder.add(entry.key(), value),
+            }
         }
         Ok(())
     }
 
+    /// Whether the mem-table has a key in `[first_key, last_key]`.
+    pub fn overlaps(&self, first_key: &[u8], last_key: &[u8]) -> bool {
+        self.map
+            .range::<[u8], _>((Bound::Included(first_key), Bound::Included(last_key)))
+            .next()
+            .is_some()
+    }
+
+    /// Whether the mem-table has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
     /// Get the approximate size of the mem-table.
     ///
     /// # Returns usize
@@ -95,6 +147,7 @@ mod tests {
     use anyhow::Ok;
 
     use super::*;
+    use crate::fs::InMemoryFileSystem;
 
     #[test]
     fn test_mem_table_create() {
@@ -111,8 +164,8 @@ mod tests {
     /// Test mem_table by wal create
     #[test]
     fn test_mem_table_create_with_wal() -> Result<()> {
-        let path = "test_wal_create.wal";
-        let mem_table = MemTable::create_with_wal(0, path)?;
+        let fs = InMemoryFileSystem::new();
+        let mem_table = MemTable::create_with_wal(&fs, 0, "test_wal_create.wal")?;
         assert_eq!(mem_table.id, 0);
         assert_eq!(
             mem_table
@@ -120,7 +173,6 @@ mod tests {
                 .load(std::sync::atomic::Ordering::Relaxed),
             0
         );
-        std::fs::remove_file(path)?;
         Ok(())
     }
 
@@ -128,10 +180,9 @@ mod tests {
     ///
     #[test]
     fn test_mem_table_recover_with_wal() -> Result<()> {
-        let path = "test_wal_recover.wal";
-        let mut mem_table = MemTable::create_with_wal(0, path)?;
+        let fs = InMemoryFileSystem::new();
+        let mem_table = MemTable::create_with_wal(&fs, 0, "test_wal_recover.wal")?;
         mem_table.put(b"key", b"value")?;
-        std::fs::remove_file(path)?;
         assert_eq!(mem_table.id, 0);
         assert_eq!(
             mem_table
@@ -139,24 +190,46 @@ mod tests {
                 .load(std::sync::atomic::Ordering::Relaxed),
             8
         );
+        let recovered = MemTable::recover_with_wal(&fs, 0, "test_wal_recover.wal")?;
+        assert_eq!(
+            recovered.get(b"key"),
+            Some(Value::Put(Bytes::from_static(b"value")))
+        );
         Ok(())
     }
 
     /// Test mem_table put
     #[test]
     fn test_mem_table_put() {
-        let mut mem_table = MemTable::create(0);
+        let mem_table = MemTable::create(0);
         mem_table.put(b"key", b"value").unwrap();
-        assert_eq!(mem_table.get(b"key"), Some(Bytes::from_static(b"value")));
+        assert_eq!(
+            mem_table.get(b"key"),
+            Some(Value::Put(Bytes::from_static(b"value")))
+        );
     }
 
     /// Test mem_table get
     ///
     #[test]
     fn test_mem_table_get() {
-        let mut mem_table = MemTable::create(0);
+        let mem_table = MemTable::create(0);
         mem_table.put(b"key", b"value").unwrap();
-        assert_eq!(mem_table.get(b"key"), Some(Bytes::from_static(b"value")));
+        assert_eq!(
+            mem_table.get(b"key"),
+            Some(Value::Put(Bytes::from_static(b"value")))
+        );
+    }
+
+    /// Test an empty value is kept apart from a delete
+    ///
+    #[test]
+    fn test_mem_table_empty_value_and_delete() {
+        let mem_table = MemTable::create(0);
+        mem_table.put(b"empty", b"").unwrap();
+        mem_table.delete(b"deleted").unwrap();
+        assert_eq!(mem_table.get(b"empty"), Some(Value::Put(Bytes::new())));
+        assert_eq!(mem_table.get(b"deleted"), Some(Value::Delete));
     }
 
     /// Test mem_table get None
@@ -166,4 +239,17 @@ mod tests {
         let mem_table = MemTable::create(0);
         assert_eq!(mem_table.get(b"key"), None);
     }
+
+    /// Test mem_table overlaps
+    ///
+    #[test]
+    fn test_mem_table_overlaps() {
+        let mem_table = MemTable::create(0);
+        mem_table.put(b"b", b"value").unwrap();
+        mem_table.put(b"d", b"value").unwrap();
+        assert!(mem_table.overlaps(b"a", b"b"));
+        assert!(mem_table.overlaps(b"c", b"e"));
+        assert!(!mem_table.overlaps(b"ba", b"c"));
+        assert!(!mem_table.overlaps(b"e", b"f"));
+    }
 }
@@ -0,0 +1,168 @@
+//! Token-bucket rate limiter for background I/O.
+//!
+//! Flush and compaction request tokens for the bytes they write, one request blocking until the
+//! bucket has refilled enough. Wal writes never go through the limiter, so foreground latency
+//! does not depend on how busy the background threads are.
+
+use std::time::{Duration, Instant};
+
+use parking_lot::{Condvar, Mutex};
+
+/// Writes are requested in chunks of at most this fraction of a second of the rate, which is
+/// also the most the bucket can hold.
+const REFILL_PERIODS_PER_SECOND: u64 = 10;
+
+/// Priority of a rate limited write. Pending high priority requests are served before low
+/// priority ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoPriority {
+    /// Flushes, which free memtables that writers may be stalled on.
+    High,
+    /// Compaction and blob garbage collection.
+    Low,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    bytes_per_second: u64,
+    available: u64,
+    last_refill: Instant,
+    /// High priority requests waiting for tokens.
+    high_waiters: usize,
+    /// Bytes granted to each priority, high first.
+    total_bytes: [u64; 2],
+}
+
+impl Bucket {
+    fn capacity(&self) -> u64 {
+        (self.bytes_per_second / REFILL_PERIODS_PER_SECOND).max(1)
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill);
+        let tokens = (elapsed.as_secs_f64() * self.bytes_per_second as f64) as u64;
+        if tokens > 0 {
+            self.available = (self.available + tokens).min(self.capacity());
+            self.last_refill = now;
+        }
+    }
+}
+
+/// Limits the bytes per second written by flush and compaction. It is shared by putting the
+/// same `Arc` in the options of the storage, and can be adjusted while the storage is running.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+    refilled: Condvar,
+}
+
+impl RateLimiter {
+    /// Create a limiter allowing `bytes_per_second`, 0 does not limit anything.
+    pub fn new(bytes_per_second: u64) -> Self {
+        let mut bucket = Bucket {
+            bytes_per_second,
+            available: 0,
+            last_refill: Instant::now(),
+            high_waiters: 0,
+            total_bytes: [0; 2],
+        };
+        bucket.available = bucket.capacity();
+        Self {
+            bucket: Mutex::new(bucket),
+            refilled: Condvar::new(),
+        }
+    }
+
+    /// The current rate, 0 when nothing is limited.
+    pub fn bytes_per_second(&self) -> u64 {
+        self.bucket.lock().bytes_per_second
+    }
+
+    /// Change the rate, waiting requests continue at the new rate.
+    pub fn set_bytes_per_second(&self, bytes_per_second: u64) {
+        let mut bucket = self.bucket.lock();
+        bucket.refill(Instant::now());
+        bucket.bytes_per_second = bytes_per_second;
+        bucket.available = bucket.available.min(bucket.capacity());
+        self.refilled.notify_all();
+    }
+
+    /// Total bytes granted to requests of `priority`.
+    pub fn total_bytes(&self, priority: IoPriority) -> u64 {
+        self.bucket.lock().total_bytes[priority as usize]
+    }
+
+    /// Block until `bytes` may be written.
+    pub fn request(&self, bytes: usize, priority: IoPriority) {
+        let mut remaining = bytes as u64;
+        let mut bucket = self.bucket.lock();
+        if priority == IoPriority::High {
+            bucket.high_waiters += 1;
+        }
+        while remaining > 0 {
+            if bucket.bytes_per_second == 0 {
+                bucket.total_bytes[priority as usize] += remaining;
+                break;
+            }
+            let chunk = remaining.min(bucket.capacity());
+            bucket.refill(Instant::now());
+            let may_take = priority == IoPriority::High || bucket.high_waiters == 0;
+            if may_take && bucket.available >= chunk {
+                bucket.available -= chunk;
+                bucket.total_bytes[priority as usize] += chunk;
+                remaining -= chunk;
+                continue;
+            }
+            let missing = chunk.saturating_sub(bucket.available).max(1);
+            let wait = Duration::from_secs_f64(missing as f64 / bucket.bytes_per_second as f64)
+                .max(Duration::from_millis(1));
+            self.refilled.wait_for(&mut bucket, wait);
+        }
+        if priority == IoPriority::High {
+            bucket.high_waiters -= 1;
+            // low priority requests may proceed now
+            self.refilled.notify_all();
+        }
+    }
+}
+
+/// Rate limiter test
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    /// Test requests are throttled to the rate and counted per priority
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(100_000);
+        let start = Instant::now();
+        // the first 10_000 bytes are already in the bucket
+        limiter.request(30_000, IoPriority::Low);
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        limiter.request(1000, IoPriority::High);
+        assert_eq!(limiter.total_bytes(IoPriority::Low), 30_000);
+        assert_eq!(limiter.total_bytes(IoPriority::High), 1000);
+
+        let unlimited = RateLimiter::new(0);
+        let start = Instant::now();
+        unlimited.request(1 << 30, IoPriority::Low);
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    /// Test a waiting request continues at the rate set at runtime
+    #[test]
+    fn test_rate_limiter_set_rate() {
+        let limiter = Arc::new(RateLimiter::new(1000));
+        let start = Instant::now();
+        std::thread::scope(|scope| {
+            scope.spawn(|| limiter.request(100_000, IoPriority::Low));
+            std::thread::sleep(Duration::from_millis(50));
+            limiter.set_bytes_per_second(0);
+        });
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(limiter.bytes_per_second(), 0);
+        assert_eq!(limiter.total_bytes(IoPriority::Low), 100_000);
+    }
+}
@@ -0,0 +1,749 @@
+mod builder;
+mod iterator;
+mod reader;
+mod writer;
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result, bail};
+use bytes::{Buf, BufMut, Bytes};
+
+pub use builder::SsTableBuilder;
+pub use iterator::SsTableIterator;
+pub use reader::TableReader;
+pub use writer::{ExternalSstFileInfo, SstFileWriter};
+
+use crate::{
+    blob::BlobPointer,
+    block::{Block, BlockIterator, SIZEOF_U16, SIZEOF_U32},
+    cache::{BlockCache, CachePriority, META_BLOCK_IDX, TableCache, block_charge},
+    error::Error,
+    fs::{FileSystem, RandomAccessFile},
+    rate_limiter::{IoPriority, RateLimiter},
+};
+
+/// Rate limited files are written in chunks of this size.
+const RATE_LIMITED_WRITE_SIZE: usize = 64 * 1024;
+
+/// Size of a property stored in the meta section.
+const SIZEOF_U64: usize = std::mem::size_of::<u64>();
+
+/// Tag of a value stored inline in the SST.
+const VALUE_TAG_INLINE: u8 = 0;
+/// Tag of a value stored in a blob file, the SST only holds a `BlobPointer`.
+const VALUE_TAG_BLOB: u8 = 1;
+/// Tag of a tombstone.
+const VALUE_TAG_DELETE: u8 = 2;
+
+/// The value of an SST entry, either the value itself, a pointer into a blob file or a
+/// tombstone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TableValue {
+    Inline(Bytes),
+    Blob(BlobPointer),
+    Delete,
+}
+
+impl TableValue {
+    /// Encode the value with its tag.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            TableValue::Inline(value) => {
+                buf.put_u8(VALUE_TAG_INLINE);
+                buf.put_slice(value);
+            }
+            TableValue::Blob(pointer) => {
+                buf.put_u8(VALUE_TAG_BLOB);
+                pointer.encode(buf);
+            }
+            TableValue::Delete => buf.put_u8(VALUE_TAG_DELETE),
+        }
+    }
+
+    /// Decode a tagged value.
+    pub fn decode(mut buf: &[u8]) -> Result<Self> {
+        if buf.is_empty() {
+            bail!("sst value is missing its tag");
+        }
+        match buf.get_u8() {
+            VALUE_TAG_INLINE => Ok(TableValue::Inline(Bytes::copy_from_slice(buf))),
+            VALUE_TAG_BLOB => Ok(TableValue::Blob(BlobPointer::decode(buf)?)),
+            VALUE_TAG_DELETE if buf.is_empty() => Ok(TableValue::Delete),
+            tag => bail!("unknown sst value tag {}", tag),
+        }
+    }
+}
+
+/// Metadata of a block in an SST.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockMeta {
+    /// Offset of this data block.
+    pub offset: usize,
+    /// The first key of the data block.
+    pub first_key: Bytes,
+    /// The last key of the data block.
+    pub last_key: Bytes,
+}
+
+impl BlockMeta {
+    /// Encode block meta to a buffer.
+    ///
+    /// Layout: `| num (u32) | offset (u32) | first_key_len (u16) | first_key | last_key_len (u16) | last_key | ... |`
+    pub fn encode_block_meta(block_meta: &[BlockMeta], buf: &mut Vec<u8>) {
+        buf.put_u32(block_meta.len() as u32);
+        for meta in block_meta {
+            buf.put_u32(meta.offset as u32);
+            buf.put_u16(meta.first_key.len() as u16);
+            buf.put_slice(&meta.first_key);
+            buf.put_u16(meta.last_key.len() as u16);
+            buf.put_slice(&meta.last_key);
+        }
+    }
+
+    /// Size of the encoded block meta.
+    fn encoded_len(block_meta: &[BlockMeta]) -> usize {
+        SIZEOF_U32
+            + block_meta
+                .iter()
+                .map(|meta| {
+                    SIZEOF_U32 + SIZEOF_U16 * 2 + meta.first_key.len() + meta.last_key.len()
+                })
+                .sum::<usize>()
+    }
+
+    /// Decode block meta from a buffer.
+    pub fn decode_block_meta(mut buf: &[u8]) -> Result<Vec<BlockMeta>> {
+        if buf.remaining() < SIZEOF_U32 {
+            bail!("block meta is truncated");
+        }
+        let num = buf.get_u32() as usize;
+        let mut block_meta = Vec::with_capacity(num);
+        for _ in 0..num {
+            if buf.remaining() < SIZEOF_U32 + SIZEOF_U16 {
+                bail!("block meta is truncated");
+            }
+            let offset = buf.get_u32() as usize;
+            let first_key_len = buf.get_u16() as usize;
+            if buf.remaining() < first_key_len + SIZEOF_U16 {
+                bail!("block meta is truncated");
+            }
+            let first_key = buf.copy_to_bytes(first_key_len);
+            let last_key_len = buf.get_u16() as usize;
+            if buf.remaining() < last_key_len {
+                bail!("block meta is truncated");
+            }
+            let last_key = buf.copy_to_bytes(last_key_len);
+            block_meta.push(BlockMeta {
+                offset,
+                first_key,
+                last_key,
+            });
+        }
+        Ok(block_meta)
+    }
+}
+
+/// A file object.
+pub struct FileObject(Arc<dyn RandomAccessFile>, u64, PathBuf);
+
+impl FileObject {
+    /// Read `len` bytes at `offset`.
+    pub fn read(&self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let mut data = vec![0; len as usize];
+        self.0.read_at(offset, &mut data[..])?;
+        Ok(data)
+    }
+
+    /// Get the size of the file.
+    pub fn size(&self) -> u64 {
+        self.1
+    }
+
+    /// Get the path of the file.
+    pub fn path(&self) -> &Path {
+        &self.2
+    }
+
+    /// Create a new file object and write the file to the disk.
+    pub fn create(fs: &dyn FileSystem, path: &Path, data: Vec<u8>) -> Result<Self> {
+        Self::create_rate_limited(fs, path, data, None)
+    }
+
+    /// Create a new file object, each chunk of the file is written once `rate_limiter` grants
+    /// it.
+    pub fn create_rate_limited(
+        fs: &dyn FileSystem,
+        path: &Path,
+        data: Vec<u8>,
+        rate_limiter: Option<(&RateLimiter, IoPriority)>,
+    ) -> Result<Self> {
+        let mut file = fs.create(path)?;
+        match rate_limiter {
+            Some((rate_limiter, priority)) => {
+                for chunk in data.chunks(RATE_LIMITED_WRITE_SIZE) {
+                    rate_limiter.request(chunk.len(), priority);
+                    file.append(chunk)?;
+                }
+            }
+            None => file.append(&data)?,
+        }
+        file.sync()?;
+        fs.sync_parent_dir(path)?;
+        Ok(FileObject(
+            fs.open(path)?,
+            data.len() as u64,
+            path.to_path_buf(),
+        ))
+    }
+
+    /// Open an existing file.
+    pub fn open(fs: &dyn FileSystem, path: &Path) -> Result<Self> {
+        let file = fs
+            .open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        let size = file.size()?;
+        Ok(FileObject(file, size, path.to_path_buf()))
+    }
+}
+
+/// An SSTable.
+///
+/// Layout: `| data block | ... | data block | meta section | meta checksum (u32) | footer |`.
+///
+/// Each data block is `| block | codec (u8) | checksum (u32) |`, the meta section is
+/// `| block meta | properties | dictionary |` where the zstd dictionary is empty unless the
+/// blocks were compressed with one, and the footer is `| meta offset (u32) | footer checksum
+/// (u32) |`. Checksums are crc32 of the bytes they follow.
+///
+/// The properties are `| creation time (u64) | file creation time (u64) | entries (u64) |
+/// tombstones (u64) | blob files (u32) | blob file id (u64) | bytes (u64) | ... |`, the times
+/// are in milliseconds since the unix epoch, and each blob file the SST points into comes with
+/// the bytes of the values it points to.
+///
+/// The SST only keeps its properties, the open file and the block meta are in its
+/// `TableReader`, which is either kept by the SST or by a `TableCache`.
+pub struct SsTable {
+    id: usize,
+    path: PathBuf,
+    file_size: u64,
+    num_blocks: usize,
+    first_key: Bytes,
+    last_key: Bytes,
+    creation_time: SystemTime,
+    file_creation_time: SystemTime,
+    num_entries: u64,
+    num_tombstones: u64,
+    blob_references: Vec<(usize, u64)>,
+    /// Whether block checksums are verified on read.
+    verify_checksums: bool,
+    /// The cache of the blocks read, with the cache id of the SST.
+    block_cache: Option<(Arc<BlockCache>, u64)>,
+    reader: ReaderHandle,
+    /// Set once the SST left the state, its file is removed through this file system when the
+    /// last snapshot or iterator reading it is dropped.
+    obsolete: OnceLock<Arc<dyn FileSystem>>,
+}
+
+/// Where an SST finds its `TableReader`.
+enum ReaderHandle {
+    /// Kept open as long as the SST.
+    Open(Arc<TableReader>),
+    /// Looked up in the table cache, and opened again once evicted.
+    Cached(Arc<TableCache>),
+    /// A meta-only SST has no file.
+    None,
+}
+
+impl SsTable {
+    /// Open SSTable from a file, verifying the checksums of the footer and the meta section.
+    pub fn open(id: usize, file: FileObject) -> Result<Self> {
+        let path = file.path().to_path_buf();
+        let file_size = file.size();
+        let (reader, properties) = TableReader::open(id, file)?;
+        Ok(Self {
+            id,
+            path,
+            file_size,
+            num_blocks: reader.block_meta.len(),
+            first_key: properties.first_key,
+            last_key: properties.last_key,
+            creation_time: properties.creation_time,
+            file_creation_time: properties.file_creation_time,
+            num_entries: properties.num_entries,
+            num_tombstones: properties.num_tombstones,
+            blob_references: properties.blob_references,
+            verify_checksums: true,
+            block_cache: None,
+            reader: ReaderHandle::Open(Arc::new(reader)),
+            obsolete: OnceLock::new(),
+        })
+    }
+
+    /// Create an SST holding only its metadata, for the compaction simulator which never reads
+    /// its blocks.
+    pub(crate) fn create_meta_only(
+        id: usize,
+        file_size: u64,
+        first_key: Bytes,
+        last_key: Bytes,
+        num_entries: u64,
+    ) -> Self {
+        let now = SystemTime::now();
+        Self {
+            id,
+            path: PathBuf::new(),
+            file_size,
+            num_blocks: 0,
+            first_key,
+            last_key,
+            creation_time: now,
+            file_creation_time: now,
+            num_entries,
+            num_tombstones: 0,
+            blob_references: Vec::new(),
+            verify_checksums: false,
+            block_cache: None,
+            reader: ReaderHandle::None,
+            obsolete: OnceLock::new(),
+        }
+    }
+
+    /// Whether to verify the checksum of every block read, on by default. Only skip it for
+    /// trusted storage.
+    pub fn with_verify_checksums(mut self, verify_checksums: bool) -> Self {
+        self.verify_checksums = verify_checksums;
+        self
+    }
+
+    /// Keep the blocks read from the SST in `block_cache`, and its meta section with high
+    /// priority.
+    pub fn with_block_cache(mut self, block_cache: Option<Arc<BlockCache>>) -> Self {
+        self.block_cache = block_cache.map(|cache| {
+            let table_id = cache.new_table_id();
+            (cache, table_id)
+        });
+        if let (Some((cache, table_id)), ReaderHandle::Open(reader)) =
+            (&self.block_cache, &self.reader)
+        {
+            let meta = reader.meta.clone();
+            let charge = block_charge(&meta);
+            cache.insert(
+                (*table_id, META_BLOCK_IDX),
+                meta,
+                charge,
+                CachePriority::High,
+            );
+        }
+        self
+    }
+
+    /// Leave the open file to `table_cache`, which closes it once too many files are open. The
+    /// file is opened again when it is read.
+    pub fn with_table_cache(mut self, table_cache: Option<Arc<TableCache>>) -> Self {
+        if let (Some(table_cache), ReaderHandle::Open(reader)) = (table_cache, &self.reader) {
+            table_cache.insert(self.id, reader.clone());
+            self.reader = ReaderHandle::Cached(table_cache);
+        }
+        self
+    }
+
+    /// Remove the file of the SST through `file_system` once it is dropped, so the snapshots and
+    /// iterators still holding it keep reading it.
+    pub(crate) fn mark_obsolete(&self, file_system: Arc<dyn FileSystem>) {
+        self.obsolete.set(file_system).ok();
+    }
+
+    /// Get the reader of the SST, opening its file again when the table cache closed it.
+    pub(crate) fn reader(&self) -> Result<Arc<TableReader>> {
+        match &self.reader {
+            ReaderHandle::Open(reader) => Ok(reader.clone()),
+            ReaderHandle::Cached(table_cache) => {
+                let block_cache = self
+                    .block_cache
+                    .as_ref()
+                    .map(|(cache, table_id)| (cache.as_ref(), *table_id));
+                table_cache.get_or_open(self.id, &self.path, block_cache)
+            }
+            ReaderHandle::None => bail!("sst {} has no file", self.id),
+        }
+    }
+
+    /// Read a block from the block cache, or from the disk and cache it.
+    pub fn read_block(&self, block_idx: usize) -> Result<Arc<Block>> {
+        self.read_block_cached(block_idx, true)
+    }
+
+    /// Read a block from the block cache, or from the disk. The block is only cached when
+    /// `fill_cache` is set, so that blocks read once by a scan do not evict others.
+    pub fn read_block_cached(&self, block_idx: usize, fill_cache: bool) -> Result<Arc<Block>> {
+        self.read_block_with_reader(None, block_idx, fill_cache)
+    }
+
+    /// Read a block like `read_block_cached`, from the disk through `reader` when it is given.
+    pub(crate) fn read_block_with_reader(
+        &self,
+        reader: Option<&TableReader>,
+        block_idx: usize,
+        fill_cache: bool,
+    ) -> Result<Arc<Block>> {
+        let Some((cache, table_id)) = &self.block_cache else {
+            return self.read_block_from_disk(reader, block_idx, self.verify_checksums);
+        };
+        let key = (*table_id, block_idx);
+        if let Some(block) = cache.get(&key) {
+            return Ok(block);
+        }
+        let block = self.read_block_from_disk(reader, block_idx, self.verify_checksums)?;
+        if fill_cache {
+            cache.insert(key, block.clone(), block_charge(&block), CachePriority::Low);
+        }
+        Ok(block)
+    }
+
+    /// Read a block from the disk, verifying its checksum whatever the table was opened with.
+    pub fn read_block_verified(&self, block_idx: usize) -> Result<Arc<Block>> {
+        self.read_block_from_disk(None, block_idx, true)
+    }
+
+    fn read_block_from_disk(
+        &self,
+        reader: Option<&TableReader>,
+        block_idx: usize,
+        verify_checksums: bool,
+    ) -> Result<Arc<Block>> {
+        match reader {
+            Some(reader) => reader.read_block(block_idx, verify_checksums),
+            None => self.reader()?.read_block(block_idx, verify_checksums),
+        }
+    }
+
+    /// Find the block that may contain `key`.
+    pub fn find_block_idx(&self, key: &[u8]) -> Result<usize> {
+        Ok(self.reader()?.find_block_idx(key))
+    }
+
+    /// Get the value of `key` if this SST contains it.
+    pub fn get(&self, key: &[u8]) -> Result<Option<TableValue>> {
+        if key < self.first_key.as_ref() || key > self.last_key.as_ref() {
+            return Ok(None);
+        }
+        let reader = self.reader()?;
+        let block = self.read_block_with_reader(Some(&reader), reader.find_block_idx(key), true)?;
+        let iter = BlockIterator::create_and_seek_to_key(block, key);
+        if iter.is_valid() && iter.key() == key {
+            return Ok(Some(TableValue::decode(iter.value())?));
+        }
+        Ok(None)
+    }
+
+    /// Get number of data blocks.
+    pub fn num_of_blocks(&self) -> usize {
+        self.num_blocks
+    }
+
+    /// Get the first key of the SST.
+    pub fn first_key(&self) -> &Bytes {
+        &self.first_key
+    }
+
+    /// Get the last key of the SST.
+    pub fn last_key(&self) -> &Bytes {
+        &self.last_key
+    }
+
+    /// Get the time the newest data of the SST was written: when it was flushed, or the latest
+    /// creation time of the SSTs it was compacted from.
+    pub fn creation_time(&self) -> SystemTime {
+        self.creation_time
+    }
+
+    /// Get the time the SST file was written, its data has not been compacted since.
+    pub fn file_creation_time(&self) -> SystemTime {
+        self.file_creation_time
+    }
+
+    /// Get the number of entries of the SST, tombstones included.
+    pub fn num_entries(&self) -> u64 {
+        self.num_entries
+    }
+
+    /// Get the number of tombstones of the SST.
+    pub fn num_tombstones(&self) -> u64 {
+        self.num_tombstones
+    }
+
+    /// Get the blob files the SST points into, with the bytes of the values it points to in each.
+    pub fn blob_references(&self) -> &[(usize, u64)] {
+        &self.blob_references
+    }
+
+    /// Get the size of the SST file.
+    pub fn table_size(&self) -> u64 {
+        self.file_size
+    }
+
+    /// Get the id of the SST.
+    pub fn sst_id(&self) -> usize {
+        self.id
+    }
+}
+
+impl Drop for SsTable {
+    fn drop(&mut self) {
+        let Some(file_system) = self.obsolete.get() else {
+            return;
+        };
+        // nothing opens the file again once the SST is dropped
+        if let ReaderHandle::Cached(table_cache) = &self.reader {
+            table_cache.evict(self.id);
+        }
+        file_system.remove_file(&self.path).ok();
+    }
+}
+
+fn corruption(file: &FileObject, offset: u64) -> anyhow::Error {
+    Error::Corruption {
+        file: file.path().to_path_buf(),
+        offset,
+    }
+    .into()
+}
+
+/// SsTable test
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::{
+        compress::CompressionType, fs::StdFileSystem, iterators::StorageIterator,
+        lsm_storage::MAX_KEY_SIZE,
+    };
+
+    fn key_of(idx: usize) -> Vec<u8> {
+        format!("key_{:03}", idx).into_bytes()
+    }
+
+    fn value_of(idx: usize) -> Vec<u8> {
+        format!("value_{:010}", idx).into_bytes()
+    }
+
+    /// Test sst build and open
+    #[test]
+    fn test_sst_build_and_open() -> Result<()> {
+        let dir = tempdir()?;
+        let mut builder = SsTableBuilder::new(128);
+        for idx in 0..100 {
+            builder.add(&key_of(idx), &value_of(idx));
+        }
+        let sst = builder.build(1, dir.path().join("1.sst"))?;
+        assert!(sst.num_of_blocks() > 1);
+        assert_eq!(sst.first_key().as_ref(), key_of(0));
+        assert_eq!(sst.last_key().as_ref(), key_of(99));
+
+        let reopened = SsTable::open(
+            1,
+            FileObject::open(&StdFileSystem, &dir.path().join("1.sst"))?,
+        )?;
+        assert_eq!(reopened.reader()?.block_meta, sst.reader()?.block_meta);
+        assert_eq!(
+            reopened.get(&key_of(42))?,
+            Some(TableValue::Inline(Bytes::from(value_of(42))))
+        );
+        assert_eq!(reopened.get(b"key_0421")?, None);
+        Ok(())
+    }
+
+    /// Test sst iterator
+    #[test]
+    fn test_sst_iterator() -> Result<()> {
+        let dir = tempdir()?;
+        let mut builder = SsTableBuilder::new(128);
+        for idx in 0..100 {
+            builder.add(&key_of(idx), &value_of(idx));
+        }
+        let sst = Arc::new(builder.build(1, dir.path().join("1.sst"))?);
+
+        let mut iter = SsTableIterator::create_and_seek_to_first(sst.clone())?;
+        for idx in 0..100 {
+            assert!(iter.is_valid());
+            assert_eq!(iter.key(), key_of(idx));
+            assert_eq!(
+                TableValue::decode(iter.value())?,
+                TableValue::Inline(Bytes::from(value_of(idx)))
+            );
+            iter.next()?;
+        }
+        assert!(!iter.is_valid());
+        Ok(())
+    }
+
+    /// Test an SST compressed with a trained dictionary is smaller and reads back
+    #[test]
+    fn test_sst_zstd_dictionary() -> Result<()> {
+        let dir = tempdir()?;
+        let json = |idx: usize| {
+            format!(
+                r#"{{"id":{},"user":"user_{}","status":"active","tags":["alpha","beta"]}}"#,
+                idx,
+                idx % 7
+            )
+        };
+        let build = |builder: SsTableBuilder, name: &str| -> Result<SsTable> {
+            let mut builder = builder;
+            for idx in 0..2000 {
+                builder.add(format!("key_{:05}", idx).as_bytes(), json(idx).as_bytes());
+            }
+            builder.build(1, dir.path().join(name))
+        };
+        let plain = build(
+            SsTableBuilder::new(256).with_compression(CompressionType::Zstd),
+            "plain.sst",
+        )?;
+        let with_dictionary = build(
+            SsTableBuilder::new(256)
+                .with_compression(CompressionType::Zstd)
+                .with_zstd_dictionary(4096),
+            "dictionary.sst",
+        )?;
+        assert!(with_dictionary.reader()?.dictionary.is_some());
+        assert!(with_dictionary.table_size() < plain.table_size());
+
+        let sst = SsTable::open(
+            1,
+            FileObject::open(&StdFileSystem, &dir.path().join("dictionary.sst"))?,
+        )?;
+        assert_eq!(
+            sst.reader()?.block_meta,
+            with_dictionary.reader()?.block_meta
+        );
+        for idx in [0, 999, 1999] {
+            assert_eq!(
+                sst.get(format!("key_{:05}", idx).as_bytes())?,
+                Some(TableValue::Inline(Bytes::from(json(idx))))
+            );
+        }
+        Ok(())
+    }
+
+    fn error_of<T>(result: Result<T>) -> Error {
+        result.err().unwrap().downcast::<Error>().unwrap()
+    }
+
+    /// Test corrupted blocks, meta and footer are reported with their offset
+    #[test]
+    fn test_sst_checksum() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("1.sst");
+        let mut builder = SsTableBuilder::new(128);
+        for idx in 0..100 {
+            builder.add(&key_of(idx), &value_of(idx));
+        }
+        let sst = builder.build(1, &path)?;
+        let block_offset = sst.reader()?.block_meta[1].offset;
+        let meta_offset = sst.reader()?.block_meta_offset;
+        let data = std::fs::read(&path)?;
+        let corrupt = |offset: usize| -> Result<FileObject> {
+            let mut data = data.clone();
+            data[offset] ^= 0xff;
+            std::fs::write(&path, data)?;
+            FileObject::open(&StdFileSystem, &path)
+        };
+
+        let sst = SsTable::open(1, corrupt(block_offset + 4)?)?;
+        assert!(sst.read_block(0).is_ok());
+        assert_eq!(
+            error_of(sst.read_block(1)),
+            Error::Corruption {
+                file: path.clone(),
+                offset: block_offset as u64
+            }
+        );
+        // the flipped byte is in a key, so the block still decodes without verification
+        let sst = sst.with_verify_checksums(false);
+        assert!(sst.read_block(1).is_ok());
+
+        assert_eq!(
+            error_of(SsTable::open(1, corrupt(meta_offset + 1)?)),
+            Error::Corruption {
+                file: path.clone(),
+                offset: meta_offset as u64
+            }
+        );
+        assert_eq!(
+            error_of(SsTable::open(1, corrupt(data.len() - 5)?)),
+            Error::Corruption {
+                file: path.clone(),
+                offset: data.len() as u64 - 8
+            }
+        );
+        Ok(())
+    }
+
+    /// Test table value encode and decode
+    #[test]
+    fn test_table_value_encode_decode() -> Result<()> {
+        let pointer = BlobPointer {
+            file_id: 3,
+            offset: 42,
+            len: 7,
+        };
+        for value in [
+            TableValue::Inline(Bytes::from_static(b"value")),
+            TableValue::Inline(Bytes::new()),
+            TableValue::Blob(pointer),
+            TableValue::Delete,
+        ] {
+            let mut buf = Vec::new();
+            value.encode(&mut buf);
+            assert_eq!(TableValue::decode(&buf)?, value);
+        }
+        Ok(())
+    }
+
+    /// Test sst file writer, and that it refuses unordered, empty and too large keys
+    #[test]
+    fn test_sst_file_writer() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("external.sst");
+        let mut writer = SstFileWriter::create(&path, 128);
+        for idx in 0..10 {
+            writer.put(&key_of(idx), &value_of(idx))?;
+        }
+        writer.delete(&key_of(10))?;
+        assert!(writer.put(&key_of(10), b"value").is_err());
+        assert!(writer.put(&key_of(5), b"value").is_err());
+        writer.put(&key_of(11), b"")?;
+        let error = Error::from(writer.put(&[b'z'; MAX_KEY_SIZE + 1], b"value").unwrap_err());
+        assert!(matches!(error, Error::InvalidArgument(_)));
+        let error = Error::from(writer.delete(b"").unwrap_err());
+        assert!(matches!(error, Error::InvalidArgument(_)));
+        let info = writer.finish()?;
+        assert_eq!(info.first_key.as_ref(), key_of(0));
+        assert_eq!(info.last_key.as_ref(), key_of(11));
+        assert_eq!(info.num_entries, 12);
+
+        let sst = SsTable::open(0, FileObject::open(&StdFileSystem, &path)?)?;
+        assert_eq!(
+            sst.get(&key_of(3))?,
+            Some(TableValue::Inline(Bytes::from(value_of(3))))
+        );
+        assert_eq!(sst.get(&key_of(10))?, Some(TableValue::Delete));
+        assert_eq!((sst.num_entries(), sst.num_tombstones()), (12, 1));
+        assert_eq!(
+            sst.get(&key_of(11))?,
+            Some(TableValue::Inline(Bytes::new()))
+        );
+
+        assert!(
+            SstFileWriter::create(dir.path().join("empty.sst"), 128)
+                .finish()
+                .is_err()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,258 @@
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Result, bail};
+use bytes::Bytes;
+
+use super::{BlockMeta, FileObject, SsTable, TableValue, VALUE_TAG_BLOB, VALUE_TAG_DELETE};
+use crate::{
+    blob::BlobPointer,
+    block::BlockBuilder,
+    compress::{CompressionType, DictionaryCompressor, ZstdDictionary},
+    error::Error,
+    fs::{FileSystem, StdFileSystem},
+    rate_limiter::{IoPriority, RateLimiter},
+};
+
+/// Builds an SSTable from key-value pairs.
+pub struct SsTableBuilder {
+    builder: BlockBuilder,
+    first_key: Vec<u8>,
+    last_key: Vec<u8>,
+    data: Vec<u8>,
+    pub(crate) meta: Vec<BlockMeta>,
+    block_size: usize,
+    compression: CompressionType,
+    /// Maximum size of the zstd dictionary, 0 when the blocks are compressed on their own.
+    zstd_max_dict_bytes: usize,
+    /// Blocks waiting for the dictionary to be trained.
+    pending_blocks: Vec<Vec<u8>>,
+    rate_limiter: Option<(Arc<RateLimiter>, IoPriority)>,
+    file_system: Arc<dyn FileSystem>,
+    /// Creation time of the SST, the time it is built unless set.
+    creation_time: Option<SystemTime>,
+    num_entries: u64,
+    num_tombstones: u64,
+    /// Bytes of the values the SST points to in each blob file.
+    blob_references: BTreeMap<usize, u64>,
+}
+
+impl SsTableBuilder {
+    /// Create a builder based on target block size.
+    pub fn new(block_size: usize) -> Self {
+        Self {
+            builder: BlockBuilder::new(block_size),
+            first_key: Vec::new(),
+            last_key: Vec::new(),
+            data: Vec::new(),
+            meta: Vec::new(),
+            block_size,
+            compression: CompressionType::None,
+            zstd_max_dict_bytes: 0,
+            pending_blocks: Vec::new(),
+            rate_limiter: None,
+            file_system: Arc::new(StdFileSystem),
+            creation_time: None,
+            num_entries: 0,
+            num_tombstones: 0,
+            blob_references: BTreeMap::new(),
+        }
+    }
+
+    /// Compress the data blocks with `compression`.
+    pub fn with_compression(mut self, compression: CompressionType) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Train a zstd dictionary of at most `max_dict_bytes` from the blocks of the SST and
+    /// compress every block with it. The blocks are kept in memory until the SST is built, and
+    /// compressed with the codec of `with_compression` when no dictionary can be trained.
+    pub fn with_zstd_dictionary(mut self, max_dict_bytes: usize) -> Self {
+        self.zstd_max_dict_bytes = max_dict_bytes;
+        self
+    }
+
+    /// Write the SST through `rate_limiter` with `priority`.
+    pub fn with_rate_limiter(
+        mut self,
+        rate_limiter: Option<Arc<RateLimiter>>,
+        priority: IoPriority,
+    ) -> Self {
+        self.rate_limiter = rate_limiter.map(|rate_limiter| (rate_limiter, priority));
+        self
+    }
+
+    /// Write the SST to `file_system` instead of the file system of the operating system.
+    pub fn with_file_system(mut self, file_system: Arc<dyn FileSystem>) -> Self {
+        self.file_system = file_system;
+        self
+    }
+
+    /// Record `creation_time` as the time the newest data of the SST was written.
+    pub fn with_creation_time(mut self, creation_time: SystemTime) -> Self {
+        self.creation_time = Some(creation_time);
+        self
+    }
+
+    /// Adds a key-value pair to SSTable, the value is stored inline.
+    ///
+    /// Keys must be added in ascending order.
+    pub fn add(&mut self, key: &[u8], value: &[u8]) {
+        let mut buf = Vec::with_capacity(value.len() + 1);
+        TableValue::Inline(Bytes::copy_from_slice(value)).encode(&mut buf);
+        self.add_raw(key, &buf);
+    }
+
+    /// Adds a key with its value stored in a blob file.
+    pub fn add_blob(&mut self, key: &[u8], pointer: BlobPointer) {
+        let mut buf = Vec::new();
+        TableValue::Blob(pointer).encode(&mut buf);
+        self.add_raw(key, &buf);
+    }
+
+    /// Adds a tombstone for a key.
+    pub fn add_delete(&mut self, key: &[u8]) {
+        let mut buf = Vec::with_capacity(1);
+        TableValue::Delete.encode(&mut buf);
+        self.add_raw(key, &buf);
+    }
+
+    /// Adds a key with an already encoded `TableValue`.
+    pub(crate) fn add_raw(&mut self, key: &[u8], value: &[u8]) {
+        self.num_entries += 1;
+        match value.split_first() {
+            Some((&VALUE_TAG_DELETE, _)) => self.num_tombstones += 1,
+            Some((&VALUE_TAG_BLOB, pointer)) => {
+                if let Ok(pointer) = BlobPointer::decode(pointer) {
+                    *self.blob_references.entry(pointer.file_id).or_default() += pointer.len as u64;
+                }
+            }
+            _ => {}
+        }
+        if self.first_key.is_empty() {
+            self.first_key = key.to_vec();
+        }
+        if self.builder.add(key, value) {
+            self.last_key = key.to_vec();
+            return;
+        }
+        // the current block is full, start a new one
+        self.finish_block();
+        assert!(self.builder.add(key, value));
+        self.first_key = key.to_vec();
+        self.last_key = key.to_vec();
+    }
+
+    fn finish_block(&mut self) {
+        let builder = std::mem::replace(&mut self.builder, BlockBuilder::new(self.block_size));
+        let encoded_block = builder.build().encode();
+        self.meta.push(BlockMeta {
+            offset: self.data.len(),
+            first_key: std::mem::take(&mut self.first_key).into(),
+            last_key: std::mem::take(&mut self.last_key).into(),
+        });
+        if self.zstd_max_dict_bytes > 0 {
+            // the offset is set once the block is compressed
+            self.pending_blocks.push(encoded_block.to_vec());
+        } else {
+            let offset = self.data.len();
+            self.compression
+                .compress_block(&encoded_block, &mut self.data);
+            append_checksum(&mut self.data, offset);
+        }
+    }
+
+    /// Compress the pending blocks, returning the dictionary they were compressed with.
+    fn compress_pending_blocks(&mut self) -> Result<Vec<u8>> {
+        if self.pending_blocks.is_empty() {
+            return Ok(Vec::new());
+        }
+        let blocks = std::mem::take(&mut self.pending_blocks);
+        let dictionary = ZstdDictionary::train(&blocks, self.zstd_max_dict_bytes);
+        let mut compressor = dictionary
+            .as_deref()
+            .map(DictionaryCompressor::new)
+            .transpose()?;
+        for (meta, block) in self.meta.iter_mut().zip(&blocks) {
+            meta.offset = self.data.len();
+            match compressor.as_mut() {
+                Some(compressor) => compressor.compress_block(block, &mut self.data),
+                None => self.compression.compress_block(block, &mut self.data),
+            }
+            append_checksum(&mut self.data, meta.offset);
+        }
+        Ok(dictionary.unwrap_or_default())
+    }
+
+    /// Get the estimated size of the SSTable.
+    pub fn estimated_size(&self) -> usize {
+        self.data.len() + self.pending_blocks.iter().map(Vec::len).sum::<usize>()
+    }
+
+    /// Whether no key has been added to the builder.
+    pub fn is_empty(&self) -> bool {
+        self.meta.is_empty() && self.builder.is_empty()
+    }
+
+    /// Builds the SSTable and writes it to the given path. Fails when the data blocks add up to
+    /// more than the 4 GiB the 32-bit block offsets can address.
+    pub fn build(mut self, id: usize, path: impl AsRef<Path>) -> Result<SsTable> {
+        if !self.builder.is_empty() {
+            self.finish_block();
+        }
+        let dictionary = self.compress_pending_blocks()?;
+        let mut buf = self.data;
+        let meta_offset = buf.len();
+        // every block offset is below the meta offset
+        let Ok(encoded_meta_offset) = u32::try_from(meta_offset) else {
+            bail!(Error::InvalidArgument(format!(
+                "SST data of {} bytes exceeds the maximum of {} bytes",
+                meta_offset,
+                u32::MAX
+            )));
+        };
+        BlockMeta::encode_block_meta(&self.meta, &mut buf);
+        let file_creation_time = SystemTime::now();
+        for time in [
+            self.creation_time.unwrap_or(file_creation_time),
+            file_creation_time,
+        ] {
+            let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+            buf.extend((time.as_millis() as u64).to_be_bytes());
+        }
+        buf.extend(self.num_entries.to_be_bytes());
+        buf.extend(self.num_tombstones.to_be_bytes());
+        buf.extend((self.blob_references.len() as u32).to_be_bytes());
+        for (blob_id, bytes) in &self.blob_references {
+            buf.extend((*blob_id as u64).to_be_bytes());
+            buf.extend(bytes.to_be_bytes());
+        }
+        buf.extend(dictionary);
+        append_checksum(&mut buf, meta_offset);
+        let footer_offset = buf.len();
+        buf.extend(encoded_meta_offset.to_be_bytes());
+        append_checksum(&mut buf, footer_offset);
+        let rate_limiter = self
+            .rate_limiter
+            .as_ref()
+            .map(|(rate_limiter, priority)| (rate_limiter.as_ref(), *priority));
+        let file = FileObject::create_rate_limited(
+            self.file_system.as_ref(),
+            path.as_ref(),
+            buf,
+            rate_limiter,
+        )?;
+        SsTable::open(id, file)
+    }
+}
+
+/// Append the crc32 of `buf[offset..]`.
+fn append_checksum(buf: &mut Vec<u8>, offset: usize) {
+    let checksum = crc32fast::hash(&buf[offset..]);
+    buf.extend(checksum.to_be_bytes());
+}
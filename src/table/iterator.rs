@@ -0,0 +1,138 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use super::{SsTable, TableReader};
+use crate::{block::BlockIterator, iterators::StorageIterator};
+
+/// An iterator over the contents of an SSTable.
+///
+/// The iterator keeps the SST file open, so it can still be read after a compaction removed it.
+pub struct SsTableIterator {
+    table: Arc<SsTable>,
+    reader: Arc<TableReader>,
+    blk_iter: BlockIterator,
+    blk_idx: usize,
+    /// Whether the blocks read are added to the block cache.
+    fill_cache: bool,
+}
+
+impl SsTableIterator {
+    fn seek_to_first_inner(
+        table: &Arc<SsTable>,
+        reader: &TableReader,
+        fill_cache: bool,
+    ) -> Result<(usize, BlockIterator)> {
+        Ok((
+            0,
+            BlockIterator::create_and_seek_to_first(table.read_block_with_reader(
+                Some(reader),
+                0,
+                fill_cache,
+            )?),
+        ))
+    }
+
+    fn seek_to_key_inner(
+        table: &Arc<SsTable>,
+        reader: &TableReader,
+        key: &[u8],
+        fill_cache: bool,
+    ) -> Result<(usize, BlockIterator)> {
+        let mut blk_idx = reader.find_block_idx(key);
+        let mut blk_iter = BlockIterator::create_and_seek_to_key(
+            table.read_block_with_reader(Some(reader), blk_idx, fill_cache)?,
+            key,
+        );
+        if !blk_iter.is_valid() {
+            blk_idx += 1;
+            if blk_idx < table.num_of_blocks() {
+                blk_iter = BlockIterator::create_and_seek_to_first(table.read_block_with_reader(
+                    Some(reader),
+                    blk_idx,
+                    fill_cache,
+                )?);
+            }
+        }
+        Ok((blk_idx, blk_iter))
+    }
+
+    /// Create a new iterator and seek to the first key-value pair in the first data block.
+    pub fn create_and_seek_to_first(table: Arc<SsTable>) -> Result<Self> {
+        Self::create_and_seek_to_first_with_fill_cache(table, true)
+    }
+
+    /// Create a new iterator and seek to the first key-value pair, the blocks it reads are only
+    /// added to the block cache when `fill_cache` is set.
+    pub fn create_and_seek_to_first_with_fill_cache(
+        table: Arc<SsTable>,
+        fill_cache: bool,
+    ) -> Result<Self> {
+        let reader = table.reader()?;
+        let (blk_idx, blk_iter) = Self::seek_to_first_inner(&table, &reader, fill_cache)?;
+        Ok(Self {
+            table,
+            reader,
+            blk_iter,
+            blk_idx,
+            fill_cache,
+        })
+    }
+
+    /// Create a new iterator and seek to the first key-value pair which >= `key`.
+    pub fn create_and_seek_to_key(table: Arc<SsTable>, key: &[u8]) -> Result<Self> {
+        Self::create_and_seek_to_key_with_fill_cache(table, key, true)
+    }
+
+    /// Create a new iterator and seek to the first key-value pair which >= `key`, the blocks it
+    /// reads are only added to the block cache when `fill_cache` is set.
+    pub fn create_and_seek_to_key_with_fill_cache(
+        table: Arc<SsTable>,
+        key: &[u8],
+        fill_cache: bool,
+    ) -> Result<Self> {
+        let reader = table.reader()?;
+        let (blk_idx, blk_iter) = Self::seek_to_key_inner(&table, &reader, key, fill_cache)?;
+        Ok(Self {
+            table,
+            reader,
+            blk_iter,
+            blk_idx,
+            fill_cache,
+        })
+    }
+}
+
+impl StorageIterator for SsTableIterator {
+    /// Return the `value` that's held by the underlying block iterator.
+    fn value(&self) -> &[u8] {
+        self.blk_iter.value()
+    }
+
+    /// Return the `key` that's held by the underlying block iterator.
+    fn key(&self) -> &[u8] {
+        self.blk_iter.key()
+    }
+
+    /// Return whether the current block iterator is valid or not.
+    fn is_valid(&self) -> bool {
+        self.blk_iter.is_valid()
+    }
+
+    /// Move to the next `key` in the block.
+    fn next(&mut self) -> Result<()> {
+        self.blk_iter.next();
+        if !self.blk_iter.is_valid() {
+            self.blk_idx += 1;
+            if self.blk_idx < self.table.num_of_blocks() {
+                self.blk_iter =
+                    BlockIterator::create_and_seek_to_first(self.table.read_block_with_reader(
+                        Some(&self.reader),
+                        self.blk_idx,
+                        self.fill_cache,
+                    )?);
+            }
+        }
+        Ok(())
+    }
+}
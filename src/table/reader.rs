@@ -0,0 +1,174 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Result, bail};
+use bytes::{Buf, Bytes};
+
+use super::{BlockMeta, FileObject, SIZEOF_U64, corruption};
+use crate::{
+    block::{Block, SIZEOF_U32},
+    cache::{BlockCache, CachePriority, META_BLOCK_IDX, block_charge},
+    compress::{CompressionType, ZstdDictionary},
+};
+
+/// Size of the footer, with the checksum of the meta section before it.
+const FOOTER_LEN: u64 = SIZEOF_U32 as u64 * 3;
+
+/// The properties recorded in the meta section of an SST.
+pub(crate) struct TableProperties {
+    pub(crate) first_key: Bytes,
+    pub(crate) last_key: Bytes,
+    pub(crate) creation_time: SystemTime,
+    pub(crate) file_creation_time: SystemTime,
+    pub(crate) num_entries: u64,
+    pub(crate) num_tombstones: u64,
+    pub(crate) blob_references: Vec<(usize, u64)>,
+}
+
+/// An open SST file with its parsed meta section, all that is needed to read its blocks.
+pub struct TableReader {
+    /// The actual storage unit of SsTable.
+    pub(crate) file: FileObject,
+    /// The meta blocks that hold info for data blocks.
+    pub(crate) block_meta: Vec<BlockMeta>,
+    /// The offset that indicates the start point of meta blocks in `file`.
+    pub(crate) block_meta_offset: usize,
+    pub(crate) dictionary: Option<ZstdDictionary>,
+    /// The meta section as read, shared with the block cache.
+    pub(crate) meta: Arc<Block>,
+}
+
+impl TableReader {
+    /// Open the SST `id` from a file, verifying the checksums of the footer and the meta
+    /// section.
+    pub(crate) fn open(id: usize, file: FileObject) -> Result<(Self, TableProperties)> {
+        Self::open_cached(id, file, None)
+    }
+
+    /// Open the SST `id` like `open`, taking its meta section from `block_cache` when it is
+    /// cached under the cache id of the SST, and caching it with high priority otherwise.
+    pub(crate) fn open_cached(
+        id: usize,
+        file: FileObject,
+        block_cache: Option<(&BlockCache, u64)>,
+    ) -> Result<(Self, TableProperties)> {
+        let read_meta = |file: &FileObject| -> Result<Arc<Block>> {
+            Ok(Arc::new(Block {
+                data: Self::read_meta(file)?,
+                offsets: Vec::new(),
+            }))
+        };
+        let meta = match block_cache {
+            Some((cache, table_id)) => {
+                let key = (table_id, META_BLOCK_IDX);
+                match cache.get(&key) {
+                    Some(meta) => meta,
+                    None => {
+                        let meta = read_meta(&file)?;
+                        cache.insert(key, meta.clone(), block_charge(&meta), CachePriority::High);
+                        meta
+                    }
+                }
+            }
+            None => read_meta(&file)?,
+        };
+        let block_meta_offset = file.size() - FOOTER_LEN - meta.data.len() as u64;
+        let raw_meta = &meta.data[..];
+        let block_meta = BlockMeta::decode_block_meta(raw_meta)?;
+        let mut properties = &raw_meta[BlockMeta::encoded_len(&block_meta)..];
+        if properties.remaining() < SIZEOF_U64 * 4 + SIZEOF_U32 {
+            return Err(corruption(&file, block_meta_offset));
+        }
+        let creation_time = UNIX_EPOCH + Duration::from_millis(properties.get_u64());
+        let file_creation_time = UNIX_EPOCH + Duration::from_millis(properties.get_u64());
+        let num_entries = properties.get_u64();
+        let num_tombstones = properties.get_u64();
+        let num_blob_references = properties.get_u32() as usize;
+        if properties.remaining() < num_blob_references * SIZEOF_U64 * 2 {
+            return Err(corruption(&file, block_meta_offset));
+        }
+        let blob_references = (0..num_blob_references)
+            .map(|_| (properties.get_u64() as usize, properties.get_u64()))
+            .collect();
+        let dictionary = properties;
+        let dictionary = (!dictionary.is_empty()).then(|| ZstdDictionary::new(dictionary));
+        let (Some(first), Some(last)) = (block_meta.first(), block_meta.last()) else {
+            bail!("sst {} has no blocks", id);
+        };
+        let properties = TableProperties {
+            first_key: first.first_key.clone(),
+            last_key: last.last_key.clone(),
+            creation_time,
+            file_creation_time,
+            num_entries,
+            num_tombstones,
+            blob_references,
+        };
+        let reader = Self {
+            file,
+            block_meta,
+            block_meta_offset: block_meta_offset as usize,
+            dictionary,
+            meta,
+        };
+        Ok((reader, properties))
+    }
+
+    /// Read the meta section from the disk, verifying the checksums of the footer and the meta
+    /// section.
+    fn read_meta(file: &FileObject) -> Result<Vec<u8>> {
+        let len = file.size();
+        if len < FOOTER_LEN {
+            return Err(corruption(file, 0));
+        }
+        let footer = file.read(len - FOOTER_LEN, FOOTER_LEN)?;
+        let (meta_checksum, footer) = footer.split_at(SIZEOF_U32);
+        let (raw_meta_offset, footer_checksum) = footer.split_at(SIZEOF_U32);
+        if crc32fast::hash(raw_meta_offset) != (&footer_checksum[..]).get_u32() {
+            return Err(corruption(file, len - SIZEOF_U32 as u64 * 2));
+        }
+        let block_meta_offset = (&raw_meta_offset[..]).get_u32() as u64;
+        if block_meta_offset > len - FOOTER_LEN {
+            return Err(corruption(file, len - SIZEOF_U32 as u64 * 2));
+        }
+        let raw_meta = file.read(block_meta_offset, len - FOOTER_LEN - block_meta_offset)?;
+        if crc32fast::hash(&raw_meta) != (&meta_checksum[..]).get_u32() {
+            return Err(corruption(file, block_meta_offset));
+        }
+        Ok(raw_meta)
+    }
+
+    /// Read a block from the disk.
+    pub(crate) fn read_block(
+        &self,
+        block_idx: usize,
+        verify_checksums: bool,
+    ) -> Result<Arc<Block>> {
+        let offset = self.block_meta[block_idx].offset;
+        let offset_end = self
+            .block_meta
+            .get(block_idx + 1)
+            .map_or(self.block_meta_offset, |meta| meta.offset);
+        let raw_block = self
+            .file
+            .read(offset as u64, (offset_end - offset) as u64)?;
+        if raw_block.len() < SIZEOF_U32 {
+            return Err(corruption(&self.file, offset as u64));
+        }
+        let (raw_block, checksum) = raw_block.split_at(raw_block.len() - SIZEOF_U32);
+        if verify_checksums && crc32fast::hash(raw_block) != (&checksum[..]).get_u32() {
+            return Err(corruption(&self.file, offset as u64));
+        }
+        let block_data = CompressionType::decompress_block(raw_block, self.dictionary.as_ref())?;
+        Ok(Arc::new(Block::decode(&block_data[..])))
+    }
+
+    /// Find the block that may contain `key`.
+    pub(crate) fn find_block_idx(&self, key: &[u8]) -> usize {
+        self.block_meta
+            .partition_point(|meta| meta.first_key.as_ref() <= key)
+            .saturating_sub(1)
+    }
+}
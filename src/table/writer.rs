@@ -0,0 +1,98 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, bail};
+use bytes::Bytes;
+
+use super::SsTableBuilder;
+use crate::{
+    compress::CompressionType,
+    lsm_storage::{MAX_KEY_SIZE, MAX_VALUE_SIZE, check_key, check_value},
+};
+
+/// Builds a sorted SST outside of the storage engine, to be loaded with
+/// `MiniLsm::ingest_external_files`.
+pub struct SstFileWriter {
+    builder: SsTableBuilder,
+    path: PathBuf,
+    first_key: Option<Bytes>,
+    last_key: Vec<u8>,
+    num_entries: usize,
+}
+
+/// Describes an SST built by `SstFileWriter`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExternalSstFileInfo {
+    pub path: PathBuf,
+    pub first_key: Bytes,
+    pub last_key: Bytes,
+    pub num_entries: usize,
+}
+
+impl SstFileWriter {
+    /// Create a writer for the SST at `path`.
+    pub fn create(path: impl AsRef<Path>, block_size: usize) -> Self {
+        Self {
+            builder: SsTableBuilder::new(block_size),
+            path: path.as_ref().to_path_buf(),
+            first_key: None,
+            last_key: Vec::new(),
+            num_entries: 0,
+        }
+    }
+
+    /// Compress the data blocks with `compression`.
+    pub fn with_compression(mut self, compression: CompressionType) -> Self {
+        self.builder = self.builder.with_compression(compression);
+        self
+    }
+
+    fn check_key(&self, key: &[u8]) -> Result<()> {
+        check_key(key, MAX_KEY_SIZE)?;
+        if self.first_key.is_some() && key <= self.last_key.as_slice() {
+            bail!("keys must be added in strictly increasing order");
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, key: &[u8], value: Option<&[u8]>) {
+        match value {
+            Some(value) => self.builder.add(key, value),
+            None => self.builder.add_delete(key),
+        }
+        if self.first_key.is_none() {
+            self.first_key = Some(Bytes::copy_from_slice(key));
+        }
+        self.last_key = key.to_vec();
+        self.num_entries += 1;
+    }
+
+    /// Add a key-value pair, keys must be added in strictly increasing order.
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.check_key(key)?;
+        check_value(value, MAX_VALUE_SIZE)?;
+        self.add(key, Some(value));
+        Ok(())
+    }
+
+    /// Add a tombstone for `key`, keys must be added in strictly increasing order.
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.check_key(key)?;
+        self.add(key, None);
+        Ok(())
+    }
+
+    /// Write the SST to its path.
+    pub fn finish(self) -> Result<ExternalSstFileInfo> {
+        let Some(first_key) = self.first_key else {
+            bail!("cannot write an empty sst");
+        };
+        // the id is assigned again when the file is ingested
+        self.builder.build(0, &self.path)?;
+        Ok(ExternalSstFileInfo {
+            path: self.path,
+            first_key,
+            last_key: self.last_key.into(),
+            num_entries: self.num_entries,
+        })
+    }
+}
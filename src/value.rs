@@ -0,0 +1,53 @@
+//! Typed values.
+//!
+//! Every record carries the type of the write that produced it, so a delete is told apart from a
+//! put of an empty value. The type is a one byte tag in the wal, new types get new tags.
+
+use anyhow::{Result, bail};
+use bytes::Bytes;
+
+const TAG_PUT: u8 = 0;
+const TAG_DELETE: u8 = 1;
+
+/// A value written to the storage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Put(Bytes),
+    /// A tombstone hiding older values of the key.
+    Delete,
+}
+
+impl Value {
+    /// The tag of the value type.
+    pub fn tag(&self) -> u8 {
+        match self {
+            Value::Put(_) => TAG_PUT,
+            Value::Delete => TAG_DELETE,
+        }
+    }
+
+    /// Build a value from its tag and data.
+    pub fn from_tag(tag: u8, value: Bytes) -> Result<Self> {
+        match tag {
+            TAG_PUT => Ok(Value::Put(value)),
+            TAG_DELETE => Ok(Value::Delete),
+            tag => bail!("unknown value type {}", tag),
+        }
+    }
+
+    /// The data of the value, empty for a delete.
+    pub fn data(&self) -> &[u8] {
+        match self {
+            Value::Put(value) => value,
+            Value::Delete => &[],
+        }
+    }
+
+    /// The value as seen by a read, `None` for a delete.
+    pub fn into_put(self) -> Option<Bytes> {
+        match self {
+            Value::Put(value) => Some(value),
+            Value::Delete => None,
+        }
+    }
+}
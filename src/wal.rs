@@ -1,19 +1,27 @@
-use std::{
-    fs::{File, OpenOptions},
-    io::BufWriter,
-    path::Path,
-    sync::Arc,
-};
+use std::{path::Path, sync::Arc};
 
-use anyhow::{Context, Ok, Result};
-use bytes::Bytes;
+use anyhow::{Context, Ok, Result, bail};
+use bytes::{Buf, BufMut, Bytes};
 use crossbeam_skiplist::SkipMap;
 use parking_lot::Mutex;
 
-/// wal
+use crate::{
+    fs::{FileSystem, WritableFile},
+    value::Value,
+};
 
+/// The column family of records written through `Wal::put_value`.
+const DEFAULT_COLUMN_FAMILY_ID: usize = 0;
+
+/// wal
+///
+/// Each record is a write batch: `| batch_len (u32) | entry | entry | ... |`, and each entry is
+/// `| cf_id (u32) | value_type (u8) | key_len (u16) | key | value_len (u32) | value |`. A batch is
+/// only replayed when it was completely written.
 pub struct Wal {
-    file: Arc<Mutex<BufWriter<File>>>,
+    /// `None` once an append or a sync failed: the file may end with a torn batch, the batches
+    /// appended after it would not be replayed.
+    file: Arc<Mutex<Option<Box<dyn WritableFile>>>>,
 }
 impl Wal {
     /// Create Wal from file.
@@ -23,35 +31,139 @@ impl Wal {
     ///
     /// # Returns
     /// * `Result<Wal>`
-    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
-        let file = Arc::new(Mutex::new(BufWriter::new(
-            OpenOptions::new()
-                .read(true)
-                .create_new(true)
-                .write(true)
-                .open(path)
-                .context("Failed to open wal file")?,
-        )));
-        Ok(Self { file })
-    }
-    pub(crate) fn put(&self, _key: &[u8], _value: &[u8]) -> Result<()> {
-        /// todo!()
+    pub fn create(fs: &dyn FileSystem, path: impl AsRef<Path>) -> Result<Self> {
+        let file = fs
+            .create(path.as_ref())
+            .context("Failed to open wal file")?;
+        fs.sync_parent_dir(path.as_ref())?;
+        Ok(Self {
+            file: Arc::new(Mutex::new(Some(file))),
+        })
+    }
+
+    pub(crate) fn put_value(&self, key: &[u8], value: &Value) -> Result<()> {
+        self.put_batch(&[(DEFAULT_COLUMN_FAMILY_ID, key, value)])
+    }
+
+    /// Append a write batch of `(cf_id, key, value)` entries as a single record.
+    pub(crate) fn put_batch(&self, batch: &[(usize, &[u8], &Value)]) -> Result<()> {
+        let mut buf = Vec::new();
+        for (cf_id, key, value) in batch {
+            buf.put_u32(*cf_id as u32);
+            buf.put_u8(value.tag());
+            buf.put_u16(key.len() as u16);
+            buf.put_slice(key);
+            buf.put_u32(value.data().len() as u32);
+            buf.put_slice(value.data());
+        }
+        self.write(|file| {
+            file.append(&(buf.len() as u32).to_be_bytes())?;
+            file.append(&buf)
+        })
+    }
+
+    /// Hand the buffered records to the file system, without syncing them.
+    pub(crate) fn flush(&self) -> Result<()> {
+        self.write(|file| file.flush())
+    }
+
+    /// Flush the buffered records and fsync the wal file.
+    pub fn sync(&self) -> Result<()> {
+        self.write(|file| file.sync())
+    }
+
+    /// Run a write on the file, no more write is accepted once one failed.
+    fn write(&self, f: impl FnOnce(&mut dyn WritableFile) -> std::io::Result<()>) -> Result<()> {
+        let mut file = self.file.lock();
+        let Some(writer) = file.as_mut() else {
+            bail!("wal is unusable after a failed write");
+        };
+        if let Err(e) = f(writer.as_mut()) {
+            *file = None;
+            return Err(e.into());
+        }
         Ok(())
     }
 
+    /// Decode the complete batches in `data`, returning the entries and the length of the data
+    /// they cover. Decoding stops at a torn or malformed batch.
+    fn decode_batches(data: &[u8]) -> (Vec<(usize, Bytes, Value)>, usize) {
+        let mut buf = data;
+        let mut records = Vec::new();
+        while buf.remaining() >= 4 {
+            let batch_len = (&buf[..4]).get_u32() as usize;
+            if buf.remaining() < 4 + batch_len {
+                // torn write of the last batch
+                break;
+            }
+            let Some(batch) = Self::decode_batch(&buf[4..4 + batch_len]) else {
+                break;
+            };
+            records.extend(batch);
+            buf.advance(4 + batch_len);
+        }
+        (records, data.len() - buf.remaining())
+    }
+
+    fn decode_batch(mut batch: &[u8]) -> Option<Vec<(usize, Bytes, Value)>> {
+        let mut records = Vec::new();
+        while batch.has_remaining() {
+            if batch.remaining() < 4 + 1 + 2 {
+                return None;
+            }
+            let cf_id = batch.get_u32() as usize;
+            let tag = batch.get_u8();
+            let key_len = batch.get_u16() as usize;
+            if batch.remaining() < key_len + 4 {
+                return None;
+            }
+            let key = batch.copy_to_bytes(key_len);
+            let value_len = batch.get_u32() as usize;
+            if batch.remaining() < value_len {
+                return None;
+            }
+            let value = Value::from_tag(tag, batch.copy_to_bytes(value_len)).ok()?;
+            records.push((cf_id, key, value));
+        }
+        Some(records)
+    }
+
+    /// Check the data of a wal file, returning the number of entries and the offset of the data
+    /// after the last complete batch when the data does not end there. Batches have no checksum,
+    /// only their framing and encoding are checked.
+    pub(crate) fn verify(data: &[u8]) -> (usize, Option<u64>) {
+        let (records, valid_len) = Self::decode_batches(data);
+        let tail = (valid_len < data.len()).then_some(valid_len as u64);
+        (records.len(), tail)
+    }
+
+    /// Read all `(cf_id, key, value)` entries of the complete batches in a wal file.
+    pub(crate) fn read_records(
+        fs: &dyn FileSystem,
+        path: &Path,
+    ) -> Result<Vec<(usize, Bytes, Value)>> {
+        let data = fs.read(path).context("Failed to open wal file")?;
+        Ok(Self::decode_batches(&data).0)
+    }
+
     /// Recover wal from file.
-    pub(crate) fn recover(path: &Path, map: &SkipMap<Bytes, Bytes>) -> Result<Self> {
-        //todo!()
-
-        let file = Arc::new(Mutex::new(BufWriter::new(
-            OpenOptions::new()
-                .read(true)
-                .create_new(true)
-                .write(true)
-                .open(path)
-                .context("Failed to open wal file")?,
-        )));
-        Ok(Self { file })
+    pub(crate) fn recover(
+        fs: &dyn FileSystem,
+        path: &Path,
+        map: &SkipMap<Bytes, Value>,
+    ) -> Result<Self> {
+        let data = fs.read(path).context("Failed to open wal file")?;
+        let (records, valid_len) = Self::decode_batches(&data);
+        for (cf_id, key, value) in records {
+            if cf_id == DEFAULT_COLUMN_FAMILY_ID {
+                map.insert(key, value);
+            }
+        }
+        // drop a torn batch, so new batches are appended after a complete one
+        fs.truncate(path, valid_len as u64)?;
+        Ok(Self {
+            file: Arc::new(Mutex::new(Some(fs.open_append(path)?))),
+        })
     }
 }
 
@@ -61,16 +173,92 @@ impl Wal {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::fs::{FaultInjectionFs, InMemoryFileSystem};
+
+    fn put(value: &'static [u8]) -> Value {
+        Value::Put(Bytes::from_static(value))
+    }
 
     /// Test wal create
     ///
     #[test]
     fn test_wal_create() -> Result<()> {
-        let path = "test_create.wal";
-        let wal = Wal::create(path);
+        let fs = InMemoryFileSystem::new();
+        let wal = Wal::create(&fs, "test_create.wal");
         assert!(wal.is_ok());
-        // remove test.wal
-        std::fs::remove_file(path)?;
+        assert!(fs.exists(Path::new("test_create.wal")));
+        Ok(())
+    }
+
+    /// Test wal batches are replayed with their column family
+    ///
+    #[test]
+    fn test_wal_put_batch_and_recover() -> Result<()> {
+        let fs = InMemoryFileSystem::new();
+        let path = Path::new("00001.wal");
+        let wal = Wal::create(&fs, path)?;
+        wal.put_value(b"key1", &put(b"value1"))?;
+        wal.put_batch(&[(1, b"key2", &put(b"value2")), (0, b"key3", &Value::Delete)])?;
+        wal.sync()?;
+
+        let records = Wal::read_records(&fs, path)?;
+        assert_eq!(
+            records,
+            vec![
+                (0, Bytes::from_static(b"key1"), put(b"value1")),
+                (1, Bytes::from_static(b"key2"), put(b"value2")),
+                (0, Bytes::from_static(b"key3"), Value::Delete),
+            ]
+        );
+
+        let map = SkipMap::new();
+        let wal = Wal::recover(&fs, path, &map)?;
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get(b"key1".as_ref()).unwrap().value(), &put(b"value1"));
+        // the recovered wal keeps appending to the same file
+        wal.put_value(b"key4", &put(b"value4"))?;
+        wal.sync()?;
+        assert_eq!(Wal::read_records(&fs, path)?.len(), 4);
+        Ok(())
+    }
+
+    /// Test a torn batch at the end of the wal is ignored
+    ///
+    #[test]
+    fn test_wal_torn_batch() -> Result<()> {
+        let fs = InMemoryFileSystem::new();
+        let path = Path::new("00001.wal");
+        let wal = Wal::create(&fs, path)?;
+        wal.put_value(b"key1", &put(b"value1"))?;
+        wal.put_batch(&[(0, b"key2", &put(b"value2")), (0, b"key3", &put(b"value3"))])?;
+        wal.sync()?;
+        let len = fs.open(path)?.size()?;
+        fs.truncate(path, len - 3)?;
+
+        let records = Wal::read_records(&fs, path)?;
+        assert_eq!(
+            records,
+            vec![(0, Bytes::from_static(b"key1"), put(b"value1"))]
+        );
+        Ok(())
+    }
+
+    /// Test the wal accepts no more write after a torn one, the later batches would be lost
+    ///
+    #[test]
+    fn test_wal_failed_write() -> Result<()> {
+        let fs = FaultInjectionFs::new(Arc::new(InMemoryFileSystem::new()));
+        let path = Path::new("00001.wal");
+        let wal = Wal::create(&fs, path)?;
+        wal.put_value(b"key1", &put(b"value1"))?;
+        fs.tear_next_append(".wal", 2);
+        assert!(wal.put_value(b"key2", &put(b"value2")).is_err());
+        assert!(wal.put_value(b"key3", &put(b"value3")).is_err());
+        assert!(wal.sync().is_err());
+        assert_eq!(
+            Wal::read_records(&fs, path)?,
+            vec![(0, Bytes::from_static(b"key1"), put(b"value1"))]
+        );
         Ok(())
     }
 }